@@ -0,0 +1,140 @@
+use crate::{
+    _tests_::util::mock_app,
+    asset::RemoteAssetPlugin,
+    tasks::QueryStore,
+    transport::mock::{MockResponse, MockTransport, RequestMatcher},
+};
+use bevy::{
+    asset::{io::Reader, AssetLoader, LoadContext, LoadState},
+    prelude::*,
+};
+use ntest::timeout;
+use std::time::Duration;
+
+#[derive(Asset, TypePath, Debug)]
+struct Blob(Vec<u8>);
+
+#[derive(Default)]
+struct BlobLoader;
+
+impl AssetLoader for BlobLoader {
+    type Asset = Blob;
+    type Settings = ();
+    type Error = std::io::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _: &(),
+        _: &mut LoadContext<'_>,
+    ) -> Result<Blob, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        Ok(Blob(bytes))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["blob"]
+    }
+}
+
+fn skin() -> Vec<u8> {
+    (0..=255).collect()
+}
+
+fn asset_app(mock: &MockTransport, plugin: RemoteAssetPlugin) -> App {
+    let mut app = mock_app(mock);
+    app.add_plugins((TaskPoolPlugin::default(), plugin, AssetPlugin::default()))
+        .init_asset::<Blob>()
+        .init_asset_loader::<BlobLoader>();
+    app
+}
+
+/// Updates the app until the asset is loaded or failed
+fn wait_for(app: &mut App, handle: &Handle<Blob>) -> LoadState {
+    loop {
+        app.update();
+        match app.world().resource::<AssetServer>().load_state(handle) {
+            LoadState::Loading | LoadState::NotLoaded => {}
+            state => return state,
+        }
+    }
+}
+
+#[timeout(3000)]
+#[test]
+fn remote_assets() {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("http://cdn.test/skins/42.blob"),
+        MockResponse::status(200).with_body(skin()),
+    );
+    let mut app = asset_app(
+        &mock,
+        RemoteAssetPlugin::default().with_host("cdn", "http://cdn.test/"),
+    );
+
+    let handle: Handle<Blob> = app
+        .world()
+        .resource::<AssetServer>()
+        .load("remote://cdn/skins/42.blob");
+    assert!(wait_for(&mut app, &handle).is_loaded());
+    assert_eq!(
+        app.world().resource::<Assets<Blob>>().get(&handle).unwrap().0,
+        skin()
+    );
+
+    // the response is cached like any other query
+    let store = app.world().resource::<QueryStore>();
+    let key = ("http://cdn.test/skins/42.blob".to_string(), String::new());
+    let (value, query, _) = &store.cache[&key];
+    assert!(query.binary);
+    // the bytes are kept out of the JSON entry
+    assert!(value["body"].is_null());
+    assert_eq!(store.binary_bodies[&key], skin());
+
+    // reloading is served from the cache
+    app.world()
+        .resource::<AssetServer>()
+        .reload("remote://cdn/skins/42.blob");
+    for _ in 0..20 {
+        app.update();
+    }
+    assert!(wait_for(&mut app, &handle).is_loaded());
+    mock.assert_requested(&RequestMatcher::get("http://cdn.test/skins/42.blob"), 1);
+
+    let missing: Handle<Blob> = app
+        .world()
+        .resource::<AssetServer>()
+        .load("remote://cdn/skins/missing.blob");
+    assert!(matches!(wait_for(&mut app, &missing), LoadState::Failed(_)));
+}
+
+#[timeout(3000)]
+#[test]
+fn remote_assets_stale_time() {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("https://assets.test/intro.blob"),
+        MockResponse::status(200).with_body(skin()),
+    );
+    let mut app = asset_app(
+        &mock,
+        RemoteAssetPlugin::default().with_stale_time(Duration::ZERO),
+    );
+
+    let handle: Handle<Blob> = app
+        .world()
+        .resource::<AssetServer>()
+        .load("remote://assets.test/intro.blob");
+    assert!(wait_for(&mut app, &handle).is_loaded());
+
+    app.world()
+        .resource::<AssetServer>()
+        .reload("remote://assets.test/intro.blob");
+    while mock.requests().len() < 2 {
+        app.update();
+    }
+    assert!(wait_for(&mut app, &handle).is_loaded());
+    mock.assert_requested(&RequestMatcher::get("https://assets.test/intro.blob"), 2);
+}
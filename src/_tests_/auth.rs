@@ -0,0 +1,193 @@
+use crate::{
+    _tests_::util::{cached_status, mock_app, trigger, wait_for_all},
+    auth::{AuthProvider, QueryAuth},
+    stream::StreamMode,
+    tasks::{ErrorTriggerEvent, Method, QueryBuilder, QueryStore},
+    transport::{
+        mock::{MockResponse, MockTransport, RequestMatcher},
+        HttpRequest, HttpTransport, TransportError,
+    },
+};
+use bevy::{prelude::*, utils::BoxedFuture};
+use ntest::timeout;
+use serde_json::json;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[derive(Clone)]
+struct TestAuth {
+    token: Arc<Mutex<String>>,
+}
+
+impl AuthProvider for TestAuth {
+    fn authorize(&self, request: &mut HttpRequest) {
+        request.set_header("Authorization", format!("Bearer {}", self.token.lock().unwrap()));
+    }
+
+    fn refresh(
+        &self,
+        transport: Arc<dyn HttpTransport>,
+    ) -> BoxedFuture<'static, Result<(), TransportError>> {
+        let token = self.token.clone();
+        Box::pin(async move {
+            let response = transport
+                .send(HttpRequest {
+                    method: Method::Post,
+                    url: "http://api.test/refresh".to_string(),
+                    timeout: Duration::from_secs(1),
+                    ..default()
+                })
+                .await?
+                .fetch()
+                .await?;
+            if response.status != 200 {
+                return Err(TransportError::Other(format!(
+                    "Refresh responded with {}",
+                    response.status
+                )));
+            }
+            let body: serde_json::Value = serde_json::from_slice(&response.body)
+                .map_err(|err| TransportError::Other(err.to_string()))?;
+            *token.lock().unwrap() = body["token"].as_str().unwrap_or_default().to_string();
+            Ok(())
+        })
+    }
+}
+
+fn auth_app(mock: &MockTransport) -> App {
+    mock.on(RequestMatcher::get("/api/*"), MockResponse::status(401))
+        .on(
+            RequestMatcher::get("/api/*").header("Authorization", "Bearer fresh"),
+            MockResponse::json(json!({"msg": ""})),
+        );
+    let mut app = mock_app(mock);
+    app.insert_resource(QueryAuth::new(TestAuth {
+        token: Arc::new(Mutex::new("stale".to_string())),
+    }));
+    app
+}
+
+#[timeout(2000)]
+#[test]
+fn auth_refresh() {
+    let mock = MockTransport::new();
+    let mut app = auth_app(&mock);
+    mock.on(
+        RequestMatcher::post("/refresh"),
+        MockResponse::json(json!({"token": "fresh"})).with_delay(Duration::from_millis(50)),
+    );
+    let errors = Arc::new(Mutex::new(vec![]));
+    let observed = errors.clone();
+    app.add_observer(move |trigger: Trigger<ErrorTriggerEvent>| {
+        observed.lock().unwrap().push(trigger.event().error);
+    });
+
+    trigger(&mut app, "http://api.test/api/profile");
+    trigger(&mut app, "http://api.test/api/inventory");
+    trigger(&mut app, "http://api.test/api/friends");
+    while !app.world().resource::<QueryStore>().auth.is_refreshing() {
+        app.update();
+    }
+    trigger(&mut app, "http://api.test/api/shop");
+    wait_for_all(&mut app);
+
+    for url in ["profile", "inventory", "friends", "shop"] {
+        assert_eq!(
+            cached_status(&app, &format!("http://api.test/api/{}", url)),
+            Some(200)
+        );
+    }
+    mock.assert_requested(&RequestMatcher::post("/refresh"), 1);
+    mock.assert_requested(&RequestMatcher::get("/api/shop"), 1);
+    mock.assert_requested(
+        &RequestMatcher::get("/api/profile").header("Authorization", "Bearer stale"),
+        1,
+    );
+    mock.assert_requested(
+        &RequestMatcher::get("/api/profile").header("Authorization", "Bearer fresh"),
+        1,
+    );
+    assert!(errors.lock().unwrap().is_empty());
+    assert!(app.world().resource::<QueryStore>().auth.replayed.is_empty());
+}
+
+#[timeout(2000)]
+#[test]
+fn auth_refresh_failed() {
+    let mock = MockTransport::new();
+    let mut app = auth_app(&mock);
+    mock.on(RequestMatcher::post("/refresh"), MockResponse::status(500));
+
+    trigger(&mut app, "http://api.test/api/profile");
+    wait_for_all(&mut app);
+
+    assert_eq!(cached_status(&app, "http://api.test/api/profile"), Some(401));
+    mock.assert_requested(&RequestMatcher::post("/refresh"), 1);
+    mock.assert_requested(&RequestMatcher::get("/api/profile"), 1);
+}
+
+#[timeout(2000)]
+#[test]
+fn auth_unauthorized_after_refresh() {
+    let mock = MockTransport::new();
+    let mut app = auth_app(&mock);
+    mock.on(
+        RequestMatcher::post("/refresh"),
+        MockResponse::json(json!({"token": "revoked"})),
+    );
+
+    trigger(&mut app, "http://api.test/api/profile");
+    wait_for_all(&mut app);
+
+    assert_eq!(cached_status(&app, "http://api.test/api/profile"), Some(401));
+    mock.assert_requested(&RequestMatcher::post("/refresh"), 1);
+    mock.assert_requested(&RequestMatcher::get("/api/profile"), 2);
+}
+
+#[timeout(2000)]
+#[test]
+fn auth_long_poll() {
+    let url = "http://api.test/api/poll";
+    let mock = MockTransport::new();
+    let mut app = auth_app(&mock);
+    let token = Arc::new(Mutex::new("stale".to_string()));
+    app.insert_resource(QueryAuth::new(TestAuth {
+        token: token.clone(),
+    }));
+    mock.on(
+        RequestMatcher::post("/refresh"),
+        MockResponse::json(json!({"token": "fresh"})),
+    )
+    .on(
+        RequestMatcher::get("/api/*").header("Authorization", "Bearer rotated"),
+        MockResponse::json(json!({"msg": "rotated"})),
+    );
+
+    app.world_mut().commands().trigger(
+        QueryBuilder::default()
+            .url(url)
+            .stream(StreamMode::long_poll("cursor", "/cursor"))
+            .build()
+            .unwrap(),
+    );
+    // the poll answered 401 is opened again after the refresh
+    while cached_status(&app, url).is_none() {
+        app.update();
+    }
+    assert_eq!(cached_status(&app, url), Some(200));
+    mock.assert_requested(&RequestMatcher::post("/refresh"), 1);
+    mock.assert_requested(
+        &RequestMatcher::get("/api/poll").header("Authorization", "Bearer stale"),
+        1,
+    );
+
+    // every poll is sent with the current credentials
+    *token.lock().unwrap() = "rotated".to_string();
+    let rotated = RequestMatcher::get("/api/poll").header("Authorization", "Bearer rotated");
+    while mock.requests_matching(&rotated).is_empty() {
+        app.update();
+    }
+    assert!(app.world().resource::<QueryStore>().auth.replayed.is_empty());
+}
@@ -0,0 +1,167 @@
+use crate::{
+    _tests_::util::{cached_status, init_test_app, mock_app, trigger, wait_for, wait_for_all},
+    auth::{AuthProvider, QueryAuth},
+    batch::{BatchMemberResult, BatchMode, BatchSettled, QueryBatch},
+    tasks::{response_status, Method, QueryBuilder, QueryStore},
+    transport::{
+        mock::{MockResponse, MockTransport, RequestMatcher},
+        HttpRequest, HttpTransport, TransportError,
+    },
+};
+use async_io::Timer;
+use bevy::{prelude::*, utils::BoxedFuture};
+use ntest::{assert_true, timeout};
+use serde_json::json;
+use std::{sync::Arc, time::Duration};
+
+#[derive(Resource, Default)]
+struct BatchResult(Option<BatchSettled>);
+
+fn run_batch(urls: &[&str], mode: BatchMode) -> (App, BatchSettled) {
+    let urls: Vec<String> = urls
+        .iter()
+        .map(|url| format!("http://127.0.0.1:8080/{}", url))
+        .collect();
+    settle(init_test_app(), &urls, mode)
+}
+
+/// Mock answering `/batch_fail` right away and `/batch_2` once the failure settled the batch
+fn slow_mock() -> MockTransport {
+    let mock = MockTransport::new();
+    mock.on(RequestMatcher::get("/batch_fail"), MockResponse::status(500))
+        .on(
+            RequestMatcher::get("/batch_2"),
+            MockResponse::json(json!({"msg": "2"})).with_delay(Duration::from_millis(300)),
+        );
+    mock
+}
+
+/// Auth provider whose refresh takes long enough for a batch to settle meanwhile
+struct SlowRefresh;
+
+impl AuthProvider for SlowRefresh {
+    fn authorize(&self, _request: &mut HttpRequest) {}
+
+    fn refresh(
+        &self,
+        _transport: Arc<dyn HttpTransport>,
+    ) -> BoxedFuture<'static, Result<(), TransportError>> {
+        Box::pin(async {
+            Timer::after(Duration::from_millis(300)).await;
+            Ok(())
+        })
+    }
+}
+
+fn settle(mut app: App, urls: &[String], mode: BatchMode) -> (App, BatchSettled) {
+    app.init_resource::<BatchResult>();
+    app.add_observer(|t: Trigger<BatchSettled>, mut result: ResMut<BatchResult>| {
+        result.0 = Some(t.event().clone());
+    });
+
+    app.world_mut().commands().trigger(QueryBatch {
+        key: "screen".to_string(),
+        queries: urls
+            .iter()
+            .map(|url| {
+                QueryBuilder::default()
+                    .method(Method::Get)
+                    .url(url)
+                    .build()
+                    .unwrap()
+            })
+            .collect(),
+        mode,
+    });
+
+    loop {
+        if let Some(result) = app.world().resource::<BatchResult>().0.clone() {
+            return (app, result);
+        }
+        app.update();
+    }
+}
+
+#[timeout(2000)]
+#[test]
+fn batch_wait_for_all() {
+    let (app, result) = run_batch(&["batch_1", "batch_fail", "batch_3"], BatchMode::WaitForAll);
+
+    assert_eq!(result.key, "screen");
+    assert_eq!(
+        result.results,
+        vec![
+            BatchMemberResult::Success(json!({"msg": "1"})),
+            BatchMemberResult::Error(500),
+            BatchMemberResult::Success(json!({"msg": "3"})),
+        ]
+    );
+    assert_true!(app.world().resource::<QueryStore>().batches.is_empty());
+}
+
+#[timeout(2000)]
+#[test]
+fn batch_fail_fast() {
+    let mock = slow_mock();
+    let urls = ["http://mock.test/batch_fail", "http://mock.test/batch_2"].map(String::from);
+    let (app, result) = settle(mock_app(&mock), &urls, BatchMode::FailFast);
+
+    assert_eq!(
+        result.results,
+        vec![BatchMemberResult::Error(500), BatchMemberResult::Cancelled]
+    );
+    let store = app.world().resource::<QueryStore>();
+    assert_true!(store.batches.is_empty());
+    assert_true!(store.loading_requests.is_empty());
+}
+
+#[timeout(2000)]
+#[test]
+fn batch_fail_fast_keeps_shared_requests() {
+    let mock = slow_mock();
+    let mut app = mock_app(&mock);
+    let urls = ["http://mock.test/batch_fail", "http://mock.test/batch_2"].map(String::from);
+    // the same query sent outside of the batch
+    trigger(&mut app, &urls[1]);
+
+    let (mut app, result) = settle(app, &urls, BatchMode::FailFast);
+
+    assert_eq!(
+        result.results,
+        vec![BatchMemberResult::Error(500), BatchMemberResult::Skipped]
+    );
+    let (value, _) = wait_for(&mut app, &urls[1]);
+    assert_eq!(response_status(&value), 200);
+    assert_eq!(mock.requests().len(), 2);
+}
+
+#[timeout(2000)]
+#[test]
+fn batch_fail_fast_cancels_queries_waiting_for_refresh() {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/batch_fail"),
+        MockResponse::status(500).with_delay(Duration::from_millis(100)),
+    )
+    .on(
+        RequestMatcher::get("/batch_2"),
+        MockResponse::json(json!({"msg": "2"})),
+    )
+    .on_times(RequestMatcher::get("/batch_2"), MockResponse::status(401), 1);
+    let mut app = mock_app(&mock);
+    app.insert_resource(QueryAuth::new(SlowRefresh));
+    let urls = ["http://mock.test/batch_fail", "http://mock.test/batch_2"].map(String::from);
+
+    // `/batch_2` is answered 401 and waits for the refresh when `/batch_fail` fails
+    let (mut app, result) = settle(app, &urls, BatchMode::FailFast);
+    assert_eq!(
+        result.results,
+        vec![BatchMemberResult::Error(500), BatchMemberResult::Cancelled]
+    );
+    assert_true!(app.world().resource::<QueryStore>().auth.is_refreshing());
+
+    wait_for_all(&mut app);
+    app.update();
+    assert_eq!(cached_status(&app, &urls[1]), None);
+    mock.assert_requested(&RequestMatcher::get("/batch_2"), 1);
+}
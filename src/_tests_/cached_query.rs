@@ -0,0 +1,104 @@
+use crate::{
+    _tests_::util::{init_test_app, GetResponse},
+    cached_query::{CachedQuery, QueryError, QueryStatus},
+    extractor::QueryConsumable,
+    tasks::{Method, QueryBuilder, QueryStore},
+};
+use bevy::prelude::*;
+use ntest::{assert_true, timeout};
+
+const URL: &str = "http://127.0.0.1:8080/cached_query";
+
+#[derive(Resource, Default)]
+struct Seen {
+    statuses: Vec<QueryStatus>,
+    msg: Option<String>,
+    refetched: bool,
+}
+
+fn consumable() -> QueryConsumable {
+    QueryConsumable {
+        url: URL.to_string(),
+        ..default()
+    }
+}
+
+fn read_profile(mut query: CachedQuery<GetResponse>, mut seen: ResMut<Seen>) {
+    let status = query.status(&consumable());
+    if seen.statuses.last() != Some(&status) {
+        seen.statuses.push(status);
+    }
+    if let Ok(response) = query.get(&consumable()) {
+        seen.msg = Some(response.msg.clone());
+        if !seen.refetched {
+            seen.refetched = true;
+            query.refetch(&consumable());
+        }
+    }
+}
+
+/// Runs alongside `read_profile`, neither needs exclusive access to the store
+fn read_profile_again(mut query: CachedQuery<GetResponse>) {
+    if let Err(err) = query.get(&consumable()) {
+        assert_true!(matches!(err, QueryError::Fetching | QueryError::NotCached));
+    }
+}
+
+#[timeout(2000)]
+#[test]
+fn cached_query() {
+    let mut app = init_test_app();
+    app.init_resource::<Seen>();
+    app.add_systems(Update, (read_profile, read_profile_again));
+
+    app.world_mut().commands().trigger(
+        QueryBuilder::default()
+            .method(Method::Get)
+            .url(URL)
+            .build()
+            .unwrap(),
+    );
+
+    while app.world().resource::<Seen>().msg.is_none() {
+        app.update();
+    }
+    assert_eq!(app.world().resource::<Seen>().msg.as_deref(), Some("typed"));
+
+    // the refetch keeps the previous response cached while the new one is loading
+    app.update();
+    let store = app.world().resource::<QueryStore>();
+    assert_true!(store.cache.contains_key(&(URL.to_string(), String::new())));
+    assert_true!(store.loading_requests.len() <= 1);
+
+    let statuses = &app.world().resource::<Seen>().statuses;
+    assert_eq!(statuses.last(), Some(&QueryStatus::Success));
+}
+
+#[timeout(2000)]
+#[test]
+fn cached_query_stale() {
+    let mut app = init_test_app();
+    app.add_systems(Update, |mut query: CachedQuery<GetResponse>| {
+        let stale = QueryConsumable {
+            stale_time: Some(0),
+            ..consumable()
+        };
+        let _ = query.get(&stale);
+    });
+
+    app.world_mut().commands().trigger(
+        QueryBuilder::default()
+            .method(Method::Get)
+            .url(URL)
+            .build()
+            .unwrap(),
+    );
+
+    loop {
+        app.update();
+        let store = app.world().resource::<QueryStore>();
+        if store.versions.values().any(|version| *version > 1) {
+            break;
+        }
+    }
+}
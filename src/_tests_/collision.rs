@@ -1,5 +1,5 @@
 use crate::{
-    _tests_::util::{init_test_app, GetResponse},
+    _tests_::util::{init_test_app, wait_for_all, GetResponse},
     extractor::{query_extractor, QueryConsumable},
     tasks::{Method, QueryBuilder, QueryStore},
 };
@@ -29,10 +29,14 @@ fn same_query_key() {
             .unwrap(),
     );
 
-    app.update();
-
+    // runs the observers without polling, a local server may answer before the next poll
+    app.world_mut().flush();
     let store = app.world().get_resource::<QueryStore>().unwrap();
     assert_true!(store.loading_requests.len() == 1);
+
+    wait_for_all(&mut app);
+    let store = app.world().get_resource::<QueryStore>().unwrap();
+    assert_true!(store.cache.len() == 1);
 }
 
 #[test]
@@ -108,7 +112,11 @@ fn same_url_different_query_key() {
             .unwrap(),
     );
 
-    app.update();
+    app.world_mut().flush();
     let store = app.world().get_resource::<QueryStore>().unwrap();
     assert_true!(store.loading_requests.len() == 2);
+
+    wait_for_all(&mut app);
+    let store = app.world().get_resource::<QueryStore>().unwrap();
+    assert_true!(store.cache.len() == 2);
 }
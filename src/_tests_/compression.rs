@@ -0,0 +1,145 @@
+use crate::{
+    _tests_::util::{mock_app, wait_for},
+    tasks::{response_status, Method, QueryBuilder},
+    transport::{
+        compression::{self, ACCEPT_ENCODING},
+        mock::{MockResponse, MockTransport, RequestMatcher},
+    },
+};
+use flate2::{
+    write::{DeflateEncoder, ZlibEncoder},
+    Compression,
+};
+use ntest::timeout;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+
+fn brotli(body: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![];
+    let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+    encoder.write_all(body).unwrap();
+    drop(encoder);
+    encoded
+}
+
+#[timeout(1000)]
+#[test]
+fn decompress_responses() {
+    let body = serde_json::to_vec(&json!({"msg": "compressed ".repeat(50)})).unwrap();
+    let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+    zlib.write_all(&body).unwrap();
+    let mut raw_deflate = DeflateEncoder::new(vec![], Compression::default());
+    raw_deflate.write_all(&body).unwrap();
+    let encoded = [
+        ("gzip", compression::gzip(&body)),
+        ("deflate", zlib.finish().unwrap()),
+        ("raw_deflate", raw_deflate.finish().unwrap()),
+        ("br", brotli(&body)),
+    ];
+
+    let mock = MockTransport::new();
+    for (name, bytes) in encoded.iter() {
+        let encoding = name.trim_start_matches("raw_");
+        mock.on(
+            RequestMatcher::get(format!("/{}", name)),
+            MockResponse::json(json!({}))
+                .with_header("Content-Encoding", encoding)
+                .with_header("Content-Length", bytes.len().to_string())
+                .with_body(bytes.clone()),
+        );
+    }
+    mock.on(
+        RequestMatcher::get("/plain"),
+        MockResponse::json(json!({"msg": "plain"})),
+    );
+    let mut app = mock_app(&mock);
+
+    for (name, bytes) in encoded.iter() {
+        let url = format!("http://mock.test/{}", name);
+        app.world_mut()
+            .commands()
+            .trigger(QueryBuilder::default().url(url.clone()).build().unwrap());
+        let (value, _) = wait_for(&mut app, &url);
+        assert_eq!(response_status(&value), 200, "{}", name);
+        assert_eq!(value["body"]["msg"], "compressed ".repeat(50), "{}", name);
+        assert_eq!(value["compressed_size"], bytes.len(), "{}", name);
+        assert_eq!(value["decompressed_size"], body.len(), "{}", name);
+        assert!(value["headers"].get("content-encoding").is_none(), "{}", name);
+        assert!(value["headers"].get("content-length").is_none(), "{}", name);
+    }
+
+    let url = "http://mock.test/plain";
+    app.world_mut()
+        .commands()
+        .trigger(QueryBuilder::default().url(url).build().unwrap());
+    let (value, _) = wait_for(&mut app, url);
+    assert_eq!(value["body"]["msg"], "plain");
+    assert!(value["compressed_size"].is_null());
+    assert_eq!(value["decompressed_size"], br#"{"msg":"plain"}"#.len());
+
+    mock.assert_requested(
+        &RequestMatcher::get("/gzip").header("accept-encoding", ACCEPT_ENCODING),
+        1,
+    );
+}
+
+#[timeout(1000)]
+#[test]
+fn undecodable_response() {
+    let url = "http://mock.test/broken";
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/broken"),
+        MockResponse::json(json!({"msg": "not gzip"})).with_header("Content-Encoding", "gzip"),
+    );
+    let mut app = mock_app(&mock);
+
+    app.world_mut()
+        .commands()
+        .trigger(QueryBuilder::default().url(url).build().unwrap());
+    assert_eq!(response_status(&wait_for(&mut app, url).0), 500);
+}
+
+#[timeout(1000)]
+#[test]
+fn gzip_request_body() {
+    let mock = MockTransport::new();
+    mock.on(RequestMatcher::post("/telemetry/*"), MockResponse::json(json!({})));
+    let mut app = mock_app(&mock);
+
+    let events = json!({"events": vec!["frame"; 100]});
+    for (url, gzip_body) in [
+        ("http://mock.test/telemetry/gzip", true),
+        ("http://mock.test/telemetry/plain", false),
+    ] {
+        app.world_mut().commands().trigger(
+            QueryBuilder::default()
+                .url(url)
+                .method(Method::Post)
+                .body(events.clone())
+                .gzip_body(gzip_body)
+                .headers(vec![("Accept-Encoding".to_string(), "gzip".to_string())])
+                .build()
+                .unwrap(),
+        );
+        wait_for(&mut app, url);
+    }
+
+    let requests = mock.requests();
+    let gzipped = &requests[0];
+    assert_eq!(gzipped.header("content-encoding"), Some("gzip"));
+    assert_eq!(gzipped.header("accept-encoding"), Some("gzip"));
+    let mut decoded = vec![];
+    flate2::read::GzDecoder::new(gzipped.body.as_deref().unwrap())
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(serde_json::from_slice::<Value>(&decoded).unwrap(), events);
+    assert!(gzipped.body.as_ref().unwrap().len() < decoded.len());
+
+    let plain = &requests[1];
+    assert_eq!(plain.header("content-encoding"), None);
+    assert_eq!(
+        serde_json::from_slice::<Value>(plain.body.as_deref().unwrap()).unwrap(),
+        events
+    );
+}
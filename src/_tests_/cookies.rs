@@ -0,0 +1,144 @@
+use crate::{
+    _tests_::util::{init_test_app, GetResponse},
+    extractor::{query_extractor, QueryConsumable},
+    tasks::{QueryBuilder, QueryStore},
+    transport::{
+        cookies::CookieJar, hyper_transport::HyperTransport, HttpTransport, QueryTransport,
+        UreqTransport,
+    },
+};
+use bevy::prelude::*;
+use ntest::timeout;
+use std::fs;
+
+fn set_cookies(jar: &CookieJar, url: &str, cookies: &[&str]) {
+    let headers: Vec<(String, String)> = cookies
+        .iter()
+        .map(|cookie| ("set-cookie".to_string(), cookie.to_string()))
+        .collect();
+    jar.store(url, &headers);
+}
+
+#[test]
+fn cookie_jar_rules() {
+    let jar = CookieJar::new();
+    set_cookies(
+        &jar,
+        "https://api.game.test/auth/login",
+        &[
+            "session=abc; Path=/; Secure; HttpOnly",
+            "region=eu; Domain=.game.test; Path=/",
+            "draft=1",
+            "old=1; Max-Age=0",
+            "evil=1; Domain=other.test",
+            "remember=yes; Path=/; Max-Age=3600",
+        ],
+    );
+
+    assert_eq!(
+        jar.header("https://api.game.test/auth/refresh").as_deref(),
+        Some("draft=1; session=abc; region=eu; remember=yes")
+    );
+    assert_eq!(
+        jar.header("http://api.game.test/").as_deref(),
+        Some("region=eu; remember=yes")
+    );
+    assert_eq!(jar.header("https://cdn.game.test/").as_deref(), Some("region=eu"));
+    assert_eq!(jar.header("https://game.test/").as_deref(), Some("region=eu"));
+    assert_eq!(jar.header("https://other.test/"), None);
+    assert!(jar.get("https://api.game.test/authx", "draft").is_none());
+    assert!(jar.get("https://api.game.test/auth", "old").is_none());
+    assert!(jar.get("https://api.game.test/", "session").unwrap().http_only);
+
+    // plain http can neither set nor replace secure cookies, an expired cookie removes the stored one
+    set_cookies(
+        &jar,
+        "http://api.game.test/",
+        &["insecure=1; Secure", "session=; Max-Age=0"],
+    );
+    assert!(jar.get("https://api.game.test/", "insecure").is_none());
+    assert!(jar.get("https://api.game.test/", "session").is_some());
+    set_cookies(&jar, "https://api.game.test/", &["session=; Path=/; Max-Age=0"]);
+    assert!(jar.get("https://api.game.test/", "session").is_none());
+
+    jar.clear();
+    assert!(jar.cookies().is_empty());
+}
+
+#[test]
+fn cookie_jar_persistence() {
+    let path =
+        std::env::temp_dir().join(format!("bevy_cached_query_cookies_{}.json", std::process::id()));
+    let jar = CookieJar::new();
+    set_cookies(
+        &jar,
+        "https://api.game.test/",
+        &[
+            "session=abc; Path=/",
+            "remember=yes; Path=/; Max-Age=3600",
+            "legacy=1; Path=/; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+        ],
+    );
+    jar.save(&path).unwrap();
+
+    let loaded = CookieJar::load(&path).unwrap();
+    let names: Vec<String> = loaded.cookies().into_iter().map(|cookie| cookie.name).collect();
+    assert_eq!(names, vec!["remember"]);
+    assert_eq!(
+        loaded.get("https://api.game.test/", "remember"),
+        jar.get("https://api.game.test/", "remember")
+    );
+    fs::remove_file(&path).unwrap();
+    assert!(CookieJar::load(&path).unwrap().cookies().is_empty());
+}
+
+fn session_profile(transport: impl HttpTransport, jar: &CookieJar) {
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(transport));
+    let login = "http://127.0.0.1:8080/session_login";
+    let profile = "http://127.0.0.1:8080/session_profile";
+
+    for (url, query_key) in [(login, ""), (profile, "logged_in"), (profile, "logged_out")] {
+        if query_key == "logged_out" {
+            jar.clear();
+        }
+        app.world_mut().commands().trigger(
+            QueryBuilder::default()
+                .url(url)
+                .query_key(query_key)
+                .build()
+                .unwrap(),
+        );
+        app.update();
+        while !app.world().resource::<QueryStore>().loading_requests.is_empty() {
+            app.update();
+        }
+    }
+
+    let mut store = app.world_mut().resource_mut::<QueryStore>();
+    for (query_key, expected) in [("logged_in", "session=abc"), ("logged_out", "")] {
+        let response = query_extractor::<GetResponse>(
+            QueryConsumable {
+                url: profile.to_string(),
+                query_key: Some(query_key.to_string()),
+                ..default()
+            },
+            &mut store,
+        );
+        assert_eq!(response.unwrap().msg, expected);
+    }
+}
+
+#[timeout(2000)]
+#[test]
+fn cookie_jar_hyper() {
+    let jar = CookieJar::new();
+    session_profile(HyperTransport::default().with_cookie_jar(jar.clone()), &jar);
+}
+
+#[timeout(2000)]
+#[test]
+fn cookie_jar_ureq() {
+    let jar = CookieJar::new();
+    session_profile(UreqTransport::default().with_cookie_jar(jar.clone()), &jar);
+}
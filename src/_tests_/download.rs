@@ -0,0 +1,366 @@
+use crate::{
+    _tests_::util::init_test_app,
+    download::{Download, DownloadCompleted, DownloadError, DownloadFailed},
+    progress::{QueryProgress, TransferDirection},
+    tasks::QueryStore,
+    transport::{HttpRequest, HttpResponse, HttpTransport, QueryTransport, TransportError},
+};
+use bevy::{
+    prelude::*,
+    tasks::futures_lite::{io::AssertAsync, AsyncRead, AsyncReadExt},
+    utils::BoxedFuture,
+};
+use ntest::timeout;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Serves a file with support for `Range` and `If-Range` requests, the first responses can break midway
+#[derive(Clone)]
+struct FileServer {
+    file: Arc<Vec<u8>>,
+    etag: &'static str,
+    honor_range: bool,
+    /// Bytes sent before the connection breaks, for each of the next responses
+    interruptions: Arc<Mutex<Vec<usize>>>,
+    /// Bytes sent before the body stops arriving, for each of the next responses
+    stalls: Arc<Mutex<Vec<usize>>>,
+    /// `Range` and `If-Range` headers of every request received
+    ranges: Arc<Mutex<Vec<(Option<String>, Option<String>)>>>,
+}
+
+impl FileServer {
+    fn new(file: Vec<u8>) -> Self {
+        Self {
+            file: Arc::new(file),
+            etag: "\"v1\"",
+            honor_range: true,
+            interruptions: Arc::default(),
+            stalls: Arc::default(),
+            ranges: Arc::default(),
+        }
+    }
+
+    fn ranges(&self) -> Vec<Option<String>> {
+        self.ranges.lock().unwrap().iter().map(|(range, _)| range.clone()).collect()
+    }
+
+    fn if_ranges(&self) -> Vec<Option<String>> {
+        self.ranges.lock().unwrap().iter().map(|(_, if_range)| if_range.clone()).collect()
+    }
+}
+
+/// Body that never sends more data
+struct Stall;
+
+impl AsyncRead for Stall {
+    fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut [u8]) -> Poll<io::Result<usize>> {
+        Poll::Pending
+    }
+}
+
+/// Fails every read, as if the connection was reset
+struct Reset;
+
+impl Read for Reset {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::ConnectionReset.into())
+    }
+}
+
+impl HttpTransport for FileServer {
+    fn send(&self, request: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>> {
+        let range = request.header("range").map(str::to_string);
+        let if_range = request.header("if-range").map(str::to_string);
+        self.ranges.lock().unwrap().push((range.clone(), if_range.clone()));
+        let len = self.file.len();
+        if !request.url.ends_with("/file.bin") {
+            return Box::pin(async { Ok(HttpResponse::from_bytes(404, vec![], vec![])) });
+        }
+
+        let changed = if_range.is_some_and(|if_range| if_range != self.etag);
+        let start = range.filter(|_| self.honor_range && !changed).and_then(|range| {
+            range
+                .strip_prefix("bytes=")?
+                .strip_suffix('-')?
+                .parse::<usize>()
+                .ok()
+        });
+        let (status, mut headers, body) = match start {
+            Some(start) if start >= len => (
+                416,
+                vec![("content-range".to_string(), format!("bytes */{}", len))],
+                vec![],
+            ),
+            Some(start) => (
+                206,
+                vec![(
+                    "content-range".to_string(),
+                    format!("bytes {}-{}/{}", start, len - 1, len),
+                )],
+                self.file[start..].to_vec(),
+            ),
+            None => (200, vec![], self.file.to_vec()),
+        };
+        headers.push(("content-length".to_string(), body.len().to_string()));
+        headers.push(("etag".to_string(), self.etag.to_string()));
+
+        let mut interruptions = self.interruptions.lock().unwrap();
+        let mut stalls = self.stalls.lock().unwrap();
+        let response = match interruptions.is_empty() {
+            true if !stalls.is_empty() => {
+                let sent = stalls.remove(0).min(body.len());
+                HttpResponse {
+                    status,
+                    headers,
+                    body: Box::new(AsyncReadExt::chain(
+                        AssertAsync::new(io::Cursor::new(body[..sent].to_vec())),
+                        Stall,
+                    )),
+                }
+            }
+            true => HttpResponse::from_bytes(status, headers, body),
+            false => {
+                let sent = interruptions.remove(0).min(body.len());
+                HttpResponse {
+                    status,
+                    headers,
+                    body: Box::new(AssertAsync::new(
+                        io::Cursor::new(body[..sent].to_vec()).chain(Reset),
+                    )),
+                }
+            }
+        };
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+#[derive(Resource, Default)]
+struct Outcome {
+    completed: Option<DownloadCompleted>,
+    failed: Option<DownloadFailed>,
+    progress: Vec<QueryProgress>,
+}
+
+fn download_app(server: &FileServer) -> App {
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(server.clone()));
+    app.init_resource::<Outcome>();
+    app.add_observer(
+        |trigger: Trigger<DownloadCompleted>, mut outcome: ResMut<Outcome>| {
+            outcome.completed = Some(trigger.event().clone());
+        },
+    );
+    app.add_observer(|trigger: Trigger<DownloadFailed>, mut outcome: ResMut<Outcome>| {
+        outcome.failed = Some(trigger.event().clone());
+    });
+    app.add_observer(|trigger: Trigger<QueryProgress>, mut outcome: ResMut<Outcome>| {
+        outcome.progress.push(trigger.event().clone());
+    });
+    app
+}
+
+fn run_download(app: &mut App, download: Download) {
+    app.world_mut().commands().trigger(download);
+    app.update();
+    while !app.world().resource::<QueryStore>().downloads.is_empty() {
+        app.update();
+    }
+}
+
+fn test_file() -> Vec<u8> {
+    (0..300_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn destination(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "bevy_cached_query_download_{}_{}.bin",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&path);
+    let download = Download::new("", &path);
+    let _ = fs::remove_file(download.part_path());
+    let _ = fs::remove_file(download.validator_path());
+    path
+}
+
+#[timeout(2000)]
+#[test]
+fn download_resumes_after_interruptions() {
+    let file = test_file();
+    let server = FileServer::new(file.clone());
+    server.interruptions.lock().unwrap().extend([100_000, 50_000]);
+    let mut app = download_app(&server);
+    let path = destination("resume");
+    let download = Download::new("http://files.test/file.bin", &path)
+        .with_sha256(sha256(&file).to_uppercase())
+        .with_retry_delay(Duration::ZERO);
+
+    run_download(&mut app, download.clone());
+
+    let outcome = app.world().resource::<Outcome>();
+    let completed = outcome.completed.clone().unwrap();
+    assert!(outcome.failed.is_none());
+    assert_eq!(completed.path, path);
+    assert_eq!(completed.sha256, sha256(&file));
+    assert_eq!(completed.size, file.len() as u64);
+    assert_eq!(fs::read(&path).unwrap(), file);
+    assert!(!download.part_path().exists());
+    assert_eq!(
+        server.ranges(),
+        vec![
+            None,
+            Some("bytes=100000-".to_string()),
+            Some("bytes=150000-".to_string())
+        ]
+    );
+    assert_eq!(
+        server.if_ranges(),
+        vec![None, Some("\"v1\"".to_string()), Some("\"v1\"".to_string())]
+    );
+    assert!(!download.validator_path().exists());
+
+    let last = outcome.progress.last().unwrap();
+    assert_eq!(
+        last.key,
+        (
+            "http://files.test/file.bin".to_string(),
+            path.to_string_lossy().to_string()
+        )
+    );
+    assert_eq!(last.direction, TransferDirection::Download);
+    assert_eq!(
+        (last.bytes_done, last.bytes_total),
+        (file.len() as u64, Some(file.len() as u64))
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[timeout(2000)]
+#[test]
+fn download_resumes_previous_run() {
+    let file = test_file();
+    let mut server = FileServer::new(file.clone());
+    let path = destination("previous_run");
+    let download = Download::new("http://files.test/file.bin", &path);
+
+    // left over by a previous run
+    fs::write(download.part_path(), &file[..120_000]).unwrap();
+    run_download(&mut download_app(&server), download.clone());
+    assert_eq!(fs::read(&path).unwrap(), file);
+    assert_eq!(server.ranges(), vec![Some("bytes=120000-".to_string())]);
+
+    // the whole body is already on disk
+    fs::remove_file(&path).unwrap();
+    fs::write(download.part_path(), &file).unwrap();
+    run_download(&mut download_app(&server), download.clone());
+    assert_eq!(fs::read(&path).unwrap(), file);
+
+    // the server does not support ranges and sends the whole file again
+    server.honor_range = false;
+    fs::remove_file(&path).unwrap();
+    fs::write(download.part_path(), &file[..1000]).unwrap();
+    let mut app = download_app(&server);
+    run_download(&mut app, download);
+    assert_eq!(fs::read(&path).unwrap(), file);
+    assert!(app.world().resource::<Outcome>().completed.is_some());
+    fs::remove_file(&path).unwrap();
+}
+
+#[timeout(2000)]
+#[test]
+fn download_file_changed_since_previous_run() {
+    let file = test_file();
+    let server = FileServer::new(file.clone());
+    let path = destination("changed");
+    let download = Download::new("http://files.test/file.bin", &path).with_sha256(sha256(&file));
+
+    // left over by a previous run, of another version of the file
+    fs::write(download.part_path(), vec![7; 120_000]).unwrap();
+    fs::write(download.validator_path(), "\"v0\"").unwrap();
+    let mut app = download_app(&server);
+    run_download(&mut app, download.clone());
+
+    assert!(app.world().resource::<Outcome>().completed.is_some());
+    assert_eq!(fs::read(&path).unwrap(), file);
+    assert_eq!(server.ranges(), vec![Some("bytes=120000-".to_string())]);
+    assert_eq!(server.if_ranges(), vec![Some("\"v0\"".to_string())]);
+    assert!(!download.validator_path().exists());
+    fs::remove_file(&path).unwrap();
+}
+
+#[timeout(2000)]
+#[test]
+fn download_resumes_stalled_body() {
+    let file = test_file();
+    let server = FileServer::new(file.clone());
+    server.stalls.lock().unwrap().push(1000);
+    let mut app = download_app(&server);
+    let path = destination("stalled");
+    let download = Download::new("http://files.test/file.bin", &path)
+        .with_read_timeout(Duration::from_millis(50))
+        .with_retry_delay(Duration::ZERO);
+
+    run_download(&mut app, download);
+
+    assert!(app.world().resource::<Outcome>().completed.is_some());
+    assert_eq!(fs::read(&path).unwrap(), file);
+    assert_eq!(server.ranges(), vec![None, Some("bytes=1000-".to_string())]);
+    fs::remove_file(&path).unwrap();
+}
+
+#[timeout(2000)]
+#[test]
+fn download_failures() {
+    let file = test_file();
+    let server = FileServer::new(file.clone());
+    let mut app = download_app(&server);
+    let path = destination("failures");
+
+    let download = Download::new("http://files.test/file.bin", &path).with_sha256(sha256(b"other"));
+    run_download(&mut app, download.clone());
+    let failed = app.world_mut().resource_mut::<Outcome>().failed.take().unwrap();
+    assert_eq!(
+        failed.error,
+        DownloadError::ChecksumMismatch {
+            expected: sha256(b"other"),
+            actual: sha256(&file),
+        }
+    );
+    assert!(!path.exists());
+    assert!(!download.part_path().exists());
+
+    run_download(&mut app, Download::new("http://files.test/missing.bin", &path));
+    let failed = app.world_mut().resource_mut::<Outcome>().failed.take().unwrap();
+    assert_eq!(failed.error, DownloadError::Status(404));
+
+    server.interruptions.lock().unwrap().extend([10, 10, 10]);
+    let download = Download::new("http://files.test/file.bin", &path)
+        .with_max_retries(2)
+        .with_retry_delay(Duration::ZERO);
+    run_download(&mut app, download.clone());
+    let failed = app.world_mut().resource_mut::<Outcome>().failed.take().unwrap();
+    assert!(matches!(
+        failed.error,
+        DownloadError::Transport(TransportError::Connection(_))
+    ));
+    // kept to resume later
+    assert_eq!(fs::metadata(download.part_path()).unwrap().len(), 30);
+    assert!(app.world().resource::<Outcome>().completed.is_none());
+    fs::remove_file(download.part_path()).unwrap();
+}
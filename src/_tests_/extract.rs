@@ -1,6 +1,6 @@
 use crate::{
     _tests_::util::{init_test_app, GetResponse},
-    extractor::{query_extractor, QueryConsumable},
+    extractor::{extract_if_changed, query_extractor, QueryConsumable, QueryCursor},
     tasks::{Method, QueryBuilder, QueryStore},
 };
 use bevy::prelude::*;
@@ -56,23 +56,70 @@ fn force_next_refetch() {
             .unwrap(),
     );
 
-    for index in 0..2 {
+    let consumable = QueryConsumable {
+        url: url.to_string(),
+        force_next_refetch: true,
+        ..default()
+    };
+    loop {
         let mut store = app.world_mut().get_resource_mut::<QueryStore>().unwrap();
-        let result = query_extractor::<GetResponse>(
-            QueryConsumable {
-                url: url.to_string(),
-                force_next_refetch: true,
-                ..default()
-            },
-            &mut store,
-        );
-        if index > 0 {
-            assert!(result.is_err());
-        } else if let Ok(response) = result {
+        if let Ok(response) = query_extractor::<GetResponse>(consumable.clone(), &mut store) {
             assert_eq!(response.msg, "Should be consumed once");
             break;
         }
 
         app.update();
     }
+
+    let mut store = app.world_mut().get_resource_mut::<QueryStore>().unwrap();
+    assert!(query_extractor::<GetResponse>(consumable, &mut store).is_err());
+}
+
+#[timeout(2000)]
+#[test]
+fn extract_once_until_changed() {
+    let url = "http://127.0.0.1:8080/extract_if_changed";
+    let consumable = QueryConsumable {
+        url: url.to_string(),
+        ..default()
+    };
+    let mut cursor = QueryCursor::default();
+    let mut app = init_test_app();
+
+    for _ in 0..2 {
+        app.world_mut().commands().trigger(
+            QueryBuilder::default()
+                .method(Method::Get)
+                .url(url.to_string())
+                .build()
+                .unwrap(),
+        );
+
+        loop {
+            let mut store = app.world_mut().get_resource_mut::<QueryStore>().unwrap();
+            let result = extract_if_changed::<GetResponse>(consumable.clone(), &mut store, &mut cursor);
+            if let Some(response) = result {
+                assert_eq!(response.unwrap().msg, "changed");
+                break;
+            }
+
+            app.update();
+        }
+
+        let mut store = app.world_mut().get_resource_mut::<QueryStore>().unwrap();
+        assert!(
+            extract_if_changed::<GetResponse>(consumable.clone(), &mut store, &mut cursor).is_none()
+        );
+
+        // another consumer forcing a refetch makes the next response a new version
+        let forced = QueryConsumable {
+            force_next_refetch: true,
+            ..consumable.clone()
+        };
+        assert!(query_extractor::<GetResponse>(forced, &mut store).is_ok());
+        assert!(store.versions.is_empty());
+    }
+
+    let mut store = app.world_mut().get_resource_mut::<QueryStore>().unwrap();
+    assert!(extract_if_changed::<GetResponse>(consumable.clone(), &mut store, &mut cursor).is_none());
 }
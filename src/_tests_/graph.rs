@@ -0,0 +1,103 @@
+use crate::{
+    _tests_::util::init_test_app,
+    graph::{QueryGraph, QueryGraphCompleted, QueryNode},
+    tasks::{Method, QueryBuilder, QueryStore},
+};
+use bevy::prelude::*;
+use ntest::{assert_true, timeout};
+
+#[derive(Resource, Default)]
+struct GraphResult(Option<QueryGraphCompleted>);
+
+fn node(id: &str, depends_on: &[&str]) -> QueryNode {
+    QueryNode {
+        id: id.to_string(),
+        query: QueryBuilder::default()
+            .method(Method::Get)
+            .url(format!("http://127.0.0.1:8080/graph_{}", id))
+            .build()
+            .unwrap(),
+        depends_on: depends_on.iter().map(|dependency| dependency.to_string()).collect(),
+    }
+}
+
+fn run_graph(nodes: Vec<QueryNode>) -> (App, QueryGraphCompleted) {
+    let mut app = init_test_app();
+    app.init_resource::<GraphResult>();
+    app.add_observer(|t: Trigger<QueryGraphCompleted>, mut result: ResMut<GraphResult>| {
+        result.0 = Some(t.event().clone());
+    });
+
+    app.world_mut().commands().trigger(QueryGraph {
+        key: "boot".to_string(),
+        nodes,
+    });
+
+    loop {
+        if let Some(result) = app.world().resource::<GraphResult>().0.clone() {
+            return (app, result);
+        }
+        app.update();
+    }
+}
+
+#[timeout(2000)]
+#[test]
+fn graph() {
+    let (app, result) = run_graph(vec![
+        node("auth", &[]),
+        node("profile", &["auth"]),
+        node("inventory", &["auth"]),
+        node("settings", &["auth"]),
+        node("matchmaking", &["profile", "inventory", "settings"]),
+    ]);
+
+    assert_eq!(result.key, "boot");
+    assert_eq!(result.results.len(), 5);
+    assert_true!(result.failed.is_empty());
+    assert_eq!(result.results["matchmaking"]["body"]["msg"], "matchmaking");
+
+    let store = app.world().resource::<QueryStore>();
+    let called_at = |id: &str| {
+        store
+            .cache
+            .get(&(format!("http://127.0.0.1:8080/graph_{}", id), String::new()))
+            .unwrap()
+            .2
+    };
+    for id in ["profile", "inventory", "settings"] {
+        assert!(called_at("auth") <= called_at(id), "{} was called before auth", id);
+        assert!(called_at(id) <= called_at("matchmaking"), "matchmaking was called before {}", id);
+    }
+    assert_true!(store.graphs.is_empty());
+}
+
+#[timeout(2000)]
+#[test]
+fn graph_failed_dependency() {
+    let (app, result) = run_graph(vec![
+        node("auth", &[]),
+        node("fail", &["auth"]),
+        node("profile", &["fail"]),
+        node("matchmaking", &["profile"]),
+    ]);
+
+    assert_eq!(result.results.len(), 2);
+    let mut failed = result.failed.clone();
+    failed.sort();
+    assert_eq!(failed, vec!["fail", "matchmaking", "profile"]);
+
+    let store = app.world().resource::<QueryStore>();
+    assert_true!(!store
+        .cache
+        .contains_key(&("http://127.0.0.1:8080/graph_profile".to_string(), String::new())));
+}
+
+#[timeout(1000)]
+#[test]
+fn graph_cycle() {
+    let (_, result) = run_graph(vec![node("profile", &["settings"]), node("settings", &["profile"])]);
+
+    assert_true!(result.results.is_empty());
+    assert_eq!(result.failed.len(), 2);
+}
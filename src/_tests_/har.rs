@@ -0,0 +1,269 @@
+use crate::{
+    _tests_::util::init_test_app,
+    tasks::{response_status, Method, QueryBuilder, QueryStore},
+    transport::{
+        har::{Har, HarNameValue, HarRecorder, HarReplay, ReplayStrictness, REDACTED},
+        mock::{MockResponse, MockTransport, RequestMatcher},
+        HttpRequest, HttpResponse, HttpTransport, QueryTransport, TransportError,
+    },
+    Query,
+};
+use bevy::{
+    prelude::*,
+    tasks::{
+        block_on,
+        futures_lite::{future, AsyncRead, AsyncReadExt},
+    },
+    utils::BoxedFuture,
+};
+use ntest::timeout;
+use serde_json::json;
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Sends `query` and updates the app until its response is cached
+fn fetch(app: &mut App, query: Query) -> serde_json::Value {
+    let key = (query.url.clone(), query.query_key.clone().unwrap_or_default());
+    app.world_mut().resource_mut::<QueryStore>().cache.remove(&key);
+    app.world_mut().commands().trigger(query);
+    loop {
+        app.update();
+        if let Some((value, _, _)) = app.world().resource::<QueryStore>().cache.get(&key) {
+            return value.clone();
+        }
+    }
+}
+
+fn get(url: &str, params: &[(&str, &str)]) -> Query {
+    QueryBuilder::default()
+        .url(url)
+        .params(
+            params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>(),
+        )
+        .build()
+        .unwrap()
+}
+
+fn record() -> Har {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/items"),
+        MockResponse::json(json!({"items": [1, 2]})),
+    )
+    .on(
+        RequestMatcher::post("/items"),
+        MockResponse::json(json!({"created": true})).with_header("Location", "/items/3"),
+    );
+    let recorder = HarRecorder::new(mock);
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(recorder.clone()));
+
+    fetch(
+        &mut app,
+        get("http://har.test/items", &[("page", "1"), ("sort", "name asc")]),
+    );
+    fetch(
+        &mut app,
+        QueryBuilder::default()
+            .method(Method::Post)
+            .url("http://har.test/items")
+            .body(json!({"name": "sword", "damage": 3}))
+            .build()
+            .unwrap(),
+    );
+
+    recorder.har()
+}
+
+#[timeout(1000)]
+#[test]
+fn har_record() {
+    let har = record();
+    let entries = &har.log.entries;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].request.method, "GET");
+    assert_eq!(
+        entries[0].request.url,
+        "http://har.test/items?page=1&sort=name%20asc"
+    );
+    assert_eq!(entries[0].response.status, 200);
+    assert_eq!(
+        entries[0].response.content.text.as_deref(),
+        Some("{\"items\":[1,2]}")
+    );
+    assert_eq!(
+        entries[1].request.post_data.as_ref().unwrap().mime_type,
+        "application/json"
+    );
+
+    let path = std::env::temp_dir().join("bevy_cached_query_har_record.har");
+    har.save(&path).unwrap();
+    assert_eq!(Har::from_file(&path).unwrap(), har);
+}
+
+#[timeout(1000)]
+#[test]
+fn har_replay() {
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(HarReplay::new(record())));
+
+    // parameters in another order and a body with reordered keys still match
+    let value = fetch(
+        &mut app,
+        get("http://har.test/items?sort=name%20asc", &[("page", "1")]),
+    );
+    assert_eq!(value["body"], json!({"items": [1, 2]}));
+    let value = fetch(
+        &mut app,
+        QueryBuilder::default()
+            .method(Method::Post)
+            .url("http://har.test/items")
+            .body(json!({"damage": 3, "name": "sword"}))
+            .build()
+            .unwrap(),
+    );
+    assert_eq!(value["body"], json!({"created": true}));
+    assert_eq!(value["headers"]["location"], "/items/3");
+
+    // a different page was not recorded
+    let value = fetch(&mut app, get("http://har.test/items", &[("page", "2")]));
+    assert_eq!(response_status(&value), 500);
+}
+
+#[timeout(1000)]
+#[test]
+fn har_replay_strictness() {
+    let fallback = MockTransport::new();
+    fallback.on(
+        RequestMatcher::get("/live"),
+        MockResponse::json(json!({"live": true})),
+    );
+
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(
+        HarReplay::new(record()).with_strictness(ReplayStrictness::NotFound),
+    ));
+    let value = fetch(&mut app, get("http://har.test/live", &[]));
+    assert_eq!(response_status(&value), 404);
+
+    app.insert_resource(QueryTransport::new(
+        HarReplay::new(record())
+            .with_strictness(ReplayStrictness::Passthrough(Arc::new(fallback.clone()))),
+    ));
+    let value = fetch(&mut app, get("http://har.test/live", &[]));
+    assert_eq!(value["body"], json!({"live": true}));
+    let value = fetch(
+        &mut app,
+        get("http://har.test/items", &[("page", "1"), ("sort", "name asc")]),
+    );
+    assert_eq!(value["body"], json!({"items": [1, 2]}));
+    fallback.assert_requested(&RequestMatcher::any("*"), 1);
+}
+
+#[timeout(1000)]
+#[test]
+fn har_redact_credentials() {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/profile"),
+        MockResponse::json(json!({"name": "ada"})).with_header("Set-Cookie", "session=abc"),
+    );
+    let header = |name: &str, value: &str| HarNameValue {
+        name: name.to_string(),
+        value: value.to_string(),
+    };
+    let query = QueryBuilder::default()
+        .url("http://har.test/profile")
+        .headers(vec![
+            ("Authorization".to_string(), "Bearer secret".to_string()),
+            ("X-Api-Key".to_string(), "key".to_string()),
+        ])
+        .build()
+        .unwrap();
+
+    let recorder = HarRecorder::new(mock.clone());
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(recorder.clone()));
+    fetch(&mut app, query.clone());
+
+    let entry = &recorder.har().log.entries[0];
+    assert!(entry.request.headers.contains(&header("Authorization", REDACTED)));
+    assert!(entry.request.headers.contains(&header("X-Api-Key", "key")));
+    assert!(entry.response.headers.contains(&header("set-cookie", REDACTED)));
+
+    // other headers can be redacted instead, or none of them
+    let recorder = HarRecorder::new(mock).with_redacted_headers(["x-api-key"]);
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(recorder.clone()));
+    fetch(&mut app, query);
+
+    let entry = &recorder.har().log.entries[0];
+    assert!(entry.request.headers.contains(&header("Authorization", "Bearer secret")));
+    assert!(entry.request.headers.contains(&header("X-Api-Key", REDACTED)));
+}
+
+/// Body sending one NDJSON line and then nothing, like a stream waiting for its next item
+struct OpenStream(Option<&'static [u8]>);
+
+impl AsyncRead for OpenStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.0.take() {
+            Some(line) => {
+                buf[..line.len()].copy_from_slice(line);
+                Poll::Ready(Ok(line.len()))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+struct StreamTransport;
+
+impl HttpTransport for StreamTransport {
+    fn send(&self, _: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>> {
+        Box::pin(async {
+            Ok(HttpResponse {
+                status: 200,
+                headers: vec![("content-type".to_string(), "application/x-ndjson".to_string())],
+                body: Box::new(OpenStream(Some(b"{\"day\":1}\n"))),
+            })
+        })
+    }
+}
+
+#[timeout(1000)]
+#[test]
+fn har_record_stream() {
+    let recorder = HarRecorder::new(StreamTransport);
+    let mut response = block_on(recorder.send(HttpRequest {
+        url: "http://har.test/visits".to_string(),
+        ..default()
+    }))
+    .unwrap();
+
+    // the first line is handed over while the body is still open
+    let mut buf = [0; 64];
+    let read = block_on(response.body.read(&mut buf)).unwrap();
+    assert_eq!(&buf[..read], b"{\"day\":1}\n");
+    assert!(block_on(future::poll_once(response.body.read(&mut buf))).is_none());
+    assert!(recorder.har().log.entries.is_empty());
+
+    // closing the stream records what was received
+    drop(response);
+    let entries = recorder.har().log.entries;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].response.status, 200);
+    assert_eq!(entries[0].response.content.text.as_deref(), Some("{\"day\":1}\n"));
+    assert_eq!(entries[0].response.content.mime_type, "application/x-ndjson");
+}
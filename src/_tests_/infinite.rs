@@ -0,0 +1,165 @@
+use crate::{
+    _tests_::util::{init_test_app, mock_app},
+    extractor::infinite_query_extractor,
+    infinite::{
+        has_next_page, has_previous_page, parse_link_header, FetchNextPage, FetchPreviousPage,
+        InfiniteQuery, PageParam,
+    },
+    tasks::{Method, QueryBuilder, QueryStore},
+    transport::mock::{MockResponse, MockTransport, RequestMatcher},
+};
+use bevy::prelude::*;
+use ntest::{assert_false, assert_true, timeout};
+use serde_json::json;
+use std::time::Duration;
+
+fn wait_for_page(app: &mut App, key: &str) {
+    loop {
+        app.update();
+        let store = app.world().resource::<QueryStore>();
+        if !store.infinite_queries[key].is_fetching_page() {
+            break;
+        }
+    }
+}
+
+#[timeout(2000)]
+#[test]
+fn infinite_query_cursor() {
+    let mut app = init_test_app();
+
+    app.world_mut().commands().trigger(InfiniteQuery {
+        key: "leaderboard".to_string(),
+        query: QueryBuilder::default()
+            .method(Method::Get)
+            .url("http://127.0.0.1:8080/pages")
+            .build()
+            .unwrap(),
+        items_pointer: "/items".to_string(),
+        ..default()
+    });
+    wait_for_page(&mut app, "leaderboard");
+
+    let store = app.world().resource::<QueryStore>();
+    assert_eq!(
+        infinite_query_extractor::<u32>("leaderboard", store).unwrap(),
+        vec![1, 2]
+    );
+    assert_true!(has_next_page("leaderboard", store));
+    assert_false!(has_previous_page("leaderboard", store));
+
+    for _ in 0..2 {
+        app.world_mut().commands().trigger(FetchNextPage {
+            key: "leaderboard".to_string(),
+        });
+        wait_for_page(&mut app, "leaderboard");
+    }
+
+    let store = app.world().resource::<QueryStore>();
+    assert_eq!(
+        infinite_query_extractor::<u32>("leaderboard", store).unwrap(),
+        vec![1, 2, 3, 4, 5]
+    );
+    assert_false!(has_next_page("leaderboard", store));
+    assert_eq!(store.cache.len(), 1);
+}
+
+#[timeout(2000)]
+#[test]
+fn infinite_query_link_header() {
+    let mut app = init_test_app();
+
+    app.world_mut().commands().trigger(InfiniteQuery {
+        key: "match_history".to_string(),
+        query: QueryBuilder::default()
+            .method(Method::Get)
+            .url("http://127.0.0.1:8080/link_pages?page=2")
+            .build()
+            .unwrap(),
+        page_param: PageParam::LinkHeader,
+        ..default()
+    });
+    wait_for_page(&mut app, "match_history");
+    assert_true!(has_previous_page(
+        "match_history",
+        app.world().resource::<QueryStore>()
+    ));
+
+    app.world_mut().commands().trigger(FetchPreviousPage {
+        key: "match_history".to_string(),
+    });
+    wait_for_page(&mut app, "match_history");
+
+    let store = app.world().resource::<QueryStore>();
+    assert_eq!(
+        infinite_query_extractor::<u32>("match_history", store).unwrap(),
+        vec![1, 2, 3, 4]
+    );
+    assert_false!(has_previous_page("match_history", store));
+    assert_true!(has_next_page("match_history", store));
+}
+
+#[timeout(2000)]
+#[test]
+fn infinite_query_triggered_again() {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/pages"),
+        MockResponse::json(json!({"items": [3, 4], "next_cursor": null})),
+    )
+    .on_times(
+        RequestMatcher::get("/pages"),
+        MockResponse::json(json!({"items": [1, 2], "next_cursor": "2"}))
+            .with_delay(Duration::from_millis(200)),
+        1,
+    );
+    let mut app = mock_app(&mock);
+    let leaderboard = InfiniteQuery {
+        key: "leaderboard".to_string(),
+        query: QueryBuilder::default()
+            .method(Method::Get)
+            .url("http://mock.test/pages")
+            .build()
+            .unwrap(),
+        items_pointer: "/items".to_string(),
+        ..default()
+    };
+
+    app.world_mut().commands().trigger(leaderboard.clone());
+    app.update();
+    // the first page of the first run is still loading
+    app.world_mut().commands().trigger(leaderboard);
+    wait_for_page(&mut app, "leaderboard");
+    std::thread::sleep(Duration::from_millis(250));
+    app.update();
+
+    let store = app.world().resource::<QueryStore>();
+    assert_eq!(
+        infinite_query_extractor::<u32>("leaderboard", store).unwrap(),
+        vec![3, 4]
+    );
+    assert_false!(has_next_page("leaderboard", store));
+    assert_eq!(store.cache.len(), 1);
+    mock.assert_requested(&RequestMatcher::get("/pages"), 2);
+}
+
+#[test]
+fn link_header() {
+    let links = parse_link_header(
+        "<https://api.example.com/items?page=3>; rel=\"next\", <https://api.example.com/items?page=1>; rel=prev",
+    );
+
+    assert_eq!(
+        links,
+        vec![
+            (
+                "https://api.example.com/items?page=3".to_string(),
+                "next".to_string()
+            ),
+            (
+                "https://api.example.com/items?page=1".to_string(),
+                "prev".to_string()
+            ),
+        ]
+    );
+}
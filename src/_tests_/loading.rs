@@ -1,5 +1,5 @@
 use crate::{
-    _tests_::util::init_test_app,
+    _tests_::util::{init_test_app, wait_for},
     tasks::{Method, QueryBuilder, QueryStore},
 };
 use ntest::{assert_true, timeout};
@@ -19,7 +19,12 @@ fn loading() {
             .build()
             .unwrap(),
     );
-    app.update();
+    // runs the observers without polling, a local server may answer before the next poll
+    app.world_mut().flush();
     let store = app.world().get_resource::<QueryStore>().unwrap();
     assert_true!(store.loading_requests.iter().len() == 1);
+
+    wait_for(&mut app, url);
+    let store = app.world().get_resource::<QueryStore>().unwrap();
+    assert_true!(store.loading_requests.is_empty());
 }
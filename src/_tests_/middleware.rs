@@ -0,0 +1,195 @@
+use crate::{
+    _tests_::util::{mock_app, wait_for},
+    auth::{AuthProvider, QueryAuth},
+    middleware::{QueryMiddleware, QueryMiddlewareStack},
+    tasks::QueryBuilder,
+    transport::{
+        mock::{MockResponse, MockTransport, RequestMatcher},
+        FetchedResponse, HttpRequest, HttpTransport, TransportError,
+    },
+    Query,
+};
+use bevy::{prelude::*, utils::BoxedFuture};
+use ntest::timeout;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+
+/// Adds a header to requests and records the order the hooks ran in
+struct Trace {
+    name: &'static str,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl QueryMiddleware for Trace {
+    fn on_request(&self, query: &mut Query) -> Option<FetchedResponse> {
+        self.log.lock().unwrap().push(format!("request {}", self.name));
+        query
+            .headers
+            .get_or_insert_with(Vec::new)
+            .push(("X-Trace".to_string(), self.name.to_string()));
+        None
+    }
+
+    fn on_response(&self, _query: &Query, _response: &mut Result<FetchedResponse, TransportError>) {
+        self.log.lock().unwrap().push(format!("response {}", self.name));
+    }
+}
+
+/// Answers `/offline/*` queries without the transport and wraps every other body
+struct Rewrite;
+
+impl QueryMiddleware for Rewrite {
+    fn on_request(&self, query: &mut Query) -> Option<FetchedResponse> {
+        query.url.contains("/offline/").then(|| FetchedResponse {
+            status: 200,
+            headers: vec![],
+            body: br#"{"msg": "synthetic"}"#.to_vec(),
+            ..default()
+        })
+    }
+
+    fn on_response(&self, query: &Query, response: &mut Result<FetchedResponse, TransportError>) {
+        if let Ok(response) = response {
+            let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+            response.body = serde_json::to_vec(&json!({"url": query.url, "data": body})).unwrap();
+        }
+    }
+}
+
+fn middleware_app(mock: &MockTransport, middleware: QueryMiddlewareStack) -> App {
+    mock.on(
+        RequestMatcher::any("*"),
+        MockResponse::json(json!({"msg": "served"})),
+    );
+    let mut app = mock_app(mock);
+    app.insert_resource(middleware);
+    app
+}
+
+#[timeout(1000)]
+#[test]
+fn middleware_order() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let mock = MockTransport::new();
+    let mut app = middleware_app(
+        &mock,
+        QueryMiddlewareStack::default()
+            .with(Trace {
+                name: "outer",
+                log: log.clone(),
+            })
+            .with(Trace {
+                name: "inner",
+                log: log.clone(),
+            })
+            .with(Rewrite),
+    );
+
+    let url = "http://api.test/profile";
+    app.world_mut()
+        .commands()
+        .trigger(QueryBuilder::default().url(url).build().unwrap());
+    let (value, query) = wait_for(&mut app, url);
+
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![
+            "request outer",
+            "request inner",
+            "response inner",
+            "response outer"
+        ]
+    );
+    let sent = &mock.requests()[0];
+    let traces: Vec<&str> = sent
+        .headers
+        .iter()
+        .filter(|(name, _)| name == "X-Trace")
+        .map(|(_, value)| value.as_str())
+        .collect();
+    assert_eq!(traces, vec!["outer", "inner"]);
+    assert_eq!(value["body"], json!({"url": url, "data": {"msg": "served"}}));
+    // the cache keeps the query as it was triggered
+    assert_eq!(query.headers, None);
+}
+
+#[timeout(1000)]
+#[test]
+fn middleware_short_circuit() {
+    let log = Arc::new(Mutex::new(vec![]));
+    let mock = MockTransport::new();
+    let mut app = middleware_app(
+        &mock,
+        QueryMiddlewareStack::default()
+            .with(Trace {
+                name: "outer",
+                log: log.clone(),
+            })
+            .with(Rewrite)
+            .with(Trace {
+                name: "inner",
+                log: log.clone(),
+            }),
+    );
+
+    let url = "http://api.test/offline/profile";
+    app.world_mut()
+        .commands()
+        .trigger(QueryBuilder::default().url(url).build().unwrap());
+    let (value, _) = wait_for(&mut app, url);
+
+    assert!(mock.requests().is_empty());
+    assert_eq!(*log.lock().unwrap(), vec!["request outer", "response outer"]);
+    assert_eq!(value["body"], json!({"url": url, "data": {"msg": "synthetic"}}));
+}
+
+struct StaticAuth;
+
+impl AuthProvider for StaticAuth {
+    fn authorize(&self, request: &mut HttpRequest) {
+        request.set_header("Authorization", "Bearer token");
+    }
+
+    fn refresh(
+        &self,
+        _transport: Arc<dyn HttpTransport>,
+    ) -> BoxedFuture<'static, Result<(), TransportError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Signs the credentials of the request
+struct Sign;
+
+impl QueryMiddleware for Sign {
+    fn on_request(&self, query: &mut Query) -> Option<FetchedResponse> {
+        let headers = query.headers.get_or_insert_with(Vec::new);
+        let authorization = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+            .map_or("nothing", |(_, value)| value.as_str());
+        let signature = format!("signed {}", authorization);
+        headers.push(("X-Signature".to_string(), signature));
+        None
+    }
+}
+
+#[timeout(1000)]
+#[test]
+fn middleware_sees_credentials() {
+    let mock = MockTransport::new();
+    let mut app = middleware_app(&mock, QueryMiddlewareStack::default().with(Sign));
+    app.insert_resource(QueryAuth::new(StaticAuth));
+
+    let url = "http://api.test/profile";
+    app.world_mut()
+        .commands()
+        .trigger(QueryBuilder::default().url(url).build().unwrap());
+    let (_, query) = wait_for(&mut app, url);
+
+    let sent = &mock.requests()[0];
+    assert_eq!(sent.header("X-Signature"), Some("signed Bearer token"));
+    assert_eq!(sent.header("Authorization"), Some("Bearer token"));
+    // the credentials are not cached
+    assert_eq!(query.headers, None);
+}
@@ -0,0 +1,116 @@
+use crate::{
+    _tests_::util::{mock_app, wait_for, GetResponse},
+    extractor::{query_extractor, QueryConsumable},
+    tasks::{response_status, Method, QueryBuilder, QueryStore},
+    transport::{
+        mock::{MockResponse, MockTransport, RequestMatcher},
+        TransportError,
+    },
+};
+use bevy::prelude::*;
+use ntest::timeout;
+use serde_json::json;
+use std::time::Duration;
+
+/// Updates the app until the entry of `url` is cached and returns its status
+fn wait_for_status(app: &mut App, url: &str) -> u16 {
+    response_status(&wait_for(app, url).0)
+}
+
+#[timeout(1000)]
+#[test]
+fn mock_transport() {
+    let url = "http://mock.test/profile";
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/profile"),
+        MockResponse::json(json!({"msg": "mocked"})).with_header("X-Served-By", "mock"),
+    );
+    let mut app = mock_app(&mock);
+
+    app.world_mut().commands().trigger(
+        QueryBuilder::default()
+            .url(url)
+            .headers(vec![("X-Client".to_string(), "game".to_string())])
+            .build()
+            .unwrap(),
+    );
+    assert_eq!(wait_for_status(&mut app, url), 200);
+
+    let mut store = app.world_mut().resource_mut::<QueryStore>();
+    assert_eq!(
+        store.cache[&(url.to_string(), String::new())].0["headers"]["x-served-by"],
+        "mock"
+    );
+    let response = query_extractor::<GetResponse>(
+        QueryConsumable {
+            url: url.to_string(),
+            ..default()
+        },
+        &mut store,
+    )
+    .unwrap();
+    assert_eq!(response.msg, "mocked");
+
+    mock.assert_requested(&RequestMatcher::get("/profile").header("x-client", "game"), 1);
+    mock.assert_requested(&RequestMatcher::get("/profile").header("x-client", "editor"), 0);
+    mock.assert_requested(&RequestMatcher::post("/profile"), 0);
+}
+
+#[timeout(1000)]
+#[test]
+fn mock_transport_routes() {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::any("http://mock.test/items/*"),
+        MockResponse::status(503),
+    )
+    .on(
+        RequestMatcher::delete("http://mock.test/items/*"),
+        MockResponse::json(json!({})).with_status(201),
+    )
+    .on(
+        RequestMatcher::get("/broken"),
+        MockResponse::error(TransportError::Timeout),
+    );
+    let mut app = mock_app(&mock);
+
+    for (method, url) in [
+        (Method::Get, "http://mock.test/items/1"),
+        (Method::Delete, "http://mock.test/items/2"),
+        (Method::Get, "http://mock.test/broken"),
+        (Method::Get, "http://mock.test/unknown"),
+    ] {
+        app.world_mut()
+            .commands()
+            .trigger(QueryBuilder::default().method(method).url(url).build().unwrap());
+    }
+
+    assert_eq!(wait_for_status(&mut app, "http://mock.test/items/1"), 503);
+    assert_eq!(wait_for_status(&mut app, "http://mock.test/items/2"), 200);
+    assert_eq!(wait_for_status(&mut app, "http://mock.test/broken"), 500);
+    assert_eq!(wait_for_status(&mut app, "http://mock.test/unknown"), 404);
+    assert_eq!(mock.requests().len(), 4);
+    mock.assert_requested(&RequestMatcher::any("/items/*"), 2);
+}
+
+#[timeout(1000)]
+#[test]
+fn mock_transport_delay() {
+    let url = "http://mock.test/slow";
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get(url),
+        MockResponse::json(json!({"msg": "slow"})).with_delay(Duration::from_millis(100)),
+    );
+    let mut app = mock_app(&mock);
+
+    app.world_mut()
+        .commands()
+        .trigger(QueryBuilder::default().url(url).build().unwrap());
+    app.update();
+    assert_eq!(app.world().resource::<QueryStore>().loading_requests.len(), 1);
+    assert!(app.world().resource::<QueryStore>().cache.is_empty());
+
+    assert_eq!(wait_for_status(&mut app, url), 200);
+}
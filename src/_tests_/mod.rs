@@ -1,12 +1,50 @@
 #[cfg(test)]
+mod asset;
+#[cfg(test)]
+mod auth;
+#[cfg(test)]
+mod batch;
+#[cfg(test)]
+mod cached_query;
+#[cfg(test)]
 mod collision;
 #[cfg(test)]
+mod compression;
+#[cfg(test)]
+mod cookies;
+#[cfg(test)]
+mod download;
+#[cfg(test)]
 mod extract;
 #[cfg(test)]
+mod graph;
+#[cfg(test)]
+mod har;
+#[cfg(test)]
+mod infinite;
+#[cfg(test)]
 mod loading;
 #[cfg(test)]
+mod middleware;
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod offline;
+#[cfg(test)]
+mod progress;
+#[cfg(test)]
+mod rate_limit;
+#[cfg(test)]
+mod scheduler;
+#[cfg(test)]
 mod sequence;
 #[cfg(test)]
 mod staletime;
 #[cfg(test)]
+mod stream;
+#[cfg(test)]
+mod transport;
+#[cfg(test)]
 mod util;
+#[cfg(test)]
+mod websocket;
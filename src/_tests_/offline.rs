@@ -0,0 +1,249 @@
+use crate::{
+    _tests_::util::{cached_status, mock_app},
+    offline::{sync_offline_mode, MutationDropped, OfflineMode, OnlineStatus, OnlineStatusChanged},
+    tasks::{Method, QueryBuilder, QueryStore},
+    transport::{
+        mock::{MockResponse, MockTransport, RequestMatcher},
+        TransportError,
+    },
+    Query,
+};
+use bevy::prelude::*;
+use ntest::timeout;
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    fs,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+fn offline_app(mock: &MockTransport, offline_mode: OfflineMode) -> App {
+    let mut app = mock_app(mock);
+    app.insert_resource(offline_mode);
+    app.add_systems(Update, sync_offline_mode);
+    app
+}
+
+fn post(url: &str) -> Query {
+    QueryBuilder::default()
+        .method(Method::Post)
+        .url(url)
+        .body(json!({"done": true}))
+        .build()
+        .unwrap()
+}
+
+#[timeout(3000)]
+#[test]
+fn offline_queue() {
+    let path =
+        std::env::temp_dir().join(format!("bevy_cached_query_offline_{}.json", std::process::id()));
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::any("*"),
+        MockResponse::error(TransportError::Connection("unreachable".to_string())),
+    );
+    let mut app = offline_app(
+        &mock,
+        OfflineMode::default()
+            .with_probe_url("http://api.test/health")
+            .with_probe_interval(Duration::from_millis(50))
+            .with_queue_path(&path),
+    );
+    let changes = Arc::new(Mutex::new(vec![]));
+    let observed = changes.clone();
+    app.add_observer(move |trigger: Trigger<OnlineStatusChanged>| {
+        observed.lock().unwrap().push(trigger.event().status);
+    });
+
+    app.world_mut().commands().trigger(
+        QueryBuilder::default()
+            .url("http://api.test/profile")
+            .refetch_on_reconnect(true)
+            .build()
+            .unwrap(),
+    );
+    app.world_mut()
+        .commands()
+        .trigger(post("http://api.test/quest/1"));
+    while *app.world().resource::<OnlineStatus>() == OnlineStatus::Online {
+        app.update();
+    }
+    app.world_mut()
+        .commands()
+        .trigger(post("http://api.test/quest/2"));
+    app.update();
+
+    assert_eq!(cached_status(&app, "http://api.test/profile"), Some(500));
+    assert_eq!(cached_status(&app, "http://api.test/quest/1"), None);
+    let persisted: VecDeque<Query> = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    let urls: Vec<&str> = persisted.iter().map(|query| query.url.as_str()).collect();
+    assert_eq!(urls, vec!["http://api.test/quest/1", "http://api.test/quest/2"]);
+
+    mock.on(RequestMatcher::any("*"), MockResponse::json(json!({"msg": ""})));
+    while cached_status(&app, "http://api.test/quest/2").is_none()
+        || cached_status(&app, "http://api.test/profile") != Some(200)
+    {
+        app.update();
+    }
+
+    let sent: Vec<String> = mock
+        .requests_matching(&RequestMatcher::post("/quest/*"))
+        .into_iter()
+        .map(|request| request.url)
+        .collect();
+    assert_eq!(
+        sent,
+        vec![
+            "http://api.test/quest/1",
+            "http://api.test/quest/1",
+            "http://api.test/quest/2"
+        ]
+    );
+    assert_eq!(cached_status(&app, "http://api.test/quest/1"), Some(200));
+    // the confirmed mutation is removed from the file by the next sync
+    app.update();
+    assert_eq!(
+        *changes.lock().unwrap(),
+        vec![OnlineStatus::Offline, OnlineStatus::Online]
+    );
+    assert!(app.world().resource::<QueryStore>().offline.queue.is_empty());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "[]");
+    fs::remove_file(&path).unwrap();
+}
+
+#[timeout(3000)]
+#[test]
+fn offline_replay_without_probe() {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::any("*"),
+        MockResponse::error(TransportError::Connection("unreachable".to_string())),
+    );
+    let mut app = offline_app(
+        &mock,
+        OfflineMode::default().with_probe_interval(Duration::from_millis(50)),
+    );
+
+    app.world_mut()
+        .commands()
+        .trigger(post("http://api.test/quest/1"));
+    while mock.requests().len() < 2 {
+        app.update();
+    }
+    assert_eq!(*app.world().resource::<OnlineStatus>(), OnlineStatus::Offline);
+    assert_eq!(cached_status(&app, "http://api.test/quest/1"), None);
+
+    mock.on(RequestMatcher::any("*"), MockResponse::json(json!({"msg": ""})));
+    while cached_status(&app, "http://api.test/quest/1").is_none() {
+        app.update();
+    }
+    app.update();
+    assert_eq!(cached_status(&app, "http://api.test/quest/1"), Some(200));
+    assert_eq!(*app.world().resource::<OnlineStatus>(), OnlineStatus::Online);
+}
+
+#[timeout(3000)]
+#[test]
+fn offline_replay_kept_until_confirmed() {
+    let url = "http://api.test/quest/1";
+    let path =
+        std::env::temp_dir().join(format!("bevy_cached_query_confirm_{}.json", std::process::id()));
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::any("*"),
+        MockResponse::error(TransportError::Connection("unreachable".to_string())),
+    );
+    let mut app = offline_app(
+        &mock,
+        OfflineMode::default()
+            .with_probe_interval(Duration::from_millis(50))
+            .with_queue_path(&path),
+    );
+    let persisted = || -> Vec<Query> { serde_json::from_slice(&fs::read(&path).unwrap()).unwrap() };
+
+    app.world_mut().commands().trigger(post(url));
+    while *app.world().resource::<OnlineStatus>() == OnlineStatus::Online {
+        app.update();
+    }
+    // the mutation stays in the file while it is sent again and after the server failed it
+    mock.on(
+        RequestMatcher::any("*"),
+        MockResponse::status(503).with_delay(Duration::from_millis(20)),
+    );
+    while cached_status(&app, url).is_none() {
+        app.update();
+        assert_eq!(persisted().len(), 1);
+    }
+    assert_eq!(cached_status(&app, url), Some(503));
+    app.update();
+    assert_eq!(persisted().len(), 1);
+
+    mock.on(RequestMatcher::any("*"), MockResponse::json(json!({"msg": ""})));
+    while cached_status(&app, url) != Some(200) {
+        app.update();
+    }
+    app.update();
+    assert!(persisted().is_empty());
+    assert!(app.world().resource::<QueryStore>().offline.queue.is_empty());
+    fs::remove_file(&path).unwrap();
+}
+
+#[timeout(3000)]
+#[test]
+fn offline_replay_dropped() {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::any("*"),
+        MockResponse::error(TransportError::Connection("unreachable".to_string())),
+    );
+    let mut app = offline_app(
+        &mock,
+        OfflineMode::default()
+            .with_probe_interval(Duration::from_millis(50))
+            .with_max_replay_attempts(2),
+    );
+    let dropped = Arc::new(Mutex::new(vec![]));
+    let observed = dropped.clone();
+    app.add_observer(move |trigger: Trigger<MutationDropped>| {
+        observed.lock().unwrap().push(trigger.event().clone());
+    });
+
+    app.world_mut()
+        .commands()
+        .trigger(post("http://api.test/quest/1"));
+    while *app.world().resource::<OnlineStatus>() == OnlineStatus::Online {
+        app.update();
+    }
+    app.world_mut()
+        .commands()
+        .trigger(post("http://api.test/quest/2"));
+    // the server is back but always fails the first mutation
+    mock.on(RequestMatcher::any("*"), MockResponse::json(json!({"msg": ""})))
+        .on(RequestMatcher::post("/quest/1"), MockResponse::status(500));
+    while cached_status(&app, "http://api.test/quest/2").is_none() {
+        app.update();
+    }
+
+    assert_eq!(cached_status(&app, "http://api.test/quest/2"), Some(200));
+    let dropped = dropped.lock().unwrap();
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(dropped[0].query.url, "http://api.test/quest/1");
+    assert_eq!((dropped[0].status, dropped[0].attempts), (500, 2));
+    // the first attempt failed to connect, then two replays
+    mock.assert_requested(&RequestMatcher::post("/quest/1"), 3);
+    assert!(app.world().resource::<QueryStore>().offline.queue.is_empty());
+}
+
+#[test]
+fn offline_load_queue() {
+    let path = std::env::temp_dir().join(format!("bevy_cached_query_load_{}.json", std::process::id()));
+    let offline_mode = OfflineMode::default().with_queue_path(&path);
+    assert!(offline_mode.load_queue().unwrap().is_empty());
+
+    let queue = VecDeque::from([post("http://api.test/quest/1"), post("http://api.test/quest/2")]);
+    fs::write(&path, serde_json::to_vec(&queue).unwrap()).unwrap();
+    assert_eq!(offline_mode.load_queue().unwrap(), queue);
+    fs::remove_file(&path).unwrap();
+}
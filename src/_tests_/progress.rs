@@ -0,0 +1,137 @@
+use crate::{
+    _tests_::util::init_test_app,
+    progress::{QueryProgress, TransferDirection},
+    tasks::{Method, QueryBuilder, QueryStore},
+    transport::{
+        hyper_transport::HyperTransport,
+        mock::{MockResponse, MockTransport, RequestMatcher},
+        HttpTransport, QueryTransport, UreqTransport,
+    },
+};
+use bevy::prelude::*;
+use ntest::timeout;
+use serde_json::json;
+
+#[derive(Resource, Default)]
+struct Reported(Vec<QueryProgress>);
+
+fn progress_app(transport: impl HttpTransport) -> App {
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(transport));
+    app.init_resource::<Reported>();
+    app.add_observer(
+        |trigger: Trigger<QueryProgress>, mut reported: ResMut<Reported>| {
+            reported.0.push(trigger.event().clone());
+        },
+    );
+    app
+}
+
+fn run_until_done(app: &mut App) {
+    app.update();
+    while !app.world().resource::<QueryStore>().loading_requests.is_empty() {
+        app.update();
+    }
+}
+
+/// Reports of one direction for `url`
+fn reports(app: &App, url: &str, direction: TransferDirection) -> Vec<QueryProgress> {
+    app.world()
+        .resource::<Reported>()
+        .0
+        .iter()
+        .filter(|progress| progress.key.0 == url && progress.direction == direction)
+        .cloned()
+        .collect()
+}
+
+#[timeout(1000)]
+#[test]
+fn download_progress() {
+    let url = "http://mock.test/replay";
+    let body = serde_json::to_vec(&json!({"frames": vec![0; 10_000]})).unwrap();
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/replay"),
+        MockResponse::json(json!({}))
+            .with_header("Content-Length", body.len().to_string())
+            .with_body(body.clone()),
+    );
+    let mut app = progress_app(mock.clone());
+
+    app.world_mut()
+        .commands()
+        .trigger(QueryBuilder::default().url(url).build().unwrap());
+    run_until_done(&mut app);
+
+    let downloads = reports(&app, url, TransferDirection::Download);
+    let last = downloads.last().unwrap();
+    assert_eq!(last.key, (url.to_string(), String::new()));
+    assert_eq!(last.bytes_done, body.len() as u64);
+    assert_eq!(last.bytes_total, Some(body.len() as u64));
+    assert!(downloads
+        .windows(2)
+        .all(|pair| pair[0].bytes_done < pair[1].bytes_done));
+    // a GET has nothing to upload
+    assert!(reports(&app, url, TransferDirection::Upload).is_empty());
+    assert!(app.world().resource::<QueryStore>().progress.transfers.is_empty());
+}
+
+#[timeout(1000)]
+#[test]
+fn download_progress_unknown_size() {
+    let url = "http://mock.test/chunked";
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/chunked"),
+        MockResponse::json(json!({"msg": "chunked"})),
+    );
+    let mut app = progress_app(mock);
+
+    app.world_mut()
+        .commands()
+        .trigger(QueryBuilder::default().url(url).build().unwrap());
+    run_until_done(&mut app);
+
+    let last = reports(&app, url, TransferDirection::Download).pop().unwrap();
+    assert_eq!(last.bytes_done, br#"{"msg":"chunked"}"#.len() as u64);
+    assert_eq!(last.bytes_total, None);
+}
+
+fn upload_progress(transport: impl HttpTransport) {
+    let url = "http://127.0.0.1:8080/upload_size";
+    let body = json!({"events": vec!["frame"; 20_000]});
+    let size = serde_json::to_vec(&body).unwrap().len() as u64;
+    let mut app = progress_app(transport);
+
+    app.world_mut().commands().trigger(
+        QueryBuilder::default()
+            .url(url)
+            .method(Method::Post)
+            .body(body)
+            .build()
+            .unwrap(),
+    );
+    run_until_done(&mut app);
+
+    let store = app.world().resource::<QueryStore>();
+    let (value, _, _) = &store.cache[&(url.to_string(), String::new())];
+    assert_eq!(value["body"]["msg"], size.to_string());
+
+    let last = reports(&app, url, TransferDirection::Upload).pop().unwrap();
+    assert_eq!((last.bytes_done, last.bytes_total), (size, Some(size)));
+    let last = reports(&app, url, TransferDirection::Download).pop().unwrap();
+    assert_eq!(last.bytes_total, Some(last.bytes_done));
+}
+
+#[timeout(2000)]
+#[test]
+fn upload_progress_hyper() {
+    upload_progress(HyperTransport::default());
+}
+
+#[timeout(2000)]
+#[test]
+fn upload_progress_ureq() {
+    upload_progress(UreqTransport::default());
+}
@@ -0,0 +1,97 @@
+use crate::{
+    _tests_::util::{cached_status, limits_app, trigger, wait_for_all},
+    scheduler::{parse_retry_after, RateLimit, RequestLimits},
+    tasks::QueryStore,
+    transport::mock::{MockResponse, RequestMatcher},
+};
+use ntest::timeout;
+use serde_json::json;
+use std::time::{Duration, Instant, SystemTime};
+
+#[timeout(2000)]
+#[test]
+fn rate_limit_burst() {
+    let (mut app, mock) = limits_app(
+        RequestLimits::default()
+            .with_rate_limit("api.test/leaderboard/*", RateLimit::per_second(10.).with_burst(2)),
+        Duration::ZERO,
+    );
+    let start = Instant::now();
+
+    trigger(&mut app, "http://api.test/leaderboard/1");
+    trigger(&mut app, "http://api.test/leaderboard/2");
+    trigger(&mut app, "http://api.test/leaderboard/3");
+    trigger(&mut app, "http://api.test/leaderboard/4");
+    trigger(&mut app, "http://api.test/profile");
+    app.update();
+    {
+        let store = app.world().resource::<QueryStore>();
+        assert_eq!(store.loading_requests.len() + store.cache.len(), 3);
+        assert_eq!(store.pending_requests.len(), 2);
+    }
+
+    wait_for_all(&mut app);
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert_eq!(mock.requests().len(), 5);
+    assert_eq!(app.world().resource::<QueryStore>().cache.len(), 5);
+}
+
+#[timeout(4000)]
+#[test]
+fn rate_limit_retry_after() {
+    let (mut app, mock) = limits_app(RequestLimits::default(), Duration::ZERO);
+    mock.on(
+        RequestMatcher::get("/matchmaking"),
+        MockResponse::status(429).with_header("Retry-After", "1"),
+    );
+    let start = Instant::now();
+
+    trigger(&mut app, "http://api.test/matchmaking");
+    while mock.requests().is_empty() {
+        app.update();
+    }
+    mock.on(
+        RequestMatcher::get("/matchmaking"),
+        MockResponse::json(json!({"msg": "found"})),
+    );
+    wait_for_all(&mut app);
+
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert_eq!(mock.requests().len(), 2);
+    assert_eq!(cached_status(&app, "http://api.test/matchmaking"), Some(200));
+    assert!(app
+        .world()
+        .resource::<QueryStore>()
+        .rate_limits
+        .requeues
+        .is_empty());
+}
+
+#[timeout(2000)]
+#[test]
+fn rate_limit_retry_after_exhausted() {
+    let (mut app, mock) = limits_app(RequestLimits::default(), Duration::ZERO);
+    mock.on(
+        RequestMatcher::get("/matchmaking"),
+        MockResponse::status(503).with_header("Retry-After", "0"),
+    );
+
+    trigger(&mut app, "http://api.test/matchmaking");
+    wait_for_all(&mut app);
+
+    assert_eq!(mock.requests().len(), 4);
+    assert_eq!(cached_status(&app, "http://api.test/matchmaking"), Some(503));
+}
+
+#[test]
+fn rate_limit_parse_retry_after() {
+    assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+        Some(Duration::ZERO)
+    );
+    let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(30));
+    let delay = parse_retry_after(&later).unwrap();
+    assert!(delay > Duration::from_secs(28) && delay <= Duration::from_secs(30));
+    assert_eq!(parse_retry_after("soon"), None);
+}
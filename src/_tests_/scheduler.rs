@@ -0,0 +1,82 @@
+use crate::{
+    _tests_::util::{limits_app, trigger, trigger_with_priority, wait_for_all},
+    scheduler::{host, RequestLimits},
+    tasks::QueryStore,
+    transport::mock::MockTransport,
+};
+use bevy::prelude::*;
+use ntest::timeout;
+use std::time::Duration;
+
+/// Responses take a while so the requests are still in flight after an update
+fn scheduler_app(limits: RequestLimits) -> (App, MockTransport) {
+    limits_app(limits, Duration::from_millis(20))
+}
+
+#[timeout(2000)]
+#[test]
+fn scheduler_priority() {
+    let (mut app, mock) = scheduler_app(RequestLimits::default().with_max_in_flight(1));
+
+    trigger(&mut app, "http://api.test/manifest_1");
+    trigger(&mut app, "http://api.test/manifest_2");
+    trigger_with_priority(&mut app, "http://api.test/login", 10);
+    trigger_with_priority(&mut app, "http://api.test/friends", 5);
+    app.update();
+    {
+        let store = app.world().resource::<QueryStore>();
+        assert_eq!(store.loading_requests.len(), 1);
+        assert_eq!(store.pending_requests.len(), 3);
+    }
+
+    wait_for_all(&mut app);
+    let sent: Vec<String> = mock.requests().into_iter().map(|request| request.url).collect();
+    assert_eq!(
+        sent,
+        vec![
+            "http://api.test/manifest_1",
+            "http://api.test/login",
+            "http://api.test/friends",
+            "http://api.test/manifest_2",
+        ]
+    );
+    assert_eq!(app.world().resource::<QueryStore>().cache.len(), 4);
+}
+
+#[timeout(2000)]
+#[test]
+fn scheduler_host_limits() {
+    let (mut app, mock) = scheduler_app(
+        RequestLimits::default()
+            .with_max_in_flight_per_host(1)
+            .with_host_limit("cdn.test:8080", 2),
+    );
+
+    trigger(&mut app, "http://api.test/a");
+    trigger(&mut app, "http://api.test/b");
+    trigger(&mut app, "http://cdn.test:8080/a");
+    trigger(&mut app, "http://cdn.test:8080/b");
+    trigger(&mut app, "http://cdn.test:8080/c");
+    app.update();
+    {
+        let store = app.world().resource::<QueryStore>();
+        assert_eq!(store.loading_requests.len(), 3);
+        let pending: Vec<&str> = store
+            .pending_requests
+            .iter()
+            .map(|query| query.url.as_str())
+            .collect();
+        assert_eq!(pending, vec!["http://api.test/b", "http://cdn.test:8080/c"]);
+    }
+
+    wait_for_all(&mut app);
+    assert_eq!(mock.requests().len(), 5);
+    assert_eq!(app.world().resource::<QueryStore>().cache.len(), 5);
+}
+
+#[test]
+fn scheduler_host() {
+    assert_eq!(host("https://api.test/a?b=c"), "api.test");
+    assert_eq!(host("http://user@127.0.0.1:8080"), "127.0.0.1:8080");
+    assert_eq!(host("api.test/a"), "api.test");
+}
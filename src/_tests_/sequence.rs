@@ -1,12 +1,17 @@
 use crate::{
-    _tests_::util::init_test_app,
+    _tests_::util::{init_test_app, mock_app},
     extractor::{check_completed_queries, QueryConsumable},
-    tasks::{Method, QueryBuilder, QuerySequence, QueryStore},
+    tasks::{
+        Method, QueryBuilder, QuerySequence, QueryStore, SequenceCompleted, SequenceFailed,
+        SequenceFailurePolicy, SequenceStepCompleted,
+    },
+    transport::mock::{MockResponse, MockTransport, RequestMatcher},
 };
 use bevy::prelude::*;
 use ntest::{assert_true, timeout};
+use serde_json::json;
 
-#[timeout(4)]
+#[timeout(2000)]
 #[test]
 fn sequence() {
     let url1 = "http://127.0.0.1:8080/seq1";
@@ -30,6 +35,7 @@ fn sequence() {
                 .unwrap(),
         ]
         .into(),
+        ..default()
     });
 
     loop {
@@ -76,3 +82,220 @@ fn sequence() {
         app.update();
     }
 }
+
+#[derive(Resource, Default)]
+struct SequenceEvents {
+    steps: Vec<usize>,
+    completed: Option<SequenceCompleted>,
+    failed: Option<SequenceFailed>,
+}
+
+fn init_sequence_events_app() -> App {
+    with_sequence_events(init_test_app())
+}
+
+fn with_sequence_events(mut app: App) -> App {
+    app.init_resource::<SequenceEvents>();
+    app.add_observer(|t: Trigger<SequenceStepCompleted>, mut events: ResMut<SequenceEvents>| {
+        events.steps.push(t.event().step);
+    });
+    app.add_observer(|t: Trigger<SequenceCompleted>, mut events: ResMut<SequenceEvents>| {
+        events.completed = Some(t.event().clone());
+    });
+    app.add_observer(|t: Trigger<SequenceFailed>, mut events: ResMut<SequenceEvents>| {
+        events.failed = Some(t.event().clone());
+    });
+
+    app
+}
+
+fn sequence_of(urls: [&str; 3]) -> std::collections::VecDeque<crate::Query> {
+    urls.iter()
+        .map(|url| {
+            QueryBuilder::default()
+                .method(Method::Get)
+                .url(*url)
+                .build()
+                .unwrap()
+        })
+        .collect()
+}
+
+/// Steps that change server state, the ones compensations undo
+fn mutations_of(urls: [&str; 3]) -> std::collections::VecDeque<crate::Query> {
+    urls.iter().map(|url| post(url)).collect()
+}
+
+fn post(url: &str) -> crate::Query {
+    QueryBuilder::default()
+        .method(Method::Post)
+        .url(url)
+        .build()
+        .unwrap()
+}
+
+#[timeout(2000)]
+#[test]
+fn sequence_abort() {
+    let mut app = init_sequence_events_app();
+
+    app.world_mut().commands().trigger(QuerySequence {
+        key: "sequence_abort".to_string(),
+        tasks: sequence_of([
+            "http://127.0.0.1:8080/seq_abort_1",
+            "http://127.0.0.1:8080/seq_fail",
+            "http://127.0.0.1:8080/seq_abort_3",
+        ]),
+        ..default()
+    });
+
+    while app.world().resource::<SequenceEvents>().failed.is_none() {
+        app.update();
+    }
+
+    let events = app.world().resource::<SequenceEvents>();
+    let failed = events.failed.as_ref().unwrap();
+    assert_eq!(failed.step, 1);
+    assert_eq!(failed.status, 500);
+    assert_eq!(events.steps, vec![0]);
+    assert!(events.completed.is_none());
+
+    let store = app.world().resource::<QueryStore>();
+    assert_true!(store.sequences.is_empty());
+    assert_true!(!store
+        .cache
+        .contains_key(&("http://127.0.0.1:8080/seq_abort_3".to_string(), String::new())));
+}
+
+#[timeout(2000)]
+#[test]
+fn sequence_skip_step() {
+    let mut app = init_sequence_events_app();
+
+    app.world_mut().commands().trigger(QuerySequence {
+        key: "sequence_skip".to_string(),
+        tasks: sequence_of([
+            "http://127.0.0.1:8080/seq_skip_1",
+            "http://127.0.0.1:8080/seq_fail",
+            "http://127.0.0.1:8080/seq_skip_3",
+        ]),
+        on_failure: SequenceFailurePolicy::SkipStep,
+        ..default()
+    });
+
+    while app.world().resource::<SequenceEvents>().completed.is_none() {
+        app.update();
+    }
+
+    let events = app.world().resource::<SequenceEvents>();
+    assert_eq!(events.completed.as_ref().unwrap().skipped_steps, vec![1]);
+    assert_eq!(events.steps, vec![0, 1, 2]);
+    assert!(events.failed.is_none());
+    assert_true!(app.world().resource::<QueryStore>().sequences.is_empty());
+}
+
+#[timeout(2000)]
+#[test]
+fn sequence_compensations() {
+    let mut app = init_sequence_events_app();
+
+    app.world_mut().commands().trigger(QuerySequence {
+        key: "purchase".to_string(),
+        tasks: mutations_of([
+            "http://127.0.0.1:8080/comp_reserve",
+            "http://127.0.0.1:8080/comp_charge",
+            "http://127.0.0.1:8080/seq_fail",
+        ]),
+        compensations: [
+            (0, post("http://127.0.0.1:8080/comp_release")),
+            (1, post("http://127.0.0.1:8080/comp_refund")),
+        ]
+        .into_iter()
+        .collect(),
+        ..default()
+    });
+
+    while app.world().resource::<SequenceEvents>().failed.is_none() {
+        app.update();
+    }
+
+    let failed = app.world().resource::<SequenceEvents>().failed.clone().unwrap();
+    assert_eq!(failed.step, 2);
+    assert_eq!(failed.compensated, vec![1, 0]);
+    assert_true!(failed.compensation_failed.is_empty());
+
+    let store = app.world().resource::<QueryStore>();
+    let called_at = |url: &str| store.cache.get(&(url.to_string(), String::new())).unwrap().2;
+    assert!(
+        called_at("http://127.0.0.1:8080/comp_refund")
+            <= called_at("http://127.0.0.1:8080/comp_release"),
+        "steps were not compensated in reverse order"
+    );
+    assert_true!(store.sequences.is_empty());
+}
+
+/// Mock answering every step, `/seq_flaky` fails its first `failures` requests
+fn flaky_mock(failures: usize) -> MockTransport {
+    let mock = MockTransport::new();
+    mock.on(RequestMatcher::any("*"), MockResponse::json(json!({"msg": ""})))
+        .on_times(
+            RequestMatcher::get("/seq_flaky"),
+            MockResponse::status(500),
+            failures,
+        );
+    mock
+}
+
+fn flaky_sequence(max_retries: u32) -> QuerySequence {
+    QuerySequence {
+        key: "sequence_retry".to_string(),
+        tasks: sequence_of([
+            "http://mock.test/seq_retry_1",
+            "http://mock.test/seq_flaky",
+            "http://mock.test/seq_retry_3",
+        ]),
+        on_failure: SequenceFailurePolicy::RetryStep { max_retries },
+        ..default()
+    }
+}
+
+#[timeout(2000)]
+#[test]
+fn sequence_retry_step() {
+    let mock = flaky_mock(1);
+    let mut app = with_sequence_events(mock_app(&mock));
+    let flaky = RequestMatcher::get("/seq_flaky");
+
+    app.world_mut().commands().trigger(flaky_sequence(2));
+    while app.world().resource::<SequenceEvents>().completed.is_none() {
+        app.update();
+    }
+
+    let events = app.world().resource::<SequenceEvents>();
+    assert_true!(events.completed.as_ref().unwrap().skipped_steps.is_empty());
+    assert_eq!(events.steps, vec![0, 1, 2]);
+    assert!(events.failed.is_none());
+    mock.assert_requested(&flaky, 2);
+    assert_true!(app.world().resource::<QueryStore>().sequences.is_empty());
+}
+
+#[timeout(2000)]
+#[test]
+fn sequence_retry_step_exhausted() {
+    let mock = flaky_mock(3);
+    let mut app = with_sequence_events(mock_app(&mock));
+
+    app.world_mut().commands().trigger(flaky_sequence(2));
+    while app.world().resource::<SequenceEvents>().failed.is_none() {
+        app.update();
+    }
+
+    let events = app.world().resource::<SequenceEvents>();
+    let failed = events.failed.as_ref().unwrap();
+    assert_eq!(failed.step, 1);
+    assert_eq!(failed.status, 500);
+    assert_eq!(events.steps, vec![0]);
+    // the first attempt and two retries
+    mock.assert_requested(&RequestMatcher::get("/seq_flaky"), 3);
+    mock.assert_requested(&RequestMatcher::get("/seq_retry_3"), 0);
+}
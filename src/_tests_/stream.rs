@@ -0,0 +1,562 @@
+use crate::{
+    _tests_::util::{cached, init_test_app, run_until},
+    cached_query::CachedQuery,
+    extractor::{extract_if_changed, QueryConsumable, QueryCursor},
+    live::MergeStrategy,
+    middleware::{QueryMiddleware, QueryMiddlewareStack},
+    scheduler::{RateLimit, RequestLimits},
+    stream::{CloseStream, EventStreamParser, NdjsonDecoder, NdjsonItem, ServerSentEvent, StreamMode},
+    tasks::{QueryBuilder, QueryStore},
+    transport::{
+        mock::{MockResponse, MockTransport, RequestMatcher},
+        FetchedResponse, HttpRequest, HttpResponse, HttpTransport, QueryTransport, TransportError,
+    },
+    Query,
+};
+use async_io::Timer;
+use bevy::{prelude::*, tasks::futures_lite::io::AssertAsync, utils::BoxedFuture};
+use ntest::timeout;
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Resource, Default)]
+struct Received(Vec<ServerSentEvent>);
+
+#[derive(Resource, Default)]
+struct Items(Vec<NdjsonItem>);
+
+fn stream_app(transport: impl HttpTransport) -> App {
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(transport));
+    app.init_resource::<Received>();
+    app.init_resource::<Items>();
+    app.add_observer(
+        |trigger: Trigger<ServerSentEvent>, mut received: ResMut<Received>| {
+            received.0.push(trigger.event().clone());
+        },
+    );
+    app.add_observer(|trigger: Trigger<NdjsonItem>, mut items: ResMut<Items>| {
+        items.0.push(trigger.event().clone());
+    });
+    app
+}
+
+/// Serves one response whose body is written by the test while it is read
+#[derive(Clone)]
+struct ChunkedTransport {
+    body: Arc<Mutex<Option<Receiver<io::Result<Vec<u8>>>>>>,
+}
+
+impl ChunkedTransport {
+    fn new() -> (Self, Sender<io::Result<Vec<u8>>>) {
+        let (sender, receiver) = mpsc::channel();
+        let transport = Self {
+            body: Arc::new(Mutex::new(Some(receiver))),
+        };
+        (transport, sender)
+    }
+}
+
+/// Blocks until the next chunk is sent, the body ends once the sender is dropped
+struct ChunkReader(Receiver<io::Result<Vec<u8>>>);
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.recv() {
+            Ok(chunk) => {
+                let chunk = chunk?;
+                buf[..chunk.len()].copy_from_slice(&chunk);
+                Ok(chunk.len())
+            }
+            Err(_) => Ok(0),
+        }
+    }
+}
+
+impl HttpTransport for ChunkedTransport {
+    fn send(&self, _: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>> {
+        let body = self.body.lock().unwrap().take().unwrap();
+        Box::pin(async move {
+            Ok(HttpResponse {
+                status: 200,
+                headers: vec![("content-type".to_string(), "application/x-ndjson".to_string())],
+                body: Box::new(AssertAsync::new(ChunkReader(body))),
+            })
+        })
+    }
+}
+
+fn event_stream(url: &str, merge: MergeStrategy) -> Query {
+    QueryBuilder::default()
+        .url(url)
+        .stream(StreamMode::EventStream { merge })
+        .build()
+        .unwrap()
+}
+
+#[timeout(2000)]
+#[test]
+fn append_and_resume_with_last_event_id() {
+    let url = "http://mock.test/builds/events";
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/builds/events"),
+        MockResponse::json(json!({}))
+            .with_header("Content-Type", "text/event-stream")
+            .with_body(
+                ": keep-alive\r\nretry: 10\r\nid: 1\r\ndata: {\"build\":1,\r\ndata: \"state\":\"running\"}\r\n\r\n\
+                 event: done\nid: 2\ndata: {\"build\":1,\"state\":\"passed\"}\n\n",
+            ),
+    );
+    // the server has nothing after event 2
+    mock.on(
+        RequestMatcher::get("/builds/events").header("Last-Event-ID", "2"),
+        MockResponse::status(204),
+    );
+    let mut app = stream_app(mock.clone());
+
+    app.world_mut()
+        .commands()
+        .trigger(event_stream(url, MergeStrategy::Append { max_items: None }));
+    run_until(&mut app, |app| {
+        mock.requests().len() == 2 && app.world().resource::<QueryStore>().streams.is_empty()
+    });
+
+    assert_eq!(
+        cached(&app, url)["body"],
+        json!([{"build": 1, "state": "running"}, {"build": 1, "state": "passed"}])
+    );
+    let events: Vec<_> = app
+        .world()
+        .resource::<Received>()
+        .0
+        .iter()
+        .map(|event| (event.event.as_str(), event.id.as_deref()))
+        .collect();
+    assert_eq!(events, vec![("message", Some("1")), ("done", Some("2"))]);
+
+    let requests = mock.requests();
+    assert_eq!(requests[0].header("Accept"), Some("text/event-stream"));
+    assert_eq!(requests[0].header("Last-Event-ID"), None);
+    assert_eq!(requests[1].header("Last-Event-ID"), Some("2"));
+    // the cached query reopens the stream when it is refetched
+    let query = &app.world().resource::<QueryStore>().cache[&(url.to_string(), String::new())].1;
+    assert!(query.stream.is_some());
+}
+
+#[timeout(2000)]
+#[test]
+fn replace_and_close() {
+    let url = "http://mock.test/status/events";
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/status/events"),
+        MockResponse::json(json!({}))
+            .with_body("retry: 60000\ndata: starting\n\ndata: {\"up\":true}\n\n"),
+    );
+    let mut app = stream_app(mock.clone());
+
+    app.world_mut()
+        .commands()
+        .trigger(event_stream(url, MergeStrategy::Replace));
+    // an open stream is not opened twice
+    app.world_mut()
+        .commands()
+        .trigger(event_stream(url, MergeStrategy::Replace));
+    run_until(&mut app, |app| {
+        app.world().resource::<QueryStore>().streams[&(url.to_string(), String::new())].reconnects == 1
+    });
+
+    assert_eq!(cached(&app, url)["body"], json!({"up": true}));
+    let received = &app.world().resource::<Received>().0;
+    assert_eq!(received[0].data, "starting");
+    assert_eq!(received.len(), 2);
+    assert_eq!(mock.requests().len(), 1);
+
+    app.world_mut().commands().trigger(CloseStream {
+        url: url.to_string(),
+        query_key: None,
+    });
+    app.update();
+    assert!(app.world().resource::<QueryStore>().streams.is_empty());
+    assert_eq!(cached(&app, url)["body"], json!({"up": true}));
+}
+
+#[timeout(2000)]
+#[test]
+fn error_status_ends_stream() {
+    let url = "http://mock.test/feed/events";
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/feed/events"),
+        MockResponse::status(503).with_body("maintenance"),
+    );
+    let mut app = stream_app(mock.clone());
+
+    app.world_mut()
+        .commands()
+        .trigger(event_stream(url, MergeStrategy::Replace));
+    run_until(&mut app, |app| {
+        app.world().resource::<QueryStore>().streams.is_empty()
+    });
+
+    assert_eq!(cached(&app, url), json!({"status": 503, "msg": "maintenance"}));
+    assert_eq!(mock.requests().len(), 1);
+}
+
+fn ndjson(url: &str) -> Query {
+    QueryBuilder::default()
+        .url(url)
+        .stream(StreamMode::NdJson)
+        .build()
+        .unwrap()
+}
+
+fn items(app: &App) -> Vec<(usize, Value)> {
+    app.world()
+        .resource::<Items>()
+        .0
+        .iter()
+        .map(|item| (item.index, item.item.clone()))
+        .collect()
+}
+
+#[timeout(2000)]
+#[test]
+fn ndjson_items() {
+    let url = "http://mock.test/search";
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::get("/search"),
+        MockResponse::json(json!({}))
+            .with_header("Content-Type", "application/x-ndjson")
+            .with_body("{\"id\":1}\n{\"id\":2}\r\n\nnot json\n{\"id\":3}"),
+    );
+    let mut app = stream_app(mock.clone());
+
+    app.world_mut().commands().trigger(ndjson(url));
+    run_until(&mut app, |app| {
+        app.world().resource::<QueryStore>().streams.is_empty()
+    });
+
+    let value = cached(&app, url);
+    assert_eq!(value["status"], 200);
+    assert_eq!(value["body"], json!([{"id": 1}, {"id": 2}, {"id": 3}]));
+    assert_eq!(value["headers"]["content-type"], "application/x-ndjson");
+    assert_eq!(
+        items(&app),
+        vec![
+            (0, json!({"id": 1})),
+            (1, json!({"id": 2})),
+            (2, json!({"id": 3}))
+        ]
+    );
+    assert_eq!(mock.requests()[0].header("Accept"), Some("application/x-ndjson"));
+
+    // the complete response is cached like any other
+    app.world_mut().commands().trigger(ndjson(url));
+    app.update();
+    assert_eq!(mock.requests().len(), 1);
+}
+
+#[timeout(2000)]
+#[test]
+fn ndjson_grows_while_streaming() {
+    let url = "http://mock.test/analytics";
+    let (transport, body) = ChunkedTransport::new();
+    let mut app = stream_app(transport);
+
+    app.world_mut().commands().trigger(ndjson(url));
+    body.send(Ok(b"{\"day\":1,\"vi".to_vec())).unwrap();
+    body.send(Ok(b"sits\":10}\n{\"day\":2,".to_vec())).unwrap();
+    run_until(&mut app, |app| app.world().resource::<Items>().0.len() == 1);
+    // the first line is readable while the rest is in flight
+    assert_eq!(cached(&app, url)["body"], json!([{"day": 1, "visits": 10}]));
+    assert_eq!(app.world().resource::<QueryStore>().streams.len(), 1);
+
+    body.send(Ok(b"\"visits\":12}\n".to_vec())).unwrap();
+    run_until(&mut app, |app| app.world().resource::<Items>().0.len() == 2);
+    assert_eq!(
+        cached(&app, url)["body"],
+        json!([{"day": 1, "visits": 10}, {"day": 2, "visits": 12}])
+    );
+
+    // a broken connection fails the query
+    body.send(Err(io::ErrorKind::ConnectionReset.into())).unwrap();
+    run_until(&mut app, |app| {
+        app.world().resource::<QueryStore>().streams.is_empty()
+    });
+    assert_eq!(cached(&app, url), json!({"status": 500}));
+}
+
+struct ApiVersion;
+
+impl QueryMiddleware for ApiVersion {
+    fn on_request(&self, query: &mut Query) -> Option<FetchedResponse> {
+        query
+            .headers
+            .get_or_insert_with(Vec::new)
+            .push(("X-Api-Version".to_string(), "2".to_string()));
+        None
+    }
+}
+
+#[timeout(2000)]
+#[test]
+fn ndjson_admitted_like_queries() {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::any("*"),
+        MockResponse::status(200).with_body("{\"id\":1}\n"),
+    );
+    let mut app = stream_app(mock.clone());
+    app.insert_resource(
+        RequestLimits::default().with_rate_limit("mock.test/*", RateLimit::per_second(20.).with_burst(1)),
+    );
+    app.insert_resource(QueryMiddlewareStack::default().with(ApiVersion));
+
+    app.world_mut().commands().trigger(ndjson("http://mock.test/first"));
+    app.world_mut().commands().trigger(ndjson("http://mock.test/second"));
+    app.update();
+    // the second stream waits for a token
+    assert_eq!(app.world().resource::<QueryStore>().pending_requests.len(), 1);
+    run_until(&mut app, |app| items(app).len() == 2);
+
+    let requests = mock.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests
+        .iter()
+        .all(|request| request.header("x-api-version") == Some("2")));
+}
+
+#[test]
+fn decode_split_lines() {
+    let mut decoder = NdjsonDecoder::default();
+    let mut items = vec![];
+    for chunk in "[1,2]\r\n  \n{\"a\":".as_bytes().chunks(3) {
+        items.extend(decoder.feed(chunk));
+    }
+    items.extend(decoder.feed(b"true}"));
+    assert_eq!(items, vec![json!([1, 2])]);
+    assert_eq!(decoder.finish(), Some(json!({"a": true})));
+    assert_eq!(decoder.finish(), None);
+}
+
+#[test]
+fn parse_split_chunks() {
+    let mut parser = EventStreamParser::default();
+    let stream = "id: 7\r\ndata: a\r\ndata\r\n\r\nevent: ping\n\nid\ndata:b\r\r";
+    let mut events = vec![];
+    // line breaks split between chunks
+    for byte in stream.as_bytes().chunks(1) {
+        events.extend(parser.feed(byte));
+    }
+
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].data, "a\n");
+    assert_eq!(events[0].id.as_deref(), Some("7"));
+    // an event without data is not dispatched, an empty id resets the last one
+    assert_eq!(events[1].event, "message");
+    assert_eq!(events[1].data, "b");
+    assert_eq!(events[1].id, None);
+}
+
+/// Answers long polls from a script, then times out like a server without news
+#[derive(Clone, Default)]
+struct LongPollServer {
+    script: Arc<Mutex<VecDeque<Result<(u16, String), TransportError>>>>,
+    /// `cursor` param of every request received
+    cursors: Arc<Mutex<Vec<Option<String>>>>,
+}
+
+impl LongPollServer {
+    fn new(script: Vec<Result<(u16, String), TransportError>>) -> Self {
+        Self {
+            script: Arc::new(Mutex::new(script.into())),
+            ..default()
+        }
+    }
+
+    fn cursors(&self) -> Vec<Option<String>> {
+        self.cursors.lock().unwrap().clone()
+    }
+}
+
+impl HttpTransport for LongPollServer {
+    fn send(&self, request: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>> {
+        let cursor = request
+            .params
+            .iter()
+            .find(|(name, _)| name == "cursor")
+            .map(|(_, value)| value.clone());
+        self.cursors.lock().unwrap().push(cursor);
+        let next = self.script.lock().unwrap().pop_front();
+        Box::pin(async move {
+            match next {
+                Some(response) => {
+                    response.map(|(status, body)| HttpResponse::from_bytes(status, vec![], body.into()))
+                }
+                None => {
+                    Timer::after(Duration::from_millis(5)).await;
+                    Err(TransportError::Timeout)
+                }
+            }
+        })
+    }
+}
+
+fn long_poll(url: &str, idle_timeout: Duration) -> Query {
+    QueryBuilder::default()
+        .url(url)
+        .params(vec![("cursor".to_string(), "0".to_string())])
+        .stream(StreamMode::LongPoll {
+            cursor_param: "cursor".to_string(),
+            cursor_pointer: "/cursor".to_string(),
+            merge: MergeStrategy::Replace,
+            idle_timeout,
+        })
+        .build()
+        .unwrap()
+}
+
+fn received(app: &App, url: &str) -> usize {
+    app.world()
+        .resource::<QueryStore>()
+        .streams
+        .get(&(url.to_string(), String::new()))
+        .map_or(0, |stream| stream.received)
+}
+
+#[timeout(2000)]
+#[test]
+fn long_poll_carries_cursor() {
+    let url = "http://mock.test/matchmaking/poll";
+    let server = LongPollServer::new(vec![
+        Ok((200, r#"{"events":["queued"],"cursor":"c1"}"#.to_string())),
+        Err(TransportError::Timeout),
+        Ok((204, String::new())),
+        Ok((200, r#"{"events":["match_found"],"cursor":"c2"}"#.to_string())),
+        Ok((200, r#"{"events":[]}"#.to_string())),
+    ]);
+    let mut app = stream_app(server.clone());
+
+    app.world_mut()
+        .commands()
+        .trigger(long_poll(url, Duration::from_secs(5)));
+    run_until(&mut app, |app| received(app, url) == 3);
+
+    assert_eq!(cached(&app, url)["body"], json!({"events": []}));
+    // timeouts and empty responses keep the cursor, a response without one too
+    assert_eq!(
+        server.cursors()[..6],
+        ["0", "c1", "c1", "c1", "c2", "c2"].map(|cursor| Some(cursor.to_string()))
+    );
+    let stream = &app.world().resource::<QueryStore>().streams[&(url.to_string(), String::new())];
+    assert_eq!(stream.cursor.as_deref(), Some("c2"));
+    assert_eq!(stream.reconnects, 0);
+}
+
+#[timeout(2000)]
+#[test]
+fn long_poll_backs_off_without_news() {
+    let url = "http://mock.test/shop/poll";
+    let server = LongPollServer::new(vec![]);
+    let mut app = stream_app(server.clone());
+
+    app.world_mut()
+        .commands()
+        .trigger(long_poll(url, Duration::from_secs(5)));
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(400) {
+        app.update();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    // sent after 0, 100, 300ms instead of every timeout
+    let sent = server.cursors().len();
+    assert!((2..=4).contains(&sent), "{} polls sent", sent);
+}
+
+#[timeout(2000)]
+#[test]
+fn long_poll_stops_when_not_read() {
+    let url = "http://mock.test/lobby/poll";
+    let server = LongPollServer::new(vec![Ok((200, r#"{"players":1}"#.to_string()))]);
+    let mut app = stream_app(server.clone());
+    let idle_timeout = Duration::from_millis(100);
+    let mut cursor = QueryCursor::default();
+
+    app.world_mut().commands().trigger(long_poll(url, idle_timeout));
+    run_until(&mut app, |app| received(app, url) == 1);
+    // a consumer reading the entry keeps the poll going
+    let reading = Instant::now();
+    while reading.elapsed() < idle_timeout * 2 {
+        let consumable = QueryConsumable {
+            url: url.to_string(),
+            ..default()
+        };
+        let mut store = app.world_mut().resource_mut::<QueryStore>();
+        let _ = extract_if_changed::<Value>(consumable, &mut store, &mut cursor);
+        app.update();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(app.world().resource::<QueryStore>().streams.len(), 1);
+
+    run_until(&mut app, |app| {
+        app.world().resource::<QueryStore>().streams.is_empty()
+    });
+    let sent = server.cursors().len();
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(server.cursors().len(), sent);
+    assert_eq!(cached(&app, url)["body"], json!({"players": 1}));
+}
+
+#[timeout(2000)]
+#[test]
+fn cached_query_reads_keep_long_poll() {
+    let url = "http://mock.test/party/poll";
+    let server = LongPollServer::new(vec![Ok((200, r#"{"members":2}"#.to_string()))]);
+    let mut app = stream_app(server);
+    let idle_timeout = Duration::from_millis(100);
+    app.add_systems(Update, |mut party: CachedQuery<Value>| {
+        let _ = party.get(&QueryConsumable {
+            url: "http://mock.test/party/poll".to_string(),
+            ..default()
+        });
+    });
+
+    app.world_mut().commands().trigger(long_poll(url, idle_timeout));
+    run_until(&mut app, |app| received(app, url) == 1);
+    let reading = Instant::now();
+    while reading.elapsed() < idle_timeout * 2 {
+        app.update();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(app.world().resource::<QueryStore>().streams.len(), 1);
+}
+
+#[timeout(2000)]
+#[test]
+fn long_poll_error_status() {
+    let url = "http://mock.test/queue/poll";
+    let server = LongPollServer::new(vec![Ok((410, "queue closed".to_string()))]);
+    let mut app = stream_app(server.clone());
+
+    app.world_mut()
+        .commands()
+        .trigger(long_poll(url, Duration::from_secs(5)));
+    run_until(&mut app, |app| {
+        app.world().resource::<QueryStore>().streams.is_empty()
+    });
+
+    assert_eq!(cached(&app, url), json!({"status": 410, "msg": "queue closed"}));
+    assert_eq!(server.cursors().len(), 1);
+}
@@ -0,0 +1,152 @@
+use crate::{
+    _tests_::util::{init_test_app, GetResponse},
+    extractor::{query_extractor, QueryConsumable},
+    tasks::{response_status, Method, QueryBuilder, QueryStore},
+    transport::{
+        hyper_transport::HyperTransport, HttpRequest, HttpResponse, HttpTransport, QueryTransport,
+        TransportError,
+    },
+};
+use bevy::{prelude::*, utils::BoxedFuture};
+use ntest::timeout;
+use std::{
+    io::Write,
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Answers every request with the same body and remembers what was sent
+#[derive(Clone, Default)]
+struct StaticTransport {
+    sent: Arc<Mutex<Vec<HttpRequest>>>,
+}
+
+impl HttpTransport for StaticTransport {
+    fn send(&self, request: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>> {
+        self.sent.lock().unwrap().push(request);
+        Box::pin(async {
+            Ok(HttpResponse::from_bytes(
+                200,
+                vec![],
+                b"{\"msg\": \"from transport\"}".to_vec(),
+            ))
+        })
+    }
+}
+
+#[timeout(1000)]
+#[test]
+fn custom_transport() {
+    let url = "http://transport.test/profile";
+    let transport = StaticTransport::default();
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(transport.clone()));
+
+    app.world_mut().commands().trigger(
+        QueryBuilder::default()
+            .method(Method::Post)
+            .url(url)
+            .params(vec![("id".to_string(), "42".to_string())])
+            .body(serde_json::json!({"name": "player"}))
+            .build()
+            .unwrap(),
+    );
+
+    loop {
+        let mut store = app.world_mut().get_resource_mut::<QueryStore>().unwrap();
+        let result = query_extractor::<GetResponse>(
+            QueryConsumable {
+                url: url.to_string(),
+                ..default()
+            },
+            &mut store,
+        );
+        if let Ok(response) = result {
+            assert_eq!(response.msg, "from transport");
+            break;
+        }
+
+        app.update();
+    }
+
+    let sent = transport.sent.lock().unwrap();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].params, vec![("id".to_string(), "42".to_string())]);
+    assert_eq!(sent[0].header("content-type"), Some("application/json"));
+    assert_eq!(sent[0].body.as_deref(), Some(&b"{\"name\":\"player\"}"[..]));
+}
+
+#[timeout(2000)]
+#[test]
+fn hyper_transport() {
+    let url = "http://127.0.0.1:8080/";
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(HyperTransport::default()));
+
+    // more requests than idle connections are kept, all of them in flight at once
+    for index in 0..20 {
+        app.world_mut().commands().trigger(
+            QueryBuilder::default()
+                .url(url)
+                .query_key(index.to_string())
+                .build()
+                .unwrap(),
+        );
+    }
+    app.world_mut().commands().trigger(
+        QueryBuilder::default()
+            .url("http://127.0.0.1:9/")
+            .build()
+            .unwrap(),
+    );
+
+    loop {
+        app.update();
+        let store = app.world().resource::<QueryStore>();
+        if store.loading_requests.is_empty() {
+            assert_eq!(store.cache.len(), 21);
+            for index in 0..20 {
+                let (value, _, _) = &store.cache[&(url.to_string(), index.to_string())];
+                assert_eq!(response_status(value), 200);
+            }
+            let (value, _, _) = &store.cache[&("http://127.0.0.1:9/".to_string(), String::new())];
+            assert_eq!(response_status(value), 500);
+            break;
+        }
+    }
+}
+
+#[timeout(2000)]
+#[test]
+fn hyper_body_timeout() {
+    // sends the headers right away, then the body stops arriving
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/slow_body", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\n{\"msg\"")
+            .unwrap();
+        thread::sleep(Duration::from_secs(5));
+    });
+    let mut app = init_test_app();
+    let start = Instant::now();
+
+    app.world_mut().commands().trigger(
+        QueryBuilder::default()
+            .url(&url)
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap(),
+    );
+    let key = (url, String::new());
+    while !app.world().resource::<QueryStore>().cache.contains_key(&key) {
+        app.update();
+    }
+
+    assert!(start.elapsed() < Duration::from_secs(1));
+    let (value, _, _) = &app.world().resource::<QueryStore>().cache[&key];
+    assert_eq!(response_status(value), 500);
+}
@@ -1,18 +1,145 @@
-use crate::tasks::{api_task_poll, api_task_sequence, spawn_api_task, watch_cache, QueryStore};
+use crate::{
+    batch::{api_task_batch, watch_batches},
+    download::{poll_downloads, spawn_download},
+    graph::{api_task_graph, watch_graphs},
+    infinite::{api_task_infinite_query, fetch_next_page, fetch_previous_page, watch_infinite_queries},
+    middleware::QueryMiddlewareStack,
+    offline::OnlineStatus,
+    scheduler::RequestLimits,
+    stream::{close_stream, poll_streams},
+    tasks::{
+        api_task_poll, api_task_sequence, response_status, spawn_api_task, watch_cache, QueryBuilder,
+        QueryStore,
+    },
+    transport::{
+        mock::{MockResponse, MockTransport, RequestMatcher},
+        QueryTransport,
+    },
+    websocket::{poll_subscriptions, send_to_subscription, subscribe, unsubscribe},
+    Query,
+};
 use bevy::app::{App, Update};
 use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{thread, time::Duration};
 
 pub fn init_test_app() -> App {
     let mut app = App::new();
     app.add_systems(Update, api_task_poll);
     app.add_systems(Update, watch_cache);
+    app.add_systems(Update, watch_graphs);
+    app.add_systems(Update, watch_batches);
+    app.add_systems(Update, watch_infinite_queries);
+    app.add_systems(Update, poll_downloads);
+    app.add_systems(Update, poll_subscriptions);
+    app.add_systems(Update, poll_streams);
     app.init_resource::<QueryStore>();
+    app.init_resource::<QueryTransport>();
+    app.init_resource::<RequestLimits>();
+    app.init_resource::<OnlineStatus>();
+    app.init_resource::<QueryMiddlewareStack>();
     app.add_observer(spawn_api_task);
     app.add_observer(api_task_sequence);
+    app.add_observer(api_task_graph);
+    app.add_observer(api_task_batch);
+    app.add_observer(api_task_infinite_query);
+    app.add_observer(fetch_next_page);
+    app.add_observer(fetch_previous_page);
+    app.add_observer(spawn_download);
+    app.add_observer(subscribe);
+    app.add_observer(unsubscribe);
+    app.add_observer(send_to_subscription);
+    app.add_observer(close_stream);
 
     app
 }
 
+/// Test app sending its queries through `mock`
+pub fn mock_app(mock: &MockTransport) -> App {
+    let mut app = init_test_app();
+    app.insert_resource(QueryTransport::new(mock.clone()));
+    app
+}
+
+/// Test app with `limits` whose mock answers every request after `delay`
+pub fn limits_app(limits: RequestLimits, delay: Duration) -> (App, MockTransport) {
+    let mock = MockTransport::new();
+    mock.on(
+        RequestMatcher::any("*"),
+        MockResponse::json(json!({"msg": ""})).with_delay(delay),
+    );
+    let mut app = mock_app(&mock);
+    app.insert_resource(limits);
+    (app, mock)
+}
+
+/// Triggers a GET query of `url`
+pub fn trigger(app: &mut App, url: &str) {
+    trigger_with_priority(app, url, 0);
+}
+
+pub fn trigger_with_priority(app: &mut App, url: &str, priority: i32) {
+    app.world_mut().commands().trigger(
+        QueryBuilder::default()
+            .url(url)
+            .priority(priority)
+            .build()
+            .unwrap(),
+    );
+}
+
+/// Updates the app until the entry of `url` is cached and returns it
+pub fn wait_for(app: &mut App, url: &str) -> (Value, Query) {
+    loop {
+        app.update();
+        let store = app.world().resource::<QueryStore>();
+        if let Some((value, query, _)) = store.cache.get(&(url.to_string(), String::new())) {
+            return (value.clone(), query.clone());
+        }
+    }
+}
+
+/// Updates the app until `done` returns `true`, sleeping between updates so socket and stream tasks
+/// can make progress
+pub fn run_until(app: &mut App, done: impl Fn(&App) -> bool) {
+    loop {
+        app.update();
+        if done(app) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Updates the app until no query is loading, waiting for a slot or waiting for a credentials refresh
+pub fn wait_for_all(app: &mut App) {
+    loop {
+        app.update();
+        let store = app.world().resource::<QueryStore>();
+        if store.loading_requests.is_empty()
+            && store.pending_requests.is_empty()
+            && !store.auth.is_refreshing()
+        {
+            return;
+        }
+    }
+}
+
+/// Cached entry of `url`, panics if it is not cached
+pub fn cached(app: &App, url: &str) -> Value {
+    app.world().resource::<QueryStore>().cache[&(url.to_string(), String::new())]
+        .0
+        .clone()
+}
+
+pub fn cached_status(app: &App, url: &str) -> Option<u16> {
+    let store = app.world().resource::<QueryStore>();
+    store
+        .cache
+        .get(&(url.to_string(), String::new()))
+        .map(|(value, _, _)| response_status(value))
+}
+
 #[derive(Deserialize)]
 pub struct GetResponse {
     pub msg: String,
@@ -0,0 +1,314 @@
+use crate::{
+    _tests_::util::{init_test_app, run_until},
+    auth::{AuthProvider, QueryAuth},
+    extractor::{query_extractor, QueryConsumable},
+    live::MergeStrategy,
+    middleware::{QueryMiddleware, QueryMiddlewareStack},
+    tasks::QueryStore,
+    transport::{FetchedResponse, HttpRequest, HttpTransport, TransportError},
+    websocket::{
+        Subscription, SubscriptionMessage, SubscriptionSend, SubscriptionStatus,
+        SubscriptionStatusChanged, Unsubscribe,
+    },
+    Query,
+};
+use bevy::{prelude::*, utils::BoxedFuture};
+use ntest::timeout;
+use serde_json::{json, Value};
+use std::{
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
+use tungstenite::{
+    handshake::server::{Callback, ErrorResponse, Request, Response},
+    Message,
+};
+
+#[derive(Resource, Default)]
+struct Received {
+    messages: Vec<Value>,
+    statuses: Vec<SubscriptionStatus>,
+}
+
+/// Serves one scripted connection after another, every connection sends its messages then echoes what it
+/// receives, or closes right away if it is `dropped`
+fn serve(connections: Vec<(Vec<Value>, bool)>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/live", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for (messages, dropped) in connections {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            for message in messages {
+                socket.send(Message::Text(message.to_string())).unwrap();
+            }
+            if dropped {
+                continue;
+            }
+            while let Ok(message) = socket.read() {
+                if message.is_text() && socket.send(message).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    url
+}
+
+fn subscription_app() -> App {
+    let mut app = init_test_app();
+    app.init_resource::<Received>();
+    app.add_observer(
+        |trigger: Trigger<SubscriptionMessage>, mut received: ResMut<Received>| {
+            received.messages.push(trigger.event().message.clone());
+        },
+    );
+    app.add_observer(
+        |trigger: Trigger<SubscriptionStatusChanged>, mut received: ResMut<Received>| {
+            received.statuses.push(trigger.event().status);
+        },
+    );
+    app
+}
+
+fn body(app: &App, key: &(String, String)) -> Value {
+    app.world().resource::<QueryStore>().cache[key].0["body"].clone()
+}
+
+#[timeout(5000)]
+#[test]
+fn merge_into_fetched_entry() {
+    let url = serve(vec![(vec![json!({"id": 1}), json!({"id": 2})], false)]);
+    let mut app = subscription_app();
+    let key = ("http://api.test/feed".to_string(), "feed".to_string());
+    app.world_mut().resource_mut::<QueryStore>().cache.insert(
+        key.clone(),
+        (
+            json!({"status": 200, "body": [{"id": 0}], "headers": {}}),
+            Query::default(),
+            0,
+        ),
+    );
+
+    app.world_mut().commands().trigger(
+        Subscription::new(&url)
+            .with_cache_key(&key.0, Some(key.1.clone()))
+            .with_on_connect(json!({"id": "joined"}).to_string())
+            .with_merge(MergeStrategy::Append { max_items: None }),
+    );
+    run_until(&mut app, |app| {
+        app.world().resource::<Received>().messages.len() == 3
+    });
+
+    assert_eq!(
+        body(&app, &key),
+        json!([{"id": 0}, {"id": 1}, {"id": 2}, {"id": "joined"}])
+    );
+    assert_eq!(
+        app.world().resource::<Received>().statuses,
+        vec![SubscriptionStatus::Connecting, SubscriptionStatus::Open]
+    );
+
+    // live data is read like a fetched response
+    let consumable = QueryConsumable {
+        url: key.0.clone(),
+        query_key: Some(key.1.clone()),
+        ..default()
+    };
+    let items: Vec<Value> =
+        query_extractor(consumable, &mut app.world_mut().resource_mut::<QueryStore>()).unwrap();
+    assert_eq!(items.len(), 4);
+}
+
+#[timeout(5000)]
+#[test]
+fn reconnect_after_drop() {
+    let url = serve(vec![
+        (vec![json!({"seq": 1})], true),
+        (vec![json!({"seq": 2})], false),
+    ]);
+    let mut app = subscription_app();
+
+    app.world_mut().commands().trigger(
+        Subscription::new(&url)
+            .with_merge(MergeStrategy::Append { max_items: Some(1) })
+            .with_backoff(Duration::from_millis(20), Duration::from_millis(100)),
+    );
+    run_until(&mut app, |app| {
+        app.world().resource::<Received>().messages.len() == 2
+    });
+
+    // only the last item is kept
+    assert_eq!(body(&app, &(url.clone(), String::new())), json!([{"seq": 2}]));
+    let received = app.world().resource::<Received>();
+    assert_eq!(
+        received.statuses,
+        vec![
+            SubscriptionStatus::Connecting,
+            SubscriptionStatus::Open,
+            SubscriptionStatus::Reconnecting { attempt: 1 },
+            SubscriptionStatus::Open,
+        ]
+    );
+    assert_eq!(
+        app.world().resource::<QueryStore>().subscriptions[&(url, String::new())].attempts,
+        0
+    );
+}
+
+#[timeout(5000)]
+#[test]
+fn backoff_while_unreachable() {
+    // nothing listens on the port once the listener is dropped
+    let url = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("ws://{}/live", listener.local_addr().unwrap())
+    };
+    let mut app = subscription_app();
+
+    app.world_mut().commands().trigger(
+        Subscription::new(&url).with_backoff(Duration::from_millis(10), Duration::from_millis(40)),
+    );
+    run_until(&mut app, |app| {
+        app.world().resource::<Received>().statuses.len() == 5
+    });
+
+    assert_eq!(
+        app.world().resource::<Received>().statuses[1..],
+        [
+            SubscriptionStatus::Reconnecting { attempt: 1 },
+            SubscriptionStatus::Reconnecting { attempt: 2 },
+            SubscriptionStatus::Reconnecting { attempt: 3 },
+            SubscriptionStatus::Reconnecting { attempt: 4 },
+        ]
+    );
+    assert!(!app
+        .world()
+        .resource::<QueryStore>()
+        .cache
+        .contains_key(&(url, String::new())));
+}
+
+#[timeout(5000)]
+#[test]
+fn patch_send_and_unsubscribe() {
+    let url = serve(vec![(
+        vec![json!({"name": "lobby", "players": 1, "open": true})],
+        false,
+    )]);
+    let mut app = subscription_app();
+    let key = (url.clone(), "room".to_string());
+
+    app.world_mut().commands().trigger(
+        Subscription::new(&url)
+            .with_cache_key(&url, Some("room".to_string()))
+            .with_merge(MergeStrategy::Patch),
+    );
+    run_until(&mut app, |app| {
+        app.world().resource::<Received>().messages.len() == 1
+    });
+
+    // the server echoes the patch back
+    app.world_mut().commands().trigger(SubscriptionSend {
+        url: url.clone(),
+        query_key: Some("room".to_string()),
+        message: json!({"players": 2, "open": null}).to_string(),
+    });
+    run_until(&mut app, |app| {
+        app.world().resource::<Received>().messages.len() == 2
+    });
+    assert_eq!(body(&app, &key), json!({"name": "lobby", "players": 2}));
+
+    app.world_mut().commands().trigger(Unsubscribe {
+        url: url.clone(),
+        query_key: Some("room".to_string()),
+    });
+    app.update();
+    assert!(app.world().resource::<QueryStore>().subscriptions.is_empty());
+    // the cached data is kept
+    assert_eq!(body(&app, &key), json!({"name": "lobby", "players": 2}));
+}
+
+/// Hands out a new token every time a request is authorized
+struct CountingAuth(AtomicUsize);
+
+impl AuthProvider for CountingAuth {
+    fn authorize(&self, request: &mut HttpRequest) {
+        let token = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+        request.set_header("Authorization", format!("Bearer {}", token));
+    }
+
+    fn refresh(
+        &self,
+        _transport: Arc<dyn HttpTransport>,
+    ) -> BoxedFuture<'static, Result<(), TransportError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+struct ApiVersion;
+
+/// Sends the auth and version headers of every handshake
+struct Handshakes(mpsc::Sender<(String, String)>);
+
+impl Callback for Handshakes {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        let header = |name| request.headers()[name].to_str().unwrap().to_string();
+        let _ = self.0.send((header("Authorization"), header("X-Api-Version")));
+        Ok(response)
+    }
+}
+
+impl QueryMiddleware for ApiVersion {
+    fn on_request(&self, query: &mut Query) -> Option<FetchedResponse> {
+        let headers = query.headers.get_or_insert_with(Vec::new);
+        // sees the credentials added before it
+        let authorized = headers.iter().any(|(name, _)| name == "Authorization");
+        headers.push(("X-Api-Version".to_string(), authorized.to_string()));
+        None
+    }
+}
+
+#[timeout(5000)]
+#[test]
+fn handshake_authorized_and_through_middleware() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/live", listener.local_addr().unwrap());
+    let (handshakes, received) = mpsc::channel();
+    thread::spawn(move || {
+        // the first connection is dropped right away
+        for dropped in [true, false] {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept_hdr(stream, Handshakes(handshakes.clone())).unwrap();
+            if dropped {
+                continue;
+            }
+            while socket.read().is_ok() {}
+        }
+    });
+    let mut app = subscription_app();
+    app.insert_resource(QueryAuth::new(CountingAuth(AtomicUsize::new(0))));
+    app.insert_resource(QueryMiddlewareStack::default().with(ApiVersion));
+
+    app.world_mut().commands().trigger(
+        Subscription::new(&url).with_backoff(Duration::from_millis(20), Duration::from_millis(100)),
+    );
+    run_until(&mut app, |app| {
+        app.world().resource::<Received>().statuses.len() == 4
+    });
+
+    // every attempt is authorized again
+    let handshakes: Vec<_> = received.try_iter().collect();
+    assert_eq!(
+        handshakes,
+        vec![
+            ("Bearer 1".to_string(), "true".to_string()),
+            ("Bearer 2".to_string(), "true".to_string()),
+        ]
+    );
+}
@@ -0,0 +1,198 @@
+use crate::{
+    tasks::{response_status, QueryStore},
+    Query,
+};
+use async_channel::{Receiver, Sender};
+use bevy::{
+    asset::io::{
+        AssetReader, AssetReaderError, AssetSource, AssetSourceId, PathStream, Reader, VecReader,
+    },
+    prelude::*,
+    utils::HashMap,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Name of the asset source, assets are loaded from `remote://<host>/<path>`
+pub const REMOTE_SOURCE: &str = "remote";
+
+/// Registers the `remote://` asset source, its reads are sent as queries and cached in the [`QueryStore`]
+///
+/// The first segment of the path is a host alias added with [`RemoteAssetPlugin::with_host`], paths
+/// starting with an unknown alias are requested as `https://<path>`. Loads go through the transport,
+/// auth, offline mode and middleware of the queries, a cached response is served until it is older
+/// than the stale time.
+///
+/// Asset sources must be registered before the `AssetPlugin`, add this plugin before `DefaultPlugins`
+/// and next to the [`crate::QueryTasksPlugin`].
+#[derive(Default, Clone)]
+pub struct RemoteAssetPlugin {
+    /// Hashmap: host alias -> base url
+    pub hosts: HashMap<String, String>,
+    /// Cached responses older than this are fetched again, `None` keeps them until they are removed
+    pub stale_time: Option<Duration>,
+}
+
+impl RemoteAssetPlugin {
+    pub fn with_host(mut self, alias: impl Into<String>, base_url: impl Into<String>) -> Self {
+        self.hosts.insert(alias.into(), base_url.into());
+        self
+    }
+
+    pub fn with_stale_time(mut self, stale_time: Duration) -> Self {
+        self.stale_time = Some(stale_time);
+        self
+    }
+
+    /// Url of an asset path of the remote source
+    pub fn url(&self, path: &Path) -> String {
+        let path = path.to_string_lossy().replace('\\', "/");
+        match path.split_once('/') {
+            Some((host, rest)) if self.hosts.contains_key(host) => {
+                format!("{}/{}", self.hosts[host].trim_end_matches('/'), rest)
+            }
+            _ => format!("https://{}", path),
+        }
+    }
+}
+
+impl Plugin for RemoteAssetPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = async_channel::unbounded();
+        app.register_asset_source(
+            AssetSourceId::from(REMOTE_SOURCE),
+            AssetSource::build().with_reader(move || {
+                Box::new(RemoteAssetReader {
+                    requests: sender.clone(),
+                })
+            }),
+        )
+        .insert_resource(RemoteAssets {
+            settings: self.clone(),
+            requests: receiver,
+            waiting: vec![],
+        })
+        .add_systems(Update, serve_remote_assets);
+    }
+}
+
+/// Read of the asset reader, answered by [`serve_remote_assets`]
+struct AssetRequest {
+    path: PathBuf,
+    respond: Sender<Result<Vec<u8>, AssetReaderError>>,
+}
+
+/// Reads waiting for their query, kept apart from the [`QueryStore`] since they come from the asset
+/// server
+#[derive(Resource)]
+pub struct RemoteAssets {
+    pub settings: RemoteAssetPlugin,
+    requests: Receiver<AssetRequest>,
+    /// (url, query_key) of the query and the read waiting for it
+    waiting: Vec<((String, String), Sender<Result<Vec<u8>, AssetReaderError>>)>,
+}
+
+/// Sends the queries of the asset reads and answers them once the response is cached
+pub fn serve_remote_assets(
+    mut remote: ResMut<RemoteAssets>,
+    mut query_store: ResMut<QueryStore>,
+    mut commands: Commands,
+) {
+    let remote = remote.as_mut();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+
+    while let Ok(request) = remote.requests.try_recv() {
+        let query = Query {
+            url: remote.settings.url(&request.path),
+            binary: true,
+            ..default()
+        };
+        let key = (query.url.clone(), String::new());
+        let fresh = query_store
+            .cache
+            .get(&key)
+            .is_some_and(|(value, cached, called_at)| {
+                cached.binary
+                    && response_status(value) == 200
+                    && query_store.binary_bodies.contains_key(&key)
+                    && remote
+                        .settings
+                        .stale_time
+                        .is_none_or(|stale_time| now < called_at + stale_time.as_millis())
+            });
+        if !fresh {
+            query_store.remove_entry(&key);
+            commands.trigger(query);
+        }
+        remote.waiting.push((key, request.respond));
+    }
+
+    remote.waiting.retain(|(key, respond)| {
+        let Some((value, _, _)) = query_store.cache.get(key) else {
+            return true;
+        };
+        // the read was dropped if nobody listens anymore
+        let _ = respond.try_send(asset_bytes(value, query_store.binary_bodies.get(key), &key.0));
+        false
+    });
+}
+
+/// Body of a cached binary response
+fn asset_bytes(
+    value: &serde_json::Value,
+    body: Option<&Vec<u8>>,
+    url: &str,
+) -> Result<Vec<u8>, AssetReaderError> {
+    match response_status(value) {
+        200 => body
+            .cloned()
+            .ok_or_else(|| io_error(io::ErrorKind::InvalidData, "Cached response is not binary")),
+        404 => Err(AssetReaderError::NotFound(PathBuf::from(url))),
+        // failed requests are cached without a message
+        500 if value.get("msg").is_none() => Err(io_error(io::ErrorKind::Other, "Request failed")),
+        status => Err(AssetReaderError::HttpError(status)),
+    }
+}
+
+fn io_error(kind: io::ErrorKind, msg: &str) -> AssetReaderError {
+    AssetReaderError::Io(Arc::new(io::Error::new(kind, msg)))
+}
+
+/// Asset reader of the `remote://` source, reads are handed to the app to go through the queries
+struct RemoteAssetReader {
+    requests: Sender<AssetRequest>,
+}
+
+impl AssetReader for RemoteAssetReader {
+    async fn read<'a>(&'a self, path: &'a Path) -> Result<impl Reader + 'a, AssetReaderError> {
+        let (respond, response) = async_channel::bounded(1);
+        let request = AssetRequest {
+            path: path.to_path_buf(),
+            respond,
+        };
+        let closed = || io_error(io::ErrorKind::BrokenPipe, "App stopped serving remote assets");
+        self.requests.send(request).await.map_err(|_| closed())?;
+        let bytes = response.recv().await.map_err(|_| closed())??;
+        Ok(VecReader::new(bytes))
+    }
+
+    /// Remote assets use the default settings of their loader
+    async fn read_meta<'a>(&'a self, path: &'a Path) -> Result<impl Reader + 'a, AssetReaderError> {
+        Err::<VecReader, _>(AssetReaderError::NotFound(path.to_path_buf()))
+    }
+
+    async fn read_directory<'a>(&'a self, path: &'a Path) -> Result<Box<PathStream>, AssetReaderError> {
+        Err(AssetReaderError::NotFound(path.to_path_buf()))
+    }
+
+    async fn is_directory<'a>(&'a self, _: &'a Path) -> Result<bool, AssetReaderError> {
+        Ok(false)
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{
+    transport::{HttpRequest, HttpTransport, TransportError},
+    Query,
+};
+use bevy::{
+    prelude::*,
+    tasks::{IoTaskPool, Task, TaskPool},
+    utils::{BoxedFuture, HashSet},
+};
+use std::sync::Arc;
+
+/// Adds credentials to requests and refreshes them when the server answers 401
+///
+/// A single refresh runs at a time, queries answered 401 meanwhile and queries triggered meanwhile wait
+/// for it and are then sent again with the new credentials. A query answered 401 again after a refresh
+/// fails with [`crate::tasks::ErrorTriggerEvent`] like any other error.
+pub trait AuthProvider: Send + Sync + 'static {
+    /// Adds the current credentials to the request of a query about to be sent
+    ///
+    /// Runs before the middleware sees the query, the request holds its method, url, params and headers
+    fn authorize(&self, request: &mut HttpRequest);
+
+    /// Gets new credentials, `transport` can be used to reach the auth server
+    fn refresh(
+        &self,
+        transport: Arc<dyn HttpTransport>,
+    ) -> BoxedFuture<'static, Result<(), TransportError>>;
+}
+
+/// Auth provider used for every query, see [`crate::QueryTasksPlugin::with_auth`]
+#[derive(Resource, Clone)]
+pub struct QueryAuth(pub Arc<dyn AuthProvider>);
+
+impl QueryAuth {
+    pub fn new(provider: impl AuthProvider) -> Self {
+        Self(Arc::new(provider))
+    }
+
+    /// Adds the current credentials to a query about to go through the middleware
+    pub(crate) fn authorize_query(&self, query: &mut Query) {
+        let mut request = HttpRequest {
+            method: query.method,
+            url: query.url.clone(),
+            params: query.params.clone().unwrap_or_default(),
+            headers: query.headers.clone().unwrap_or_default(),
+            ..default()
+        };
+        self.0.authorize(&mut request);
+        query.url = request.url;
+        query.params = (query.params.is_some() || !request.params.is_empty()).then_some(request.params);
+        query.headers = Some(request.headers);
+    }
+}
+
+/// Credentials refresh state kept in the [`crate::tasks::QueryStore`]
+#[derive(Default, Debug)]
+pub struct AuthState {
+    /// Refresh in flight, no query is sent until it finishes
+    pub refresh: Option<Task<Result<(), TransportError>>>,
+    /// Queries answered 401, sent again once the refresh succeeded
+    pub waiting: Vec<Query>,
+    /// Hashmap: (url, query_key) of the queries sent again after a refresh
+    pub replayed: HashSet<(String, String)>,
+}
+
+impl AuthState {
+    pub fn is_refreshing(&self) -> bool {
+        self.refresh.is_some()
+    }
+
+    /// Holds a query answered 401 and starts a refresh unless one is running
+    ///
+    /// Returns the query back if it was already answered 401 after a refresh, it fails this time
+    pub(crate) fn wait_for_refresh(
+        &mut self,
+        auth: &QueryAuth,
+        transport: &Arc<dyn HttpTransport>,
+        query: Query,
+    ) -> Option<Query> {
+        let key = (query.url.clone(), query.query_key.clone().unwrap_or_default());
+        if self.replayed.contains(&key) {
+            return Some(query);
+        }
+
+        self.waiting.push(query);
+        if self.refresh.is_none() {
+            let refresh = auth.0.refresh(transport.clone());
+            self.refresh = Some(IoTaskPool::get_or_init(TaskPool::new).spawn(refresh));
+        }
+        None
+    }
+}
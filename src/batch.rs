@@ -0,0 +1,153 @@
+use crate::{
+    debug_end,
+    logging::PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS,
+    tasks::{response_status, QueryStore},
+    Query,
+};
+use bevy::prelude::*;
+use std::time::SystemTime;
+
+/// How a [`QueryBatch`] settles when one of its queries fails
+#[derive(Default, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BatchMode {
+    /// Settle once every query finished, successfully or not
+    #[default]
+    WaitForAll,
+    /// Settle on the first failed query and drop the requests of the batch still in flight
+    FailFast,
+}
+
+/// Independent queries sent at the same time and reported together with [`BatchSettled`]
+#[derive(Event, Default, Debug, Clone)]
+pub struct QueryBatch {
+    pub key: String,
+    pub queries: Vec<Query>,
+    pub mode: BatchMode,
+}
+
+/// Outcome of a single query of a batch
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchMemberResult {
+    /// Body of the cached response
+    Success(serde_json::Value),
+    /// Status of the failed response
+    Error(u16),
+    /// Still in flight when a fail-fast batch settled, the request was dropped
+    Cancelled,
+    /// Still in flight when a fail-fast batch settled, the request is shared with queries sent outside
+    /// of the batch and keeps going
+    Skipped,
+}
+
+/// Triggered once a batch settled, `results` are in the same order as the batch queries
+#[derive(Event, Debug, Clone)]
+pub struct BatchSettled {
+    pub key: String,
+    pub results: Vec<BatchMemberResult>,
+}
+
+/// Progress of a running [`QueryBatch`]
+#[derive(Default, Debug, Clone)]
+pub struct BatchState {
+    pub queries: Vec<Query>,
+    pub mode: BatchMode,
+    /// Results collected so far, `None` while the query is in flight
+    pub results: Vec<Option<BatchMemberResult>>,
+    /// Whether the request of the query is only used by the batch, only those are cancelled
+    pub owned: Vec<bool>,
+}
+
+impl BatchState {
+    /// Marks the queries of `(url, query_key)` as shared with a query sent outside of the batch
+    pub(crate) fn share(&mut self, url: &str, query_key: &str) {
+        for (query, owned) in self.queries.iter().zip(self.owned.iter_mut()) {
+            if query.url == url && query.query_key.as_deref().unwrap_or_default() == query_key {
+                *owned = false;
+            }
+        }
+    }
+}
+
+/// Sends every query of the batch, [`watch_batches`] settles it
+pub fn api_task_batch(
+    trigger: Trigger<QueryBatch>,
+    mut query_store: ResMut<QueryStore>,
+    mut commands: Commands,
+) {
+    let batch = trigger.event();
+    for query in batch.queries.iter() {
+        commands.trigger(query.clone());
+    }
+
+    query_store.batches.insert(
+        batch.key.clone(),
+        BatchState {
+            queries: batch.queries.clone(),
+            mode: batch.mode,
+            results: vec![None; batch.queries.len()],
+            owned: vec![true; batch.queries.len()],
+        },
+    );
+}
+
+/// Collects finished batch queries from the cache and triggers [`BatchSettled`] for settled batches
+///
+/// Fail-fast batches drop the tasks of their unfinished queries, unless an identical query sent outside of
+/// the batch shares the request
+pub fn watch_batches(mut query_store: ResMut<QueryStore>, mut commands: Commands) {
+    if query_store.batches.is_empty() {
+        return;
+    }
+    let start = SystemTime::now();
+    let store = query_store.bypass_change_detection();
+    let mut batches = std::mem::take(&mut store.batches);
+
+    batches.retain(|key, batch| {
+        let mut failed = false;
+        for (query, result) in batch.queries.iter().zip(batch.results.iter_mut()) {
+            if result.is_none() {
+                let cached = store
+                    .cache
+                    .get(&(query.url.clone(), query.query_key.clone().unwrap_or_default()));
+                if let Some((value, _, _)) = cached {
+                    *result = Some(match response_status(value) {
+                        200 => {
+                            BatchMemberResult::Success(value.get("body").cloned().unwrap_or_default())
+                        }
+                        status => BatchMemberResult::Error(status),
+                    });
+                }
+            }
+            failed |= matches!(result, Some(BatchMemberResult::Error(_)));
+        }
+
+        let settled =
+            batch.results.iter().all(Option::is_some) || (failed && batch.mode == BatchMode::FailFast);
+        if !settled {
+            return true;
+        }
+
+        let results = batch
+            .queries
+            .iter()
+            .zip(batch.results.iter().zip(batch.owned.iter()))
+            .map(|(query, (result, owned))| match result {
+                Some(result) => result.clone(),
+                None if !owned => BatchMemberResult::Skipped,
+                None => {
+                    store.cancel_request(query);
+                    BatchMemberResult::Cancelled
+                }
+            })
+            .collect();
+        commands.trigger(BatchSettled {
+            key: key.clone(),
+            results,
+        });
+
+        false
+    });
+
+    store.batches = batches;
+    debug_end!(start, PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS);
+}
@@ -0,0 +1,185 @@
+use crate::{
+    extractor::QueryConsumable,
+    tasks::{refetch_cached, response_status, QueryStore},
+};
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashMap};
+use serde::de::DeserializeOwned;
+use std::{fmt, time::SystemTime};
+
+pub type QueryResult<T> = Result<T, QueryError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// Nothing is cached and no request is in flight
+    NotCached,
+    /// The request is in flight
+    Fetching,
+    /// The cached response is older than its stale time, a refetch was queued
+    Stale,
+    /// The server responded with an error status
+    Status(u16),
+    /// The body could not be deserialized into the requested type
+    Decode(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::NotCached => write!(f, "No tasks matched"),
+            QueryError::Fetching => write!(f, "Task is loading"),
+            QueryError::Stale => write!(f, "Task is stale"),
+            QueryError::Status(status) => write!(f, "{}", status),
+            QueryError::Decode(err) => write!(f, "Failed to extract body: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryStatus {
+    /// Nothing is cached and no request is in flight
+    Idle,
+    Fetching,
+    Success,
+    Error(u16),
+}
+
+/// Responses already deserialized by a system, reused until the cache entry version changes
+pub struct DecodedResponses<T> {
+    /// Hashmap: (url, query_key) -> (version, response)
+    responses: HashMap<(String, String), (u64, T)>,
+}
+
+impl<T> Default for DecodedResponses<T> {
+    fn default() -> Self {
+        Self {
+            responses: HashMap::default(),
+        }
+    }
+}
+
+/// Typed read access to the query cache
+///
+/// Only needs `Res<QueryStore>` so systems reading the cache can run in parallel, stale responses
+/// and refetches are handled through `Commands`
+#[derive(SystemParam)]
+pub struct CachedQuery<'w, 's, T>
+where
+    T: DeserializeOwned + Send + Sync + 'static,
+{
+    store: Res<'w, QueryStore>,
+    decoded: Local<'s, DecodedResponses<T>>,
+    commands: Commands<'w, 's>,
+}
+
+impl<T> CachedQuery<'_, '_, T>
+where
+    T: DeserializeOwned + Send + Sync + 'static,
+{
+    /// Returns the cached response, deserialized once per version of the cache entry
+    pub fn get(&mut self, consumable: &QueryConsumable) -> QueryResult<&T> {
+        let key = (
+            consumable.url.clone(),
+            consumable.query_key.clone().unwrap_or_default(),
+        );
+        // long polls are closed once nobody reads them
+        if self.store.streams.contains_key(&key) {
+            let key = key.clone();
+            self.commands.queue(move |world: &mut World| {
+                world.resource_mut::<QueryStore>().mark_read(&key);
+            });
+        }
+        let Some((value, query, called_at)) = self.store.cache.get(&key) else {
+            if self.is_fetching(consumable) {
+                return Err(QueryError::Fetching);
+            }
+            return Err(QueryError::NotCached);
+        };
+
+        if let Some(stale_time) = consumable.stale_time {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            if now > called_at + stale_time {
+                let (query, called_at) = (query.clone(), *called_at);
+                self.commands.queue(move |world: &mut World| {
+                    let mut store = world.resource_mut::<QueryStore>();
+                    // another system may already have queued the refetch of this response
+                    if store.cache.get(&key).is_some_and(|entry| entry.2 == called_at) {
+                        store.remove_entry(&key);
+                        store.stale_queries.push(query);
+                    }
+                });
+                return Err(QueryError::Stale);
+            }
+        }
+
+        match response_status(value) {
+            200 => {}
+            status => return Err(QueryError::Status(status)),
+        }
+
+        let version = self.store.versions.get(&key).copied().unwrap_or_default();
+        let decoded = &mut self.decoded.responses;
+        if decoded
+            .get(&key)
+            .is_none_or(|(decoded_version, _)| *decoded_version != version)
+        {
+            let body = value.get("body").cloned().unwrap_or_default();
+            let response =
+                serde_json::from_value(body).map_err(|err| QueryError::Decode(err.to_string()))?;
+            decoded.insert(key.clone(), (version, response));
+        }
+
+        Ok(&decoded[&key].1)
+    }
+
+    pub fn status(&self, consumable: &QueryConsumable) -> QueryStatus {
+        let key = (
+            consumable.url.clone(),
+            consumable.query_key.clone().unwrap_or_default(),
+        );
+        match self.store.cache.get(&key) {
+            Some((value, _, _)) => match response_status(value) {
+                200 => QueryStatus::Success,
+                status => QueryStatus::Error(status),
+            },
+            None if self.is_fetching(consumable) => QueryStatus::Fetching,
+            None => QueryStatus::Idle,
+        }
+    }
+
+    pub fn is_fetching(&self, consumable: &QueryConsumable) -> bool {
+        let query_key = consumable.query_key.clone().unwrap_or_default();
+        self.store
+            .loading_requests
+            .keys()
+            .any(|(url, key, _)| url == &consumable.url && key == &query_key)
+            || self
+                .store
+                .pending_requests
+                .iter()
+                .chain(self.store.auth.waiting.iter())
+                .any(|query| {
+                    query.url == consumable.url
+                        && query.query_key.clone().unwrap_or_default() == query_key
+                })
+    }
+
+    /// Queues the cached query to be sent again, the cached response stays readable until it is replaced
+    pub fn refetch(&mut self, consumable: &QueryConsumable) {
+        let key = (
+            consumable.url.clone(),
+            consumable.query_key.clone().unwrap_or_default(),
+        );
+        let Some((_, query, _)) = self.store.cache.get(&key) else {
+            return;
+        };
+
+        let query = query.clone();
+        self.commands
+            .queue(move |world: &mut World| refetch_cached(world, key, query));
+    }
+}
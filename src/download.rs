@@ -0,0 +1,460 @@
+use crate::{
+    auth::QueryAuth,
+    debug_end,
+    logging::PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS,
+    progress::{Transfer, TransferProgress},
+    proto,
+    tasks::{Method, QueryStore},
+    transport::{HttpRequest, HttpResponse, HttpTransport, QueryTransport, TransportError, DEFAULT_TIMEOUT},
+};
+use async_io::Timer;
+use bevy::{
+    prelude::*,
+    tasks::{
+        block_on,
+        futures_lite::{future, AsyncReadExt, AsyncWriteExt},
+        IoTaskPool, Task, TaskPool,
+    },
+};
+use blocking::{unblock, Unblock};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Times an interrupted transfer is resumed before the download fails
+const MAX_RETRIES: u32 = 3;
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Time the body can go without receiving data before the transfer is resumed
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Size of the chunks written to the temp file
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Downloads a file to `destination` instead of caching the response
+///
+/// The body is written to `<destination>.part` and moved to `destination` once it is complete and its
+/// checksum matches. An interrupted transfer is resumed from the size of the temp file with a `Range`
+/// request, also when the download is triggered again after a restart. The `ETag` or `Last-Modified`
+/// of the body is kept in `<destination>.part.validator` and sent as `If-Range`, a server holding
+/// another version of the file sends it whole. Triggers [`DownloadCompleted`]
+/// or [`DownloadFailed`], and [`QueryProgress`](crate::progress::QueryProgress) events keyed by
+/// (url, destination) meanwhile.
+///
+/// Downloads go through the [`QueryTransport`] and the [`QueryAuth`], the request limits and the
+/// middleware stack do not apply to them.
+#[derive(Event, Debug, Clone)]
+pub struct Download {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub destination: PathBuf,
+    /// Expected SHA-256 of the file, hex encoded
+    pub sha256: Option<String>,
+    pub max_retries: u32,
+    /// Time waited before resuming an interrupted transfer
+    pub retry_delay: Duration,
+    /// Timeout of every attempt until the response headers arrive
+    pub timeout: Duration,
+    /// Time the body can go without receiving data before the transfer is resumed
+    pub read_timeout: Duration,
+}
+
+impl Download {
+    pub fn new(url: impl Into<String>, destination: impl Into<PathBuf>) -> Self {
+        Self {
+            url: url.into(),
+            headers: vec![],
+            destination: destination.into(),
+            sha256: None,
+            max_retries: MAX_RETRIES,
+            retry_delay: RETRY_DELAY,
+            timeout: DEFAULT_TIMEOUT,
+            read_timeout: READ_TIMEOUT,
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_sha256(mut self, sha256: impl Into<String>) -> Self {
+        self.sha256 = Some(sha256.into());
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Temp file the body is written to until the download is complete
+    pub fn part_path(&self) -> PathBuf {
+        let mut path = self.destination.clone().into_os_string();
+        path.push(".part");
+        PathBuf::from(path)
+    }
+
+    /// File keeping the `ETag` or `Last-Modified` of the body in the temp file
+    pub fn validator_path(&self) -> PathBuf {
+        let mut path = self.destination.clone().into_os_string();
+        path.push(".part.validator");
+        PathBuf::from(path)
+    }
+}
+
+/// Triggered once the file was verified and moved to its destination
+#[derive(Event, Debug, Clone)]
+pub struct DownloadCompleted {
+    pub url: String,
+    pub path: PathBuf,
+    /// SHA-256 of the file, hex encoded
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// Triggered when a download gives up, the temp file is kept unless its checksum did not match
+#[derive(Event, Debug, Clone)]
+pub struct DownloadFailed {
+    pub url: String,
+    pub path: PathBuf,
+    pub error: DownloadError,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadError {
+    /// The server answered with an error status
+    Status(u16),
+    /// The transfer was interrupted more than `max_retries` times
+    Transport(TransportError),
+    /// The file could not be written
+    Io(String),
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Status(status) => write!(f, "Server responded with {}", status),
+            DownloadError::Transport(err) => write!(f, "{}", err),
+            DownloadError::Io(err) => write!(f, "Failed to write the file: {}", err),
+            DownloadError::ChecksumMismatch { expected, actual } => {
+                write!(f, "Expected SHA-256 {} but got {}", expected, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+impl From<io::Error> for DownloadError {
+    fn from(err: io::Error) -> Self {
+        DownloadError::Io(err.to_string())
+    }
+}
+
+/// Download in flight, kept in the [`QueryStore`]
+#[derive(Debug)]
+pub struct DownloadState {
+    pub url: String,
+    pub task: Task<Result<(String, u64), DownloadError>>,
+    pub transfer: Transfer,
+}
+
+/// Starts a download unless one to the same destination is in flight
+pub fn spawn_download(
+    trigger: Trigger<Download>,
+    mut query_store: ResMut<QueryStore>,
+    transport: Res<QueryTransport>,
+    auth: Option<Res<QueryAuth>>,
+) {
+    let download = trigger.event().clone();
+    if query_store.downloads.contains_key(&download.destination) {
+        return;
+    }
+
+    let mut request = HttpRequest {
+        method: Method::Get,
+        url: download.url.clone(),
+        headers: download.headers.clone(),
+        timeout: download.timeout,
+        ..default()
+    };
+    // byte ranges refer to the encoded body, ask for the file as it is
+    request.set_header("Accept-Encoding", "identity");
+    if let Some(auth) = auth {
+        auth.0.authorize(&mut request);
+    }
+
+    let transfer = Transfer::default();
+    let transport = transport.0.clone();
+    let progress = transfer.download.clone();
+    let destination = download.destination.clone();
+    let task = IoTaskPool::get_or_init(TaskPool::new)
+        .spawn(async move { run_download(transport, request, download, progress).await });
+
+    query_store.downloads.insert(
+        destination,
+        DownloadState {
+            url: trigger.event().url.clone(),
+            task,
+            transfer,
+        },
+    );
+}
+
+/// Reports the progress of the downloads and triggers their outcome once they finish
+pub fn poll_downloads(mut query_store: ResMut<QueryStore>, mut commands: Commands) {
+    if query_store.downloads.is_empty() {
+        return;
+    }
+    let start = SystemTime::now();
+    query_store
+        .bypass_change_detection()
+        .downloads
+        .retain(|path, download| {
+            let result = block_on(future::poll_once(&mut download.task));
+            download.transfer.report(
+                (download.url.clone(), path.to_string_lossy().to_string()),
+                &mut commands,
+            );
+            let Some(result) = result else {
+                return true;
+            };
+
+            match result {
+                Ok((sha256, size)) => commands.trigger(DownloadCompleted {
+                    url: download.url.clone(),
+                    path: path.clone(),
+                    sha256,
+                    size,
+                }),
+                Err(error) => {
+                    proto!("Download of {} failed: {}", download.url, error);
+                    commands.trigger(DownloadFailed {
+                        url: download.url.clone(),
+                        path: path.clone(),
+                        error,
+                    });
+                }
+            }
+            false
+        });
+    debug_end!(start, PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS);
+}
+
+/// Downloads to the temp file, resuming after interruptions, then verifies and moves it
+///
+/// Returns the SHA-256 and the size of the file
+async fn run_download(
+    transport: Arc<dyn HttpTransport>,
+    request: HttpRequest,
+    download: Download,
+    progress: TransferProgress,
+) -> Result<(String, u64), DownloadError> {
+    let mut retries = 0;
+    loop {
+        match transfer(&transport, &request, &download, &progress).await {
+            Ok(()) => break,
+            Err(DownloadError::Transport(err)) if retries < download.max_retries => {
+                retries += 1;
+                proto!("Download of {} interrupted, resuming: {}", download.url, err);
+                Timer::after(download.retry_delay).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    // the file system and the hashing would block the task pool
+    unblock(move || {
+        let part = download.part_path();
+        let (sha256, size) = sha256_file(&part)?;
+        let _ = fs::remove_file(download.validator_path());
+        if let Some(expected) = &download.sha256 {
+            if !expected.eq_ignore_ascii_case(&sha256) {
+                // the temp file is corrupted, resuming it would not help
+                fs::remove_file(&part)?;
+                return Err(DownloadError::ChecksumMismatch {
+                    expected: expected.clone(),
+                    actual: sha256,
+                });
+            }
+        }
+        fs::rename(&part, &download.destination)?;
+        Ok((sha256, size))
+    })
+    .await
+}
+
+/// Sends one request for the rest of the file and appends the body to the temp file
+async fn transfer(
+    transport: &Arc<dyn HttpTransport>,
+    request: &HttpRequest,
+    download: &Download,
+    progress: &TransferProgress,
+) -> Result<(), DownloadError> {
+    let (part, validator_path) = (download.part_path(), download.validator_path());
+    let (offset, validator) = unblock({
+        let (part, validator_path) = (part.clone(), validator_path.clone());
+        move || {
+            let offset = fs::metadata(part).map_or(0, |metadata| metadata.len());
+            (offset, fs::read_to_string(validator_path).ok())
+        }
+    })
+    .await;
+    let mut request = request.clone();
+    if offset > 0 {
+        request.set_header("Range", format!("bytes={}-", offset));
+        // the server sends the whole file if it changed since the temp file was written
+        if let Some(validator) = validator {
+            request.set_header("If-Range", validator);
+        }
+    }
+    let mut response = transport.send(request).await.map_err(DownloadError::Transport)?;
+    let content_range = response.header("content-range").and_then(parse_content_range);
+
+    let (file, offset) = match response.status {
+        206 if offset > 0 => {
+            if content_range.is_none_or(|(start, _)| start != offset) {
+                unblock(move || File::create(part)).await?;
+                return Err(DownloadError::Transport(TransportError::Other(
+                    "Server resumed at the wrong offset".to_string(),
+                )));
+            }
+            (unblock(move || OpenOptions::new().append(true).open(part)).await?, offset)
+        }
+        // the temp file already holds the whole body
+        416 if content_range.is_some_and(|(_, total)| total == Some(offset)) => return Ok(()),
+        416 => {
+            unblock(move || fs::remove_file(part)).await?;
+            return Err(DownloadError::Transport(TransportError::Other(
+                "Server could not resume the download".to_string(),
+            )));
+        }
+        // the server ignored the range or holds another version of the file, start over
+        status if status < 300 => {
+            let validator = body_validator(&response);
+            let file = unblock(move || {
+                match validator {
+                    Some(validator) => fs::write(validator_path, validator)?,
+                    None => match fs::remove_file(validator_path) {
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                        _ => {}
+                    },
+                }
+                File::create(part)
+            })
+            .await?;
+            (file, 0)
+        }
+        status => return Err(DownloadError::Status(status)),
+    };
+
+    let length: Option<u64> = response
+        .header("content-length")
+        .and_then(|length| length.parse().ok());
+    progress.set_done(offset);
+    progress.set_total(length.map(|length| offset + length));
+
+    let mut file = Unblock::new(file);
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    loop {
+        let read = future::or(
+            async { response.body.read(&mut chunk).await.map_err(TransportError::from) },
+            async {
+                Timer::after(download.read_timeout).await;
+                Err(TransportError::Timeout)
+            },
+        )
+        .await;
+        let read = match read {
+            Ok(read) => read,
+            Err(err) => {
+                // the transfer is resumed from what was written
+                file.flush().await?;
+                return Err(DownloadError::Transport(err));
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        file.write_all(&chunk[..read]).await?;
+        received += read as u64;
+        progress.add(read as u64);
+    }
+    file.flush().await?;
+
+    if length.is_some_and(|length| received < length) {
+        return Err(DownloadError::Transport(TransportError::Connection(
+            "Connection closed before the end of the body".to_string(),
+        )));
+    }
+    Ok(())
+}
+
+/// `If-Range` value of a response, weak ETags can not be used to resume
+fn body_validator(response: &HttpResponse) -> Option<String> {
+    response
+        .header("etag")
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| response.header("last-modified"))
+        .map(str::to_string)
+}
+
+/// Parses `Content-Range: bytes start-end/total` and `bytes */total` into (start, total)
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let total = total.parse().ok();
+    let start = match range {
+        "*" => 0,
+        range => range.split_once('-')?.0.parse().ok()?,
+    };
+    Some((start, total))
+}
+
+fn sha256_file(path: &Path) -> io::Result<(String, u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&chunk[..read]);
+        size += read as u64;
+    }
+    let sha256 = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok((sha256, size))
+}
@@ -13,6 +13,27 @@ pub struct QueryConsumable {
     pub stale_time: Option<u128>,
 }
 
+/// Versions of the cache entries a consumer already read, meant to be kept in a `Local`
+#[derive(Default, Debug, Clone)]
+pub struct QueryCursor {
+    /// Hashmap: (url, query_key) -> last version read
+    pub read_versions: HashMap<(String, String), u64>,
+}
+
+impl QueryCursor {
+    /// Makes the next [`extract_if_changed`] for this query return the cached response again
+    pub fn forget(&mut self, consumable: &QueryConsumable) {
+        self.read_versions.remove(&(
+            consumable.url.clone(),
+            consumable.query_key.clone().unwrap_or_default(),
+        ));
+    }
+
+    pub fn clear(&mut self) {
+        self.read_versions.clear();
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response {
     msg: Option<String>,
@@ -48,6 +69,10 @@ where
 {
     let start = SystemTime::now();
     let mut extracted_task = None;
+    store.mark_read(&(
+        consumable.url.clone(),
+        consumable.query_key.clone().unwrap_or_default(),
+    ));
 
     if !consumable.force_next_refetch {
         let extracted_ref = store.cache.get(&(
@@ -58,19 +83,10 @@ where
             extracted_task = Some(extr.clone());
         }
     } else {
-        let extracted: Vec<((String, String), (serde_json::Value, Query, u128))> = store
-            .cache
-            .extract_if(|e, _| {
-                e.0.eq(&consumable.url)
-                    && e.1
-                        .to_string()
-                        .eq(&consumable.query_key.clone().unwrap_or_default())
-            })
-            .collect();
-
-        if !extracted.is_empty() {
-            extracted_task = Some(extracted.first().unwrap().1.clone());
-        }
+        extracted_task = store.remove_entry(&(
+            consumable.url.clone(),
+            consumable.query_key.clone().unwrap_or_default(),
+        ));
     }
 
     match extracted_task {
@@ -81,9 +97,7 @@ where
                     .as_millis()
                     > value.2 + stale_duration
                 {
-                    store
-                        .cache
-                        .remove(&(consumable.url.clone(), consumable.query_key.unwrap_or_default()));
+                    store.remove_entry(&(consumable.url.clone(), consumable.query_key.unwrap_or_default()));
                     store.stale_queries.push(value.1);
                     return Err(anyhow!("Task is stale"));
                 }
@@ -108,3 +122,69 @@ where
         None => Err(anyhow!("No tasks matched {}", consumable.url)),
     }
 }
+
+/// Returns the latest response only if it changed since the last time this cursor read it
+///
+/// `None` means nothing new is cached, systems can return early until the response is fetched again
+pub fn extract_if_changed<T>(
+    consumable: QueryConsumable,
+    store: &mut QueryStore,
+    cursor: &mut QueryCursor,
+) -> Option<Result<T>>
+where
+    T: DeserializeOwned,
+{
+    let key = (
+        consumable.url.clone(),
+        consumable.query_key.clone().unwrap_or_default(),
+    );
+    store.mark_read(&key);
+    let version = store.version(&key.0, &key.1)?;
+    if cursor.read_versions.get(&key) == Some(&version) {
+        return None;
+    }
+
+    let result = query_extractor::<T>(consumable, store);
+    // a stale response was removed from the cache, wait for the refetched one
+    if store.version(&key.0, &key.1).is_some() {
+        cursor.read_versions.insert(key, version);
+    }
+    Some(result)
+}
+
+/// Returns the items of every fetched page of an infinite query, in page order
+pub fn infinite_query_extractor<T>(key: &str, store: &QueryStore) -> Result<Vec<T>>
+where
+    T: DeserializeOwned,
+{
+    let start = SystemTime::now();
+    let state = store
+        .infinite_queries
+        .get(key)
+        .ok_or_else(|| anyhow!("No infinite query matched {}", key))?;
+    let (value, _, _) = store
+        .cache
+        .get(&(state.query.url.clone(), key.to_string()))
+        .ok_or_else(|| anyhow!("No pages fetched for {}", key))?;
+
+    let api_consumable: Response = serde_json::from_value(value.clone())?;
+    if api_consumable.status != 200 {
+        error!("API error {:?}", api_consumable.msg);
+        return Err(anyhow!(api_consumable.status));
+    }
+
+    let mut items = vec![];
+    let pages = api_consumable.body.unwrap_or_default();
+    for page in pages["pages"].as_array().into_iter().flatten() {
+        let page_items = page
+            .pointer(&state.items_pointer)
+            .and_then(|page_items| page_items.as_array())
+            .ok_or_else(|| anyhow!("Page has no items at {}", state.items_pointer))?;
+        for item in page_items {
+            items.push(serde_json::from_value(item.clone())?);
+        }
+    }
+
+    debug_end!(start, PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS);
+    Ok(items)
+}
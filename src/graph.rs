@@ -0,0 +1,198 @@
+use crate::{
+    debug_end,
+    logging::PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS,
+    proto,
+    tasks::{response_status, QueryStore},
+    Query,
+};
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use std::time::SystemTime;
+
+/// Query in a [`QueryGraph`] that is sent once every node in `depends_on` succeeded
+#[derive(Default, Debug, Clone)]
+pub struct QueryNode {
+    pub id: String,
+    pub query: Query,
+    /// Ids of the nodes that have to succeed before this one is sent
+    pub depends_on: Vec<String>,
+}
+
+/// Workflow of queries, nodes without pending dependencies are sent at the same time
+#[derive(Event, Default, Debug, Clone)]
+pub struct QueryGraph {
+    pub key: String,
+    pub nodes: Vec<QueryNode>,
+}
+
+/// Triggered once every node of a graph has either finished or can no longer run
+#[derive(Event, Debug, Clone)]
+pub struct QueryGraphCompleted {
+    pub key: String,
+    /// Hashmap: node id -> cached response of the node
+    pub results: HashMap<String, serde_json::Value>,
+    /// Nodes that failed or were not sent because a dependency failed
+    pub failed: Vec<String>,
+}
+
+/// Progress of a running [`QueryGraph`]
+#[derive(Default, Debug, Clone)]
+pub struct GraphState {
+    pub nodes: Vec<QueryNode>,
+    /// Nodes sent and waiting for a response
+    pub running: HashSet<String>,
+    pub results: HashMap<String, serde_json::Value>,
+    pub failed: Vec<String>,
+}
+
+impl GraphState {
+    fn is_settled(&self, id: &str) -> bool {
+        self.results.contains_key(id) || self.failed.iter().any(|failed| failed == id)
+    }
+
+    fn is_done(&self) -> bool {
+        self.nodes.iter().all(|node| self.is_settled(&node.id))
+    }
+}
+
+/// Starts a graph, its nodes are sent by [`watch_graphs`] as their dependencies finish
+pub fn api_task_graph(trigger: Trigger<QueryGraph>, mut query_store: ResMut<QueryStore>) {
+    let graph = trigger.event();
+    let mut state = GraphState {
+        nodes: graph.nodes.clone(),
+        ..default()
+    };
+
+    if let Err(err) = validate_graph(&graph.nodes) {
+        proto!("Invalid query graph {}: {}", graph.key, err);
+        state.failed = graph.nodes.iter().map(|node| node.id.clone()).collect();
+    }
+
+    query_store.graphs.insert(graph.key.clone(), state);
+}
+
+/// Collects finished nodes from the cache, sends the nodes that became ready and completes finished graphs
+pub fn watch_graphs(mut query_store: ResMut<QueryStore>, mut commands: Commands) {
+    if query_store.graphs.is_empty() {
+        return;
+    }
+    let start = SystemTime::now();
+    let store = query_store.bypass_change_detection();
+    let mut graphs = std::mem::take(&mut store.graphs);
+
+    graphs.retain(|key, graph| {
+        let finished: Vec<(String, serde_json::Value)> = graph
+            .nodes
+            .iter()
+            .filter(|node| graph.running.contains(&node.id))
+            .filter_map(|node| {
+                store
+                    .cache
+                    .get(&(
+                        node.query.url.clone(),
+                        node.query.query_key.clone().unwrap_or_default(),
+                    ))
+                    .map(|(value, _, _)| (node.id.clone(), value.clone()))
+            })
+            .collect();
+
+        for (id, value) in finished {
+            graph.running.remove(&id);
+            if response_status(&value) != 200 {
+                graph.failed.push(id.clone());
+            }
+            graph.results.insert(id, value);
+        }
+
+        // nodes depending on a failed node can never run, repeat until dependents of dependents are caught
+        loop {
+            let blocked: Vec<String> = graph
+                .nodes
+                .iter()
+                .filter(|node| !graph.is_settled(&node.id) && !graph.running.contains(&node.id))
+                .filter(|node| {
+                    node.depends_on
+                        .iter()
+                        .any(|dependency| graph.failed.contains(dependency))
+                })
+                .map(|node| node.id.clone())
+                .collect();
+            if blocked.is_empty() {
+                break;
+            }
+            graph.failed.extend(blocked);
+        }
+
+        let ready: Vec<QueryNode> = graph
+            .nodes
+            .iter()
+            .filter(|node| !graph.is_settled(&node.id) && !graph.running.contains(&node.id))
+            .filter(|node| {
+                node.depends_on
+                    .iter()
+                    .all(|dependency| graph.results.contains_key(dependency))
+            })
+            .cloned()
+            .collect();
+
+        for node in ready {
+            graph.running.insert(node.id);
+            commands.trigger(node.query);
+        }
+
+        if graph.is_done() {
+            commands.trigger(QueryGraphCompleted {
+                key: key.clone(),
+                results: std::mem::take(&mut graph.results),
+                failed: std::mem::take(&mut graph.failed),
+            });
+            return false;
+        }
+
+        true
+    });
+
+    store.graphs = graphs;
+    debug_end!(start, PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS);
+}
+
+/// Checks that node ids are unique, every dependency exists and there are no cycles
+fn validate_graph(nodes: &[QueryNode]) -> Result<(), String> {
+    let mut ids = HashSet::new();
+    for node in nodes {
+        if !ids.insert(node.id.as_str()) {
+            return Err(format!("duplicate node {}", node.id));
+        }
+    }
+    for node in nodes {
+        if let Some(missing) = node
+            .depends_on
+            .iter()
+            .find(|dependency| !ids.contains(dependency.as_str()))
+        {
+            return Err(format!("{} depends on unknown node {}", node.id, missing));
+        }
+    }
+
+    let mut resolved: HashSet<&str> = HashSet::new();
+    while resolved.len() < nodes.len() {
+        let next: Vec<&str> = nodes
+            .iter()
+            .filter(|node| !resolved.contains(node.id.as_str()))
+            .filter(|node| {
+                node.depends_on
+                    .iter()
+                    .all(|dependency| resolved.contains(dependency.as_str()))
+            })
+            .map(|node| node.id.as_str())
+            .collect();
+        if next.is_empty() {
+            return Err("dependency cycle".to_string());
+        }
+        resolved.extend(next);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,389 @@
+use crate::{
+    debug_end,
+    logging::PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS,
+    tasks::{response_status, QueryStore},
+    Query,
+};
+use bevy::prelude::*;
+use serde_json::json;
+use std::time::SystemTime;
+
+/// How the request for the page before or after a fetched page is built
+#[derive(Debug, Clone, PartialEq)]
+pub enum PageParam {
+    /// Cursor read from the page body at a JSON pointer and sent as the `param` query parameter
+    ///
+    /// A missing or null cursor means there is no page in that direction
+    Cursor {
+        param: String,
+        next_pointer: String,
+        previous_pointer: Option<String>,
+    },
+    /// Offset sent as the `param` query parameter, a page with fewer than `limit` items is the last one
+    Offset { param: String, limit: usize },
+    /// Urls of the `next` and `prev` relations of the `Link` response header
+    LinkHeader,
+}
+
+impl Default for PageParam {
+    fn default() -> Self {
+        PageParam::Cursor {
+            param: "cursor".to_string(),
+            next_pointer: "/next_cursor".to_string(),
+            previous_pointer: None,
+        }
+    }
+}
+
+/// Paginated query whose pages are stored together in the cache under `(query.url, key)`
+///
+/// `query` fetches the first page, the following ones are requested with [`FetchNextPage`] and
+/// [`FetchPreviousPage`]
+#[derive(Event, Default, Debug, Clone)]
+pub struct InfiniteQuery {
+    pub key: String,
+    pub query: Query,
+    pub page_param: PageParam,
+    /// JSON pointer to the items array of a page, an empty pointer means the page body is the array
+    pub items_pointer: String,
+}
+
+/// Requests the page after the last fetched page of an infinite query
+#[derive(Event, Debug, Clone)]
+pub struct FetchNextPage {
+    pub key: String,
+}
+
+/// Requests the page before the first fetched page of an infinite query
+#[derive(Event, Debug, Clone)]
+pub struct FetchPreviousPage {
+    pub key: String,
+}
+
+#[derive(Default, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PageDirection {
+    #[default]
+    Next,
+    Previous,
+}
+
+/// Pagination state of an [`InfiniteQuery`], the pages themselves live in the cache
+#[derive(Default, Debug, Clone)]
+pub struct InfiniteQueryState {
+    pub query: Query,
+    pub page_param: PageParam,
+    pub items_pointer: String,
+    pub next_page: Option<Query>,
+    pub previous_page: Option<Query>,
+    /// Page in flight and the side of the fetched pages it goes to
+    pub fetching: Option<(Query, PageDirection)>,
+}
+
+impl InfiniteQueryState {
+    pub fn has_next_page(&self) -> bool {
+        self.next_page.is_some()
+    }
+
+    pub fn has_previous_page(&self) -> bool {
+        self.previous_page.is_some()
+    }
+
+    pub fn is_fetching_page(&self) -> bool {
+        self.fetching.is_some()
+    }
+}
+
+/// Starts an infinite query by fetching its first page
+///
+/// Fetched pages of the same key are dropped, as is a page still in flight so it is not merged into the
+/// new pages
+pub fn api_task_infinite_query(
+    trigger: Trigger<InfiniteQuery>,
+    mut query_store: ResMut<QueryStore>,
+    mut commands: Commands,
+) {
+    let infinite_query = trigger.event();
+    query_store.remove_entry(&(infinite_query.query.url.clone(), infinite_query.key.clone()));
+    let fetching = query_store
+        .infinite_queries
+        .remove(&infinite_query.key)
+        .and_then(|state| state.fetching);
+    if let Some((page, _)) = fetching {
+        query_store.cancel_request(&page);
+        query_store.remove_entry(&(page.url.clone(), page.query_key.clone().unwrap_or_default()));
+    }
+
+    let first_page = page_query(&infinite_query.key, infinite_query.query.clone());
+    let page_key = (
+        first_page.url.clone(),
+        first_page.query_key.clone().unwrap_or_default(),
+    );
+    query_store.remove_entry(&page_key);
+    commands.trigger(first_page.clone());
+    query_store.infinite_queries.insert(
+        infinite_query.key.clone(),
+        InfiniteQueryState {
+            query: infinite_query.query.clone(),
+            page_param: infinite_query.page_param.clone(),
+            items_pointer: infinite_query.items_pointer.clone(),
+            fetching: Some((first_page, PageDirection::Next)),
+            ..default()
+        },
+    );
+}
+
+pub fn fetch_next_page(
+    trigger: Trigger<FetchNextPage>,
+    mut query_store: ResMut<QueryStore>,
+    mut commands: Commands,
+) {
+    fetch_page(
+        &mut query_store,
+        &trigger.event().key,
+        PageDirection::Next,
+        &mut commands,
+    );
+}
+
+pub fn fetch_previous_page(
+    trigger: Trigger<FetchPreviousPage>,
+    mut query_store: ResMut<QueryStore>,
+    mut commands: Commands,
+) {
+    fetch_page(
+        &mut query_store,
+        &trigger.event().key,
+        PageDirection::Previous,
+        &mut commands,
+    );
+}
+
+/// Sends the page request for the given direction, unless a page is already in flight
+fn fetch_page(
+    query_store: &mut QueryStore,
+    key: &str,
+    direction: PageDirection,
+    commands: &mut Commands,
+) {
+    let Some(state) = query_store.infinite_queries.get_mut(key) else {
+        return;
+    };
+    if state.is_fetching_page() {
+        return;
+    }
+
+    let page = match direction {
+        PageDirection::Next => state.next_page.clone(),
+        PageDirection::Previous => state.previous_page.clone(),
+    };
+    if let Some(page) = page {
+        let page = page_query(key, page);
+        let page_key = (page.url.clone(), page.query_key.clone().unwrap_or_default());
+        state.fetching = Some((page.clone(), direction));
+        query_store.remove_entry(&page_key);
+        commands.trigger(page);
+    }
+}
+
+/// Moves fetched pages from their own cache entry into the entry of their infinite query
+pub fn watch_infinite_queries(mut query_store: ResMut<QueryStore>) {
+    if query_store.infinite_queries.is_empty() {
+        return;
+    }
+    let start = SystemTime::now();
+    let store = query_store.bypass_change_detection();
+    let mut changed = false;
+    let mut updated = vec![];
+    let mut infinite_queries = std::mem::take(&mut store.infinite_queries);
+
+    for (key, state) in infinite_queries.iter_mut() {
+        let Some((page, direction)) = state.fetching.clone() else {
+            continue;
+        };
+        let page_key = (page.url.clone(), page.query_key.clone().unwrap_or_default());
+        let Some((value, _, called_at)) = store.remove_entry(&page_key) else {
+            continue;
+        };
+        state.fetching = None;
+        changed = true;
+
+        let entry_key = (state.query.url.clone(), key.clone());
+        let status = response_status(&value);
+        if status != 200 {
+            // a failed first page becomes the entry so consumers see the error, later pages are dropped
+            if !store.cache.contains_key(&entry_key) {
+                updated.push(entry_key.clone());
+                store
+                    .cache
+                    .insert(entry_key, (value, state.query.clone(), called_at));
+            }
+            continue;
+        }
+        updated.push(entry_key.clone());
+
+        let body = value.get("body").cloned().unwrap_or_default();
+        let link = value.pointer("/headers/link").and_then(|link| link.as_str());
+        let (previous_page, next_page) = adjacent_pages(state, &page, &body, link);
+
+        let entry = store.cache.entry(entry_key).or_insert_with(|| {
+            (
+                json!({"status": 200, "body": {"pages": []}}),
+                state.query.clone(),
+                called_at,
+            )
+        });
+        let pages = entry.0["body"]["pages"].as_array_mut();
+        match (direction, pages) {
+            (PageDirection::Next, Some(pages)) => {
+                if pages.is_empty() {
+                    state.previous_page = previous_page;
+                }
+                pages.push(body);
+                state.next_page = next_page;
+            }
+            (PageDirection::Previous, Some(pages)) => {
+                pages.insert(0, body);
+                state.previous_page = previous_page;
+            }
+            _ => {
+                // the entry held a failed first page, start over from this page
+                entry.0 = json!({"status": 200, "body": {"pages": [body]}});
+                state.previous_page = previous_page;
+                state.next_page = next_page;
+            }
+        }
+        entry.2 = called_at;
+    }
+
+    store.infinite_queries = infinite_queries;
+    for key in updated {
+        store.mark_updated(key);
+    }
+    if changed {
+        query_store.set_changed();
+    }
+    debug_end!(start, PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS);
+}
+
+/// Returns `true` if the infinite query has a page after its last fetched page
+pub fn has_next_page(key: &str, store: &QueryStore) -> bool {
+    store
+        .infinite_queries
+        .get(key)
+        .is_some_and(InfiniteQueryState::has_next_page)
+}
+
+/// Returns `true` if the infinite query has a page before its first fetched page
+pub fn has_previous_page(key: &str, store: &QueryStore) -> bool {
+    store
+        .infinite_queries
+        .get(key)
+        .is_some_and(InfiniteQueryState::has_previous_page)
+}
+
+/// Page requests are cached under their own key until they are merged into the infinite query entry
+fn page_query(key: &str, query: Query) -> Query {
+    Query {
+        query_key: Some(format!("{}#page", key)),
+        ..query
+    }
+}
+
+/// Builds the requests for the pages before and after `page`
+fn adjacent_pages(
+    state: &InfiniteQueryState,
+    page: &Query,
+    body: &serde_json::Value,
+    link: Option<&str>,
+) -> (Option<Query>, Option<Query>) {
+    match &state.page_param {
+        PageParam::Cursor {
+            param,
+            next_pointer,
+            previous_pointer,
+        } => {
+            let cursor_query = |pointer: &str| {
+                let cursor = match body.pointer(pointer)? {
+                    serde_json::Value::String(cursor) => cursor.clone(),
+                    serde_json::Value::Number(cursor) => cursor.to_string(),
+                    _ => return None,
+                };
+                Some(with_param(&state.query, param, cursor))
+            };
+            (
+                previous_pointer.as_deref().and_then(cursor_query),
+                cursor_query(next_pointer),
+            )
+        }
+        PageParam::Offset { param, limit } => {
+            let offset = page
+                .params
+                .iter()
+                .flatten()
+                .find(|(name, _)| name == param)
+                .and_then(|(_, offset)| offset.parse::<usize>().ok())
+                .unwrap_or_default();
+            let items = body
+                .pointer(&state.items_pointer)
+                .and_then(|items| items.as_array())
+                .map_or(0, Vec::len);
+            (
+                (offset > 0)
+                    .then(|| with_param(&state.query, param, offset.saturating_sub(*limit).to_string())),
+                (items >= *limit).then(|| with_param(&state.query, param, (offset + limit).to_string())),
+            )
+        }
+        PageParam::LinkHeader => {
+            let links = link.map(parse_link_header).unwrap_or_default();
+            let link_query = |relation: &str| {
+                links
+                    .iter()
+                    .find(|(_, rel)| rel == relation)
+                    .map(|(url, _)| Query {
+                        url: url.clone(),
+                        params: None,
+                        ..state.query.clone()
+                    })
+            };
+            (link_query("prev"), link_query("next"))
+        }
+    }
+}
+
+/// Returns a copy of `query` with the query parameter `name` set to `value`
+fn with_param(query: &Query, name: &str, value: String) -> Query {
+    let mut params: Vec<(String, String)> = query
+        .params
+        .iter()
+        .flatten()
+        .filter(|(param, _)| param != name)
+        .cloned()
+        .collect();
+    params.push((name.to_string(), value));
+
+    Query {
+        params: Some(params),
+        ..query.clone()
+    }
+}
+
+/// Parses a `Link` header into `(url, rel)` pairs
+pub(crate) fn parse_link_header(header: &str) -> Vec<(String, String)> {
+    header
+        .split(',')
+        .filter_map(|link| {
+            let mut parts = link.split(';');
+            let url = parts
+                .next()?
+                .trim()
+                .strip_prefix('<')?
+                .strip_suffix('>')?
+                .to_string();
+            let rel = parts.find_map(|part| {
+                let (name, value) = part.split_once('=')?;
+                (name.trim() == "rel").then(|| value.trim().trim_matches('"').to_string())
+            })?;
+            Some((url, rel))
+        })
+        .collect()
+}
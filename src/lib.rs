@@ -1,16 +1,81 @@
+use auth::{AuthProvider, QueryAuth};
+use batch::{api_task_batch, watch_batches};
 use bevy::{prelude::*, time::common_conditions::on_timer};
+use download::{poll_downloads, spawn_download};
+use graph::{api_task_graph, watch_graphs};
+use infinite::{api_task_infinite_query, fetch_next_page, fetch_previous_page, watch_infinite_queries};
+use middleware::{QueryMiddleware, QueryMiddlewareStack};
+use offline::{sync_offline_mode, OfflineMode, OnlineStatus};
+use scheduler::RequestLimits;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
+use stream::{close_stream, poll_streams};
 use tasks::{
     api_task_poll, api_task_sequence, loading_requests_is_empty, spawn_api_task, watch_cache, QueryStore,
 };
+use transport::{HttpTransport, QueryTransport};
+use websocket::{poll_subscriptions, send_to_subscription, subscribe, unsubscribe};
 
 mod _tests_;
+pub mod asset;
+pub mod auth;
+pub mod batch;
+pub mod cached_query;
+pub mod download;
 pub mod extractor;
+pub mod graph;
+pub mod infinite;
+pub mod live;
 mod logging;
+pub mod middleware;
+pub mod offline;
+pub mod progress;
+pub mod scheduler;
+pub mod stream;
 pub mod tasks;
+pub mod transport;
+pub mod websocket;
 
-pub struct QueryTasksPlugin;
+#[derive(Default)]
+pub struct QueryTasksPlugin {
+    transport: Option<Arc<dyn HttpTransport>>,
+    limits: RequestLimits,
+    offline_mode: Option<OfflineMode>,
+    auth: Option<QueryAuth>,
+    middleware: QueryMiddlewareStack,
+}
+
+impl QueryTasksPlugin {
+    /// Sends every query through `transport` instead of the default `ureq` transport
+    pub fn with_transport(mut self, transport: impl HttpTransport) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Caps the number of requests in flight, queries over the caps are queued by priority
+    pub fn with_limits(mut self, limits: RequestLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Tracks the [`OnlineStatus`] and holds mutations while offline, see [`OfflineMode`]
+    pub fn with_offline_mode(mut self, offline_mode: OfflineMode) -> Self {
+        self.offline_mode = Some(offline_mode);
+        self
+    }
+
+    /// Adds credentials to every query and refreshes them when the server answers 401
+    pub fn with_auth(mut self, provider: impl AuthProvider) -> Self {
+        self.auth = Some(QueryAuth::new(provider));
+        self
+    }
+
+    /// Adds a layer to the middleware stack, layers see requests in the order they were added
+    pub fn with_middleware(mut self, middleware: impl QueryMiddleware) -> Self {
+        self.middleware = self.middleware.with(middleware);
+        self
+    }
+}
 pub type Query = tasks::Query;
 
 #[derive(Serialize, Deserialize)]
@@ -21,15 +86,56 @@ pub struct ErrorResponse {
 
 impl Plugin for QueryTasksPlugin {
     fn build(&self, app: &mut App) {
+        match self.transport.clone() {
+            Some(transport) => app.insert_resource(QueryTransport(transport)),
+            None => app.init_resource::<QueryTransport>(),
+        };
+
+        app.insert_resource(self.limits.clone());
+        app.insert_resource(self.middleware.clone());
+        if let Some(auth) = self.auth.clone() {
+            app.insert_resource(auth);
+        }
+
         app.add_systems(
             FixedUpdate,
             api_task_poll
                 .run_if(on_timer(Duration::from_millis(100)))
                 .run_if(not(loading_requests_is_empty)),
         )
-        .add_systems(FixedUpdate, watch_cache)
+        .add_systems(
+            FixedUpdate,
+            (watch_cache, watch_graphs, watch_batches, watch_infinite_queries),
+        )
+        .add_systems(
+            FixedUpdate,
+            poll_downloads.run_if(on_timer(Duration::from_millis(100))),
+        )
+        .add_systems(FixedUpdate, (poll_subscriptions, poll_streams))
         .init_resource::<QueryStore>()
+        .init_resource::<OnlineStatus>()
         .add_observer(spawn_api_task)
-        .add_observer(api_task_sequence);
+        .add_observer(api_task_sequence)
+        .add_observer(api_task_graph)
+        .add_observer(api_task_batch)
+        .add_observer(api_task_infinite_query)
+        .add_observer(fetch_next_page)
+        .add_observer(fetch_previous_page)
+        .add_observer(spawn_download)
+        .add_observer(subscribe)
+        .add_observer(unsubscribe)
+        .add_observer(send_to_subscription)
+        .add_observer(close_stream);
+
+        if let Some(offline_mode) = self.offline_mode.clone() {
+            match offline_mode.load_queue() {
+                Ok(queue) => app.world_mut().resource_mut::<QueryStore>().offline.queue = queue,
+                Err(err) => proto!("Failed to read the offline queue {:#?}", err),
+            }
+            app.insert_resource(offline_mode).add_systems(
+                FixedUpdate,
+                sync_offline_mode.run_if(on_timer(Duration::from_millis(100))),
+            );
+        }
     }
 }
@@ -0,0 +1,92 @@
+use crate::{
+    tasks::{response_status, QueryStore},
+    Query,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::SystemTime;
+
+/// How a live update is written into its cache entry
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MergeStrategy {
+    /// The update becomes the body
+    #[default]
+    Replace,
+    /// The body is an array the updates are pushed to, only the last `max_items` are kept if set
+    Append { max_items: Option<usize> },
+    /// Object updates are applied to the body as a JSON merge patch (RFC 7386), `null` removes a field
+    Patch,
+}
+
+impl MergeStrategy {
+    pub fn apply(&self, body: &mut Value, update: Value) {
+        match self {
+            MergeStrategy::Replace => *body = update,
+            MergeStrategy::Append { max_items } => {
+                if !body.is_array() {
+                    // keep a fetched body that is not a list as the first item
+                    *body = match body.take() {
+                        Value::Null => json!([]),
+                        previous => json!([previous]),
+                    };
+                }
+                let items = body.as_array_mut().unwrap();
+                items.push(update);
+                if let Some(max_items) = max_items {
+                    let excess = items.len().saturating_sub(*max_items);
+                    items.drain(..excess);
+                }
+            }
+            MergeStrategy::Patch => merge_patch(body, update),
+        }
+    }
+}
+
+fn merge_patch(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = json!({});
+    }
+    let target = target.as_object_mut().unwrap();
+    for (name, value) in patch {
+        if value.is_null() {
+            target.remove(&name);
+        } else {
+            merge_patch(target.entry(name).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Merges an update into the cache entry `(url, query_key)`, as if it was the body of a fetched response
+///
+/// A missing or failed entry starts over from an empty body, cached with `query` if it is set. The entry
+/// is marked as fetched now.
+pub(crate) fn merge_update(
+    store: &mut QueryStore,
+    key: (String, String),
+    update: Value,
+    strategy: MergeStrategy,
+    query: Option<&Query>,
+) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let (mut value, query) = match store.cache.remove(&key) {
+        Some((value, query, _)) if response_status(&value) == 200 => (value, query),
+        _ => (
+            json!({"status": 200, "body": null, "headers": {}}),
+            query.cloned().unwrap_or_else(|| Query {
+                url: key.0.clone(),
+                query_key: Some(key.1.clone()).filter(|query_key| !query_key.is_empty()),
+                ..Default::default()
+            }),
+        ),
+    };
+    strategy.apply(&mut value["body"], update);
+    store.cache.insert(key.clone(), (value, query, now));
+    store.mark_updated(key);
+}
@@ -0,0 +1,62 @@
+use crate::{
+    transport::{FetchedResponse, TransportError},
+    Query,
+};
+use bevy::prelude::*;
+use std::sync::Arc;
+
+/// Layer of the [`QueryMiddlewareStack`], both hooks do nothing by default
+///
+/// `on_request` runs on the main thread when the query is sent, once the [`crate::auth::AuthProvider`]
+/// added the credentials, `on_response` runs on the task pool once the response is read, before it
+/// is written to the cache. Queries read as a [`crate::stream::StreamMode`] and the handshakes of
+/// [`crate::websocket::Subscription`] only go through `on_request`.
+pub trait QueryMiddleware: Send + Sync + 'static {
+    /// Inspects or modifies the query about to be sent, returning a response skips the transport
+    /// and the following layers
+    ///
+    /// The query is modified for this request only, the cache keeps the query as it was triggered
+    fn on_request(&self, _query: &mut Query) -> Option<FetchedResponse> {
+        None
+    }
+
+    /// Inspects or modifies the response, or the transport error, of the query as it was sent
+    fn on_response(&self, _query: &Query, _response: &mut Result<FetchedResponse, TransportError>) {}
+}
+
+/// Middleware applied to every query, see [`crate::QueryTasksPlugin::with_middleware`]
+///
+/// Requests go through the layers in the order they were added and responses in reverse order
+#[derive(Resource, Default, Clone)]
+pub struct QueryMiddlewareStack(pub Vec<Arc<dyn QueryMiddleware>>);
+
+impl QueryMiddlewareStack {
+    pub fn with(mut self, middleware: impl QueryMiddleware) -> Self {
+        self.0.push(Arc::new(middleware));
+        self
+    }
+
+    /// Runs `on_request` of every layer, stops at the first layer answering the query
+    ///
+    /// Returns the number of layers that saw the request and the response if one answered it
+    pub(crate) fn on_request(&self, query: &mut Query) -> (usize, Option<FetchedResponse>) {
+        for (index, middleware) in self.0.iter().enumerate() {
+            if let Some(response) = middleware.on_request(query) {
+                return (index + 1, Some(response));
+            }
+        }
+        (self.0.len(), None)
+    }
+
+    /// Runs `on_response` of the first `layers` layers in reverse order
+    pub(crate) fn on_response(
+        &self,
+        layers: usize,
+        query: &Query,
+        response: &mut Result<FetchedResponse, TransportError>,
+    ) {
+        for middleware in self.0[..layers].iter().rev() {
+            middleware.on_response(query, response);
+        }
+    }
+}
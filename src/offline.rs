@@ -0,0 +1,302 @@
+use crate::{
+    auth::QueryAuth,
+    middleware::QueryMiddlewareStack,
+    proto,
+    scheduler::{promote_pending_requests, RequestLimits},
+    tasks::{refetch_cached, Method, QueryStore},
+    transport::{HttpRequest, QueryTransport},
+    Query,
+};
+use bevy::{
+    prelude::*,
+    tasks::{block_on, futures_lite::future, IoTaskPool, Task, TaskPool},
+};
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// Time between two connectivity checks while offline
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+/// Failed replays after which a held mutation is dropped
+const MAX_REPLAY_ATTEMPTS: u32 = 5;
+
+/// Whether the server could be reached by the last requests
+///
+/// Only tracked when offline mode is enabled with [`crate::QueryTasksPlugin::with_offline_mode`]
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnlineStatus {
+    #[default]
+    Online,
+    Offline,
+}
+
+impl OnlineStatus {
+    pub fn is_online(&self) -> bool {
+        *self == OnlineStatus::Online
+    }
+}
+
+/// Triggered every time the [`OnlineStatus`] changes
+#[derive(Event, Debug, Clone, Copy)]
+pub struct OnlineStatusChanged {
+    pub status: OnlineStatus,
+}
+
+/// Triggered when a held mutation is dropped because the server failed it `attempts` times
+#[derive(Event, Debug, Clone)]
+pub struct MutationDropped {
+    pub query: Query,
+    /// Status of the last failed attempt
+    pub status: u16,
+    pub attempts: u32,
+}
+
+/// Settings of the offline mode
+///
+/// The client goes offline when a request fails to connect and back online as soon as a response
+/// arrives. Mutations (`POST` and `DELETE` queries outside of a sequence) triggered while offline, or
+/// failing to connect, are held and sent again one at a time in order once the client is back online.
+/// A held mutation leaves the queue once the server answered it, it is sent again after
+/// `probe_interval` if the server answered a 5xx, 408 or 429 status. After `max_replay_attempts` such
+/// answers it is dropped and [`MutationDropped`] is triggered, so the mutations behind it go out.
+#[derive(Resource, Debug, Clone)]
+pub struct OfflineMode {
+    /// Url requested while offline to find out when the connection is back, any response counts
+    ///
+    /// Without a probe url the first held mutation is sent again instead
+    pub probe_url: Option<String>,
+    pub probe_interval: Duration,
+    /// File the held mutations are written to, they are loaded again when the plugin is built
+    pub queue_path: Option<PathBuf>,
+    /// Failed replays of a held mutation before it is dropped, counted again from zero after a restart
+    pub max_replay_attempts: u32,
+}
+
+impl Default for OfflineMode {
+    fn default() -> Self {
+        Self {
+            probe_url: None,
+            probe_interval: PROBE_INTERVAL,
+            queue_path: None,
+            max_replay_attempts: MAX_REPLAY_ATTEMPTS,
+        }
+    }
+}
+
+impl OfflineMode {
+    pub fn with_probe_url(mut self, probe_url: impl Into<String>) -> Self {
+        self.probe_url = Some(probe_url.into());
+        self
+    }
+
+    pub fn with_probe_interval(mut self, probe_interval: Duration) -> Self {
+        self.probe_interval = probe_interval;
+        self
+    }
+
+    pub fn with_queue_path(mut self, queue_path: impl Into<PathBuf>) -> Self {
+        self.queue_path = Some(queue_path.into());
+        self
+    }
+
+    pub fn with_max_replay_attempts(mut self, max_replay_attempts: u32) -> Self {
+        self.max_replay_attempts = max_replay_attempts;
+        self
+    }
+
+    /// Reads the mutations held by a previous run, empty if there is no queue file
+    pub fn load_queue(&self) -> io::Result<VecDeque<Query>> {
+        let Some(path) = &self.queue_path else {
+            return Ok(VecDeque::new());
+        };
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(VecDeque::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save_queue(&self, queue: &VecDeque<Query>) -> io::Result<()> {
+        let Some(path) = &self.queue_path else {
+            return Ok(());
+        };
+        fs::write(path, serde_json::to_vec(queue)?)
+    }
+}
+
+/// Offline mode state kept in the [`QueryStore`]
+#[derive(Default, Debug)]
+pub struct OfflineState {
+    /// Mutations held while offline, in the order they were triggered
+    pub queue: VecDeque<Query>,
+    /// (url, query_key) of the held mutation being sent again, it stays in front of the queue until the
+    /// server answered it
+    pub replaying: Option<(String, String)>,
+    /// The server could not handle the last mutation sent again, the next attempt waits for the
+    /// probe interval
+    replay_failed: bool,
+    /// Failed replays of the mutation in front of the queue
+    replay_attempts: u32,
+    /// Whether a request completed since the last sync reached the server
+    reached_server: Option<bool>,
+    probe: Option<Task<bool>>,
+    last_attempt: Option<Instant>,
+    /// The queue changed since it was last written to disk
+    dirty: bool,
+}
+
+impl OfflineState {
+    /// Holds a mutation until the client is back online
+    pub(crate) fn hold(&mut self, query: Query) {
+        self.queue.push_back(query);
+        self.dirty = true;
+    }
+
+    /// Records the outcome of a completed request, `status` is 500 for transport errors
+    ///
+    /// Returns `None` if the query is a mutation that could not reach the server, it is held again
+    /// and should not be cached
+    pub(crate) fn observe(
+        &mut self,
+        offline_mode: &OfflineMode,
+        query: Query,
+        reached_server: bool,
+        status: u16,
+        commands: &mut Commands,
+    ) -> Option<Query> {
+        self.reached_server = Some(self.reached_server.unwrap_or_default() || reached_server);
+        let key = (query.url.clone(), query.query_key.clone().unwrap_or_default());
+        if self.replaying.as_ref() == Some(&key) {
+            self.replaying = None;
+            // the replayed mutation is the oldest one, it was kept in front of the others until now
+            self.replay_failed = reached_server && matches!(status, 408 | 429 | 500..);
+            if self.replay_failed {
+                self.replay_attempts += 1;
+            }
+            let dropped = self.replay_attempts >= offline_mode.max_replay_attempts;
+            if reached_server && (!self.replay_failed || dropped) {
+                if dropped {
+                    commands.trigger(MutationDropped {
+                        query: query.clone(),
+                        status,
+                        attempts: self.replay_attempts,
+                    });
+                }
+                self.queue.pop_front();
+                self.replay_failed = false;
+                self.replay_attempts = 0;
+                self.dirty = true;
+            }
+            return reached_server.then_some(query);
+        }
+        if reached_server || !is_mutation(&query) {
+            return Some(query);
+        }
+
+        self.queue.push_back(query);
+        self.dirty = true;
+        None
+    }
+
+    /// Mutations are held while offline and while older ones wait to be sent, to keep them in order
+    pub(crate) fn should_hold(&self, status: OnlineStatus, query: &Query) -> bool {
+        is_mutation(query) && (!status.is_online() || !self.queue.is_empty() || self.replaying.is_some())
+    }
+}
+
+/// Queries changing the state of the server, sequence steps are left to their sequence
+pub(crate) fn is_mutation(query: &Query) -> bool {
+    query.method != Method::Get && query.sequence_key.is_none()
+}
+
+/// Updates the [`OnlineStatus`], checks connectivity while offline and sends the held mutations
+///
+/// Going back online refetches the cached queries marked `refetch_on_reconnect`
+pub fn sync_offline_mode(
+    mut query_store: ResMut<QueryStore>,
+    mut status: ResMut<OnlineStatus>,
+    offline_mode: Res<OfflineMode>,
+    transport: Res<QueryTransport>,
+    limits: Res<RequestLimits>,
+    auth: Option<Res<QueryAuth>>,
+    middleware: Res<QueryMiddlewareStack>,
+    mut commands: Commands,
+) {
+    let store = query_store.bypass_change_detection();
+    let offline = &mut store.offline;
+
+    if let Some(task) = offline.probe.as_mut() {
+        if let Some(reached_server) = block_on(future::poll_once(task)) {
+            offline.probe = None;
+            offline.reached_server = Some(offline.reached_server.unwrap_or_default() || reached_server);
+        }
+    }
+
+    if let Some(reached_server) = offline.reached_server.take() {
+        let next = match reached_server {
+            true => OnlineStatus::Online,
+            false => OnlineStatus::Offline,
+        };
+        if *status != next {
+            *status = next;
+            commands.trigger(OnlineStatusChanged { status: next });
+            if next.is_online() {
+                for (key, (_, query, _)) in store.cache.iter() {
+                    if query.refetch_on_reconnect {
+                        let (key, query) = (key.clone(), query.clone());
+                        commands.queue(move |world: &mut World| refetch_cached(world, key, query));
+                    }
+                }
+            } else {
+                // the request that just failed was the first attempt
+                store.offline.last_attempt = Some(Instant::now());
+            }
+        }
+    }
+
+    let offline = &mut store.offline;
+    let attempt_due = offline
+        .last_attempt
+        .is_none_or(|attempt| attempt.elapsed() >= offline_mode.probe_interval);
+    let replay = offline.replaying.is_none()
+        && !offline.queue.is_empty()
+        && match status.is_online() {
+            true => !offline.replay_failed || attempt_due,
+            false => offline_mode.probe_url.is_none() && attempt_due,
+        };
+
+    if !status.is_online() && attempt_due && offline.probe.is_none() {
+        if let Some(probe_url) = &offline_mode.probe_url {
+            offline.last_attempt = Some(Instant::now());
+            let request = HttpRequest::from_query(&Query {
+                url: probe_url.clone(),
+                ..default()
+            });
+            let transport = transport.0.clone();
+            offline.probe = Some(
+                IoTaskPool::get_or_init(TaskPool::new)
+                    .spawn(async move { transport.send(request).await.is_ok() }),
+            );
+        }
+    }
+
+    if replay {
+        // removed from the queue once the server answered it, see `OfflineState::observe`
+        let query = offline.queue.front().cloned().unwrap();
+        offline.last_attempt = Some(Instant::now());
+        offline.replaying = Some((query.url.clone(), query.query_key.clone().unwrap_or_default()));
+        store.pending_requests.push(query);
+        promote_pending_requests(store, &limits, &transport, auth.as_deref(), &middleware);
+    }
+
+    let offline = &mut store.offline;
+    if offline.dirty {
+        offline.dirty = false;
+        if let Err(err) = offline_mode.save_queue(&offline.queue) {
+            proto!("Failed to write the offline queue {:#?}", err);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use bevy::{prelude::*, tasks::futures_lite::AsyncRead, utils::HashMap};
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+/// Stored in place of a total that is not known
+const UNKNOWN_TOTAL: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransferDirection {
+    /// Request body sent to the server
+    Upload,
+    /// Response body received from the server
+    Download,
+}
+
+/// Triggered while a query sends its body or receives its response
+///
+/// Progress is reported at most once per poll of the loading requests, when the count changed, and
+/// once more when the request completes
+#[derive(Event, Debug, Clone, PartialEq, Eq)]
+pub struct QueryProgress {
+    /// (url, query_key) of the query, as in [`QueryStore::cache`](crate::tasks::QueryStore::cache)
+    pub key: (String, String),
+    pub direction: TransferDirection,
+    pub bytes_done: u64,
+    /// `None` if the size is not known, e.g. a response sent without `Content-Length`
+    ///
+    /// Compressed responses count the bytes as they are received, before they are decoded
+    pub bytes_total: Option<u64>,
+}
+
+/// Byte counter of a transfer, clones share the count
+///
+/// Transports count the request body as they write it when [`HttpRequest::upload_progress`] is set
+///
+/// [`HttpRequest::upload_progress`]: crate::transport::HttpRequest::upload_progress
+#[derive(Clone)]
+pub struct TransferProgress {
+    done: Arc<AtomicU64>,
+    total: Arc<AtomicU64>,
+}
+
+impl Default for TransferProgress {
+    fn default() -> Self {
+        Self {
+            done: Arc::default(),
+            total: Arc::new(AtomicU64::new(UNKNOWN_TOTAL)),
+        }
+    }
+}
+
+impl TransferProgress {
+    pub fn set_total(&self, total: Option<u64>) {
+        self.total
+            .store(total.unwrap_or(UNKNOWN_TOTAL), Ordering::Relaxed);
+    }
+
+    /// Sets the count, e.g. to the size already on disk when a download resumes
+    pub fn set_done(&self, bytes: u64) {
+        self.done.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, bytes: u64) {
+        self.done.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn bytes_done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_total(&self) -> Option<u64> {
+        match self.total.load(Ordering::Relaxed) {
+            UNKNOWN_TOTAL => None,
+            total => Some(total),
+        }
+    }
+}
+
+impl fmt::Debug for TransferProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransferProgress")
+            .field("bytes_done", &self.bytes_done())
+            .field("bytes_total", &self.bytes_total())
+            .finish()
+    }
+}
+
+/// Handles are equal when they share their count
+impl PartialEq for TransferProgress {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.done, &other.done)
+    }
+}
+
+/// Counts the bytes read through it, works with both blocking and async readers
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: TransferProgress,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R, progress: TransferProgress) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: io::Read> io::Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.add(read as u64);
+        Ok(read)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = poll {
+            self.progress.add(read as u64);
+        }
+        poll
+    }
+}
+
+/// Counters of a loading request
+#[derive(Default, Debug, Clone)]
+pub struct Transfer {
+    pub upload: TransferProgress,
+    pub download: TransferProgress,
+    /// Last (bytes_done, bytes_total) reported for the upload and the download
+    reported: [Option<(u64, Option<u64>)>; 2],
+}
+
+impl Transfer {
+    /// Triggers a [`QueryProgress`] for every direction whose count changed since it was last reported
+    pub(crate) fn report(&mut self, key: (String, String), commands: &mut Commands) {
+        let directions = [
+            (TransferDirection::Upload, self.upload.clone()),
+            (TransferDirection::Download, self.download.clone()),
+        ];
+        for (index, (direction, progress)) in directions.into_iter().enumerate() {
+            let current = (progress.bytes_done(), progress.bytes_total());
+            // nothing to report until bytes move or the size is known
+            if current == (0, None) || current == (0, Some(0)) {
+                continue;
+            }
+            if self.reported[index] == Some(current) {
+                continue;
+            }
+            self.reported[index] = Some(current);
+            commands.trigger(QueryProgress {
+                key: key.clone(),
+                direction,
+                bytes_done: current.0,
+                bytes_total: current.1,
+            });
+        }
+    }
+}
+
+/// Progress of the loading requests kept in the [`QueryStore`](crate::tasks::QueryStore)
+#[derive(Default, Debug)]
+pub struct ProgressState {
+    /// Hashmap: (url, query_key, task_sequence) -> transfer of the loading request
+    pub transfers: HashMap<(String, String, Option<String>), Transfer>,
+}
+
+impl ProgressState {
+    /// Starts counting the transfer of a request that is about to be sent
+    pub(crate) fn start(&mut self, key: (String, String, Option<String>)) -> Transfer {
+        let transfer = Transfer::default();
+        self.transfers.insert(key, transfer.clone());
+        transfer
+    }
+
+    /// Reports the progress of a loading request, the transfer is dropped once it is `finished`
+    pub(crate) fn report(
+        &mut self,
+        key: &(String, String, Option<String>),
+        finished: bool,
+        commands: &mut Commands,
+    ) {
+        let Some(transfer) = self.transfers.get_mut(key) else {
+            return;
+        };
+        transfer.report((key.0.clone(), key.1.clone()), commands);
+        if finished {
+            self.transfers.remove(key);
+        }
+    }
+}
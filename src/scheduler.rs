@@ -0,0 +1,284 @@
+use crate::{
+    auth::QueryAuth,
+    middleware::QueryMiddlewareStack,
+    tasks::{spawn_request, QueryStore},
+    transport::{glob_match, QueryTransport},
+    Query,
+};
+use bevy::{prelude::*, utils::HashMap};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Requeues allowed per query when the server answers with `Retry-After`
+const MAX_RETRY_AFTER_REQUEUES: u32 = 3;
+
+/// Caps on the number of requests in flight, queries over the caps wait in
+/// [`QueryStore::pending_requests`] and are sent by priority as slots free up
+#[derive(Resource, Debug, Clone)]
+pub struct RequestLimits {
+    /// Requests in flight across all hosts, `None` is unlimited
+    pub max_in_flight: Option<usize>,
+    /// Requests in flight to a single host that has no limit of its own, `None` is unlimited
+    pub max_in_flight_per_host: Option<usize>,
+    /// Hashmap: host -> requests in flight to that host
+    ///
+    /// Hosts include the port if the url has one, e.g. `api.example.com` or `127.0.0.1:8080`
+    pub hosts: HashMap<String, usize>,
+    /// Token buckets by route pattern, a query has to take a token from every bucket it matches
+    ///
+    /// Patterns without a `/` match the host, other patterns match the host and path,
+    /// e.g. `api.example.com/leaderboard/*`. `*` matches any sequence of characters.
+    pub rate_limits: Vec<(String, RateLimit)>,
+    /// Times a query answered with 429 or 503 and a `Retry-After` header is queued again before the
+    /// error is kept
+    pub max_retry_after_requeues: u32,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_in_flight: None,
+            max_in_flight_per_host: None,
+            hosts: HashMap::default(),
+            rate_limits: vec![],
+            max_retry_after_requeues: MAX_RETRY_AFTER_REQUEUES,
+        }
+    }
+}
+
+impl RequestLimits {
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    pub fn with_max_in_flight_per_host(mut self, max_in_flight_per_host: usize) -> Self {
+        self.max_in_flight_per_host = Some(max_in_flight_per_host);
+        self
+    }
+
+    pub fn with_host_limit(mut self, host: impl Into<String>, max_in_flight: usize) -> Self {
+        self.hosts.insert(host.into(), max_in_flight);
+        self
+    }
+
+    pub fn with_rate_limit(mut self, pattern: impl Into<String>, rate_limit: RateLimit) -> Self {
+        self.rate_limits.push((pattern.into(), rate_limit));
+        self
+    }
+
+    pub fn with_max_retry_after_requeues(mut self, max_retry_after_requeues: u32) -> Self {
+        self.max_retry_after_requeues = max_retry_after_requeues;
+        self
+    }
+
+    fn host_limit(&self, host: &str) -> Option<usize> {
+        self.hosts.get(host).copied().or(self.max_in_flight_per_host)
+    }
+
+    /// Patterns of the rate limits matching `url`
+    fn routes<'a>(&'a self, url: &'a str) -> impl Iterator<Item = &'a str> {
+        self.rate_limits
+            .iter()
+            .map(|(pattern, _)| pattern.as_str())
+            .filter(move |pattern| route_matches(pattern, url))
+    }
+}
+
+/// Token bucket refilled with `per_second` tokens, holding at most `burst` tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Allows bursts of one second worth of requests
+    pub fn per_second(per_second: f64) -> Self {
+        Self {
+            per_second,
+            burst: per_second.ceil().max(1.) as u32,
+        }
+    }
+
+    pub fn per_minute(per_minute: f64) -> Self {
+        Self::per_second(per_minute / 60.)
+    }
+
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    pub refilled_at: Instant,
+}
+
+/// Rate limiting state kept in the [`QueryStore`]
+#[derive(Default, Debug, Clone)]
+pub struct RateLimitState {
+    /// Hashmap: route pattern -> bucket
+    pub buckets: HashMap<String, TokenBucket>,
+    /// Hashmap: route pattern or host -> end of the pause requested by the server
+    pub paused: HashMap<String, Instant>,
+    /// Hashmap: (url, query_key) -> times the query was queued again after a `Retry-After`
+    pub requeues: HashMap<(String, String), u32>,
+}
+
+impl RateLimitState {
+    fn refill(&mut self, limits: &RequestLimits, now: Instant) {
+        for (pattern, rate_limit) in limits.rate_limits.iter() {
+            let bucket = self
+                .buckets
+                .entry(pattern.clone())
+                .or_insert_with(|| TokenBucket {
+                    tokens: rate_limit.burst as f64,
+                    refilled_at: now,
+                });
+            let elapsed = now.saturating_duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens =
+                (bucket.tokens + elapsed * rate_limit.per_second).min(rate_limit.burst as f64);
+            bucket.refilled_at = now;
+        }
+        self.paused.retain(|_, until| *until > now);
+    }
+
+    fn is_allowed(&self, limits: &RequestLimits, url: &str) -> bool {
+        if self.paused.contains_key(host(url)) {
+            return false;
+        }
+        limits.routes(url).all(|pattern| {
+            !self.paused.contains_key(pattern)
+                && self.buckets.get(pattern).is_none_or(|bucket| bucket.tokens >= 1.)
+        })
+    }
+
+    fn take_tokens(&mut self, limits: &RequestLimits, url: &str) {
+        for pattern in limits.routes(url) {
+            if let Some(bucket) = self.buckets.get_mut(pattern) {
+                bucket.tokens -= 1.;
+            }
+        }
+    }
+
+    /// Returns `true` if the query may be queued again after a `Retry-After`
+    pub(crate) fn can_requeue(&self, limits: &RequestLimits, url: &str, query_key: &str) -> bool {
+        self.requeues
+            .get(&(url.to_string(), query_key.to_string()))
+            .copied()
+            .unwrap_or_default()
+            < limits.max_retry_after_requeues
+    }
+}
+
+/// Sends pending queries while the limits allow it
+///
+/// The pending query with the highest priority goes first, queries with the same priority are sent in
+/// the order they were triggered. Queries to a host or route that is at its limit wait without
+/// blocking the other queries. Nothing is sent while the credentials are refreshed.
+pub(crate) fn promote_pending_requests(
+    store: &mut QueryStore,
+    limits: &RequestLimits,
+    transport: &QueryTransport,
+    auth: Option<&QueryAuth>,
+    middleware: &QueryMiddlewareStack,
+) {
+    if store.pending_requests.is_empty() || store.auth.is_refreshing() {
+        return;
+    }
+    store.rate_limits.refill(limits, Instant::now());
+
+    while !store.pending_requests.is_empty() {
+        if limits
+            .max_in_flight
+            .is_some_and(|max| store.loading_requests.len() >= max)
+        {
+            return;
+        }
+
+        let mut in_flight: HashMap<String, usize> = HashMap::default();
+        for (url, _, _) in store.loading_requests.keys() {
+            *in_flight.entry(host(url).to_string()).or_default() += 1;
+        }
+        let next = store
+            .pending_requests
+            .iter()
+            .enumerate()
+            .filter(|(_, query)| {
+                let host = host(&query.url);
+                limits
+                    .host_limit(host)
+                    .is_none_or(|max| in_flight.get(host).copied().unwrap_or_default() < max)
+                    && store.rate_limits.is_allowed(limits, &query.url)
+            })
+            .max_by_key(|(index, query)| (query.priority, std::cmp::Reverse(*index)))
+            .map(|(index, _)| index);
+
+        let Some(index) = next else {
+            return;
+        };
+        let query = store.pending_requests.remove(index);
+        store.rate_limits.take_tokens(limits, &query.url);
+        spawn_request(store, transport, auth, middleware, query);
+    }
+}
+
+/// Pauses the routes of a query the server asked to retry later and queues it again
+///
+/// Every rate limit matching the url is paused, or the whole host if none does
+pub(crate) fn requeue_after(
+    store: &mut QueryStore,
+    limits: &RequestLimits,
+    query: Query,
+    delay: Duration,
+) {
+    let until = Instant::now() + delay;
+    let mut routes: Vec<String> = limits.routes(&query.url).map(str::to_string).collect();
+    if routes.is_empty() {
+        routes.push(host(&query.url).to_string());
+    }
+    for route in routes {
+        let paused = store.rate_limits.paused.entry(route).or_insert(until);
+        *paused = (*paused).max(until);
+    }
+
+    *store
+        .rate_limits
+        .requeues
+        .entry((query.url.clone(), query.query_key.clone().unwrap_or_default()))
+        .or_default() += 1;
+    // it was sent before the queries waiting behind it
+    store.pending_requests.insert(0, query);
+}
+
+/// Parses a `Retry-After` header, either a number of seconds or an HTTP date
+pub(crate) fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Host of a url including its port, the url itself if it has no host
+pub(crate) fn host(url: &str) -> &str {
+    let url = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let end = url.find(['/', '?', '#']).unwrap_or(url.len());
+    let authority = &url[..end];
+    authority.rsplit_once('@').map_or(authority, |(_, host)| host)
+}
+
+/// Matches a rate limit pattern against the host, or the host and path of a url
+fn route_matches(pattern: &str, url: &str) -> bool {
+    let host = host(url);
+    if !pattern.contains('/') {
+        return glob_match(pattern, host);
+    }
+    let path = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let path = path.find('/').map_or("", |index| &path[index..]);
+    glob_match(pattern, &format!("{}{}", host, path))
+}
@@ -0,0 +1,683 @@
+use crate::{
+    auth::QueryAuth,
+    live::{merge_update, MergeStrategy},
+    proto,
+    tasks::{response_status, QueryStore},
+    transport::{HttpRequest, HttpResponse, HttpTransport, QueryTransport, TransportError},
+    Query,
+};
+use async_channel::{Receiver, Sender, TryRecvError};
+use async_io::Timer;
+use bevy::{
+    prelude::*,
+    tasks::{futures_lite::AsyncReadExt, IoTaskPool, Task, TaskPool},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    mem,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Delay before reconnecting until the server sets one with a `retry` field
+const RETRY_DELAY: Duration = Duration::from_secs(3);
+
+/// Delay after a long poll without news, doubled for every following one up to [`RETRY_DELAY`]
+const EMPTY_POLL_DELAY: Duration = Duration::from_millis(100);
+
+/// Time a long poll keeps going after its cache entry was last read
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// How a query with [`Query::stream`] set reads its response
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StreamMode {
+    /// `text/event-stream`, the data of every event is merged into the cache entry
+    ///
+    /// Data that is not JSON is merged as a string. The connection is opened again with the
+    /// `Last-Event-ID` of the last event when it drops, until [`CloseStream`] is triggered or the server
+    /// answers 204 or an error status.
+    EventStream { merge: MergeStrategy },
+    /// Newline delimited JSON, every line is appended to the cached body as soon as it arrives
+    ///
+    /// The body starts as an empty array when the response arrives and is complete once the stream is
+    /// removed from [`QueryStore::streams`]. Lines that are not JSON are skipped, a dropped connection
+    /// caches the failure like a regular query.
+    NdJson,
+    /// The request is sent again as soon as a response arrives, with the cursor of the last response
+    ///
+    /// Every response body is merged into the cache entry. Timeouts and 204 or 304 responses mean there
+    /// was no news, the next request is sent after a delay growing with every empty poll, keep the query
+    /// timeout above the time the server holds requests. The poll stops when the cache entry was not read for `idle_timeout`, when the
+    /// server answers an error status or when [`CloseStream`] is triggered.
+    LongPoll {
+        /// Query param the cursor is sent in
+        cursor_param: String,
+        /// JSON pointer to the next cursor in the response body, the last cursor is kept if it is missing
+        cursor_pointer: String,
+        merge: MergeStrategy,
+        idle_timeout: Duration,
+    },
+}
+
+impl StreamMode {
+    /// Long poll replacing the cached body with every response
+    pub fn long_poll(cursor_param: impl Into<String>, cursor_pointer: impl Into<String>) -> Self {
+        StreamMode::LongPoll {
+            cursor_param: cursor_param.into(),
+            cursor_pointer: cursor_pointer.into(),
+            merge: MergeStrategy::Replace,
+            idle_timeout: IDLE_TIMEOUT,
+        }
+    }
+}
+
+/// Triggered for every event received by an event stream query
+#[derive(Event, Debug, Clone)]
+pub struct ServerSentEvent {
+    /// (url, query_key) of the cache entry
+    pub key: (String, String),
+    /// Type of the event, `message` unless the server set one
+    pub event: String,
+    /// Last event id received on the stream
+    pub id: Option<String>,
+    pub data: String,
+}
+
+/// Triggered for every line of an NDJSON query, after it was appended to the cache entry
+#[derive(Event, Debug, Clone)]
+pub struct NdjsonItem {
+    /// (url, query_key) of the cache entry
+    pub key: (String, String),
+    /// Position of the item in the cached array
+    pub index: usize,
+    pub item: Value,
+}
+
+/// Closes the stream of the query `(url, query_key)`, the cache entry is kept
+#[derive(Event, Debug, Clone)]
+pub struct CloseStream {
+    pub url: String,
+    pub query_key: Option<String>,
+}
+
+/// Stream in flight, kept in the [`QueryStore`]
+#[derive(Debug)]
+pub struct StreamState {
+    pub query: Query,
+    /// Whether a response is being read, false while waiting to reconnect
+    pub connected: bool,
+    /// Id of the last event, sent as `Last-Event-ID` when reconnecting
+    pub last_event_id: Option<String>,
+    /// Times the connection dropped
+    pub reconnects: u32,
+    /// Events or items merged into the cache
+    pub received: usize,
+    /// Cursor sent with the next long poll
+    pub cursor: Option<String>,
+    /// Last time the cache entry was read or the query triggered, see [`QueryStore::mark_read`]
+    pub last_read: Instant,
+    updates: Receiver<StreamUpdate>,
+    // dropping the task closes the connection
+    _task: Task<()>,
+}
+
+#[derive(Debug)]
+enum StreamUpdate {
+    Connected {
+        headers: Vec<(String, String)>,
+    },
+    Event(ParsedEvent),
+    Item(Value),
+    /// Body of a long poll response
+    Response {
+        body: Value,
+        cursor: Option<String>,
+    },
+    Disconnected(TransportError),
+    /// The stream is over, with the entry to cache if it failed
+    Ended(Option<Value>),
+}
+
+/// Starts reading the stream of `query` unless it is already open, `outgoing` is the query as the
+/// middleware modified it
+///
+/// Called by [`crate::tasks::spawn_request`] once the query was admitted like any other, reconnects and
+/// following polls are not counted against the [`crate::scheduler::RequestLimits`]
+pub(crate) fn start_stream(
+    query_store: &mut QueryStore,
+    transport: &QueryTransport,
+    auth: Option<&QueryAuth>,
+    query: Query,
+    outgoing: &Query,
+) {
+    let key = (query.url.clone(), query.query_key.clone().unwrap_or_default());
+    if query_store.streams.contains_key(&key) {
+        return;
+    }
+
+    let mut request = HttpRequest::from_query(outgoing);
+    let auth = auth.cloned();
+    let (sender, updates) = async_channel::unbounded();
+    let transport = transport.0.clone();
+    let pool = IoTaskPool::get_or_init(TaskPool::new);
+    let task = match query.stream.clone() {
+        Some(StreamMode::LongPoll {
+            cursor_param,
+            cursor_pointer,
+            ..
+        }) => pool.spawn(async move {
+            run_long_poll(transport, auth, request, cursor_param, cursor_pointer, sender).await
+        }),
+        Some(StreamMode::NdJson) => {
+            // compressed bodies would only be decoded once the stream ends
+            request.set_header("Accept-Encoding", "identity");
+            request.set_header("Accept", "application/x-ndjson");
+            pool.spawn(async move { run_ndjson(transport, auth, request, sender).await })
+        }
+        _ => {
+            request.set_header("Accept-Encoding", "identity");
+            request.set_header("Accept", "text/event-stream");
+            pool.spawn(async move { run_event_stream(transport, auth, request, sender).await })
+        }
+    };
+
+    query_store.streams.insert(
+        key,
+        StreamState {
+            query,
+            connected: false,
+            last_event_id: None,
+            reconnects: 0,
+            received: 0,
+            cursor: None,
+            last_read: Instant::now(),
+            updates,
+            _task: task,
+        },
+    );
+}
+
+pub fn close_stream(trigger: Trigger<CloseStream>, mut query_store: ResMut<QueryStore>) {
+    let event = trigger.event();
+    query_store
+        .streams
+        .remove(&(event.url.clone(), event.query_key.clone().unwrap_or_default()));
+}
+
+/// Merges the received events and items into the cache and triggers them
+///
+/// A stream answered 401 is opened again once the credentials are refreshed
+pub fn poll_streams(
+    mut query_store: ResMut<QueryStore>,
+    transport: Res<QueryTransport>,
+    auth: Option<Res<QueryAuth>>,
+    mut commands: Commands,
+) {
+    if query_store.streams.is_empty() {
+        return;
+    }
+    let store = query_store.bypass_change_detection();
+    // nobody reads the entries of idle long polls anymore
+    store.streams.retain(|key, stream| match &stream.query.stream {
+        Some(StreamMode::LongPoll { idle_timeout, .. })
+            if stream.last_read.elapsed() > *idle_timeout =>
+        {
+            proto!("Long poll {} is not read anymore, stopping", key.0);
+            false
+        }
+        _ => true,
+    });
+    // applied in the order they were received once the streams are polled
+    let mut received = vec![];
+
+    for (key, stream) in store.streams.iter_mut() {
+        loop {
+            let update = match stream.updates.try_recv() {
+                Ok(update) => update,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) => StreamUpdate::Ended(None),
+            };
+            match &update {
+                StreamUpdate::Connected { .. } => stream.connected = true,
+                StreamUpdate::Event(event) => stream.last_event_id = event.id.clone(),
+                StreamUpdate::Response {
+                    cursor: Some(cursor), ..
+                } => stream.cursor = Some(cursor.clone()),
+                StreamUpdate::Disconnected(err) => {
+                    proto!("Stream {} dropped, reconnecting: {}", key.0, err);
+                    stream.connected = false;
+                    stream.reconnects += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            let ended = matches!(update, StreamUpdate::Ended(_));
+            received.push((key.clone(), stream.query.clone(), update));
+            if ended {
+                break;
+            }
+        }
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    for (key, query, update) in received {
+        if !matches!(update, StreamUpdate::Ended(_)) {
+            query_store.auth.replayed.remove(&key);
+        }
+        match (update, query.stream.clone()) {
+            (StreamUpdate::Connected { headers }, Some(StreamMode::NdJson)) => {
+                let headers: serde_json::Map<String, Value> = headers
+                    .into_iter()
+                    .map(|(name, value)| (name, json!(value)))
+                    .collect();
+                query_store.cache.insert(
+                    key.clone(),
+                    (json!({"status": 200, "body": [], "headers": headers}), query, now),
+                );
+                query_store.mark_updated(key);
+            }
+            (StreamUpdate::Event(event), Some(StreamMode::EventStream { merge })) => {
+                let data =
+                    serde_json::from_str(&event.data).unwrap_or(Value::String(event.data.clone()));
+                merge_update(&mut query_store, key.clone(), data, merge, Some(&query));
+                count_received(&mut query_store, &key);
+                commands.trigger(ServerSentEvent {
+                    key,
+                    event: event.event,
+                    id: event.id,
+                    data: event.data,
+                });
+            }
+            (StreamUpdate::Response { body, .. }, Some(StreamMode::LongPoll { merge, .. })) => {
+                merge_update(&mut query_store, key.clone(), body, merge, Some(&query));
+                count_received(&mut query_store, &key);
+            }
+            (StreamUpdate::Item(item), _) => {
+                let append = MergeStrategy::Append { max_items: None };
+                merge_update(&mut query_store, key.clone(), item.clone(), append, Some(&query));
+                let index = count_received(&mut query_store, &key);
+                commands.trigger(NdjsonItem { key, index, item });
+            }
+            (StreamUpdate::Ended(failed), _) => {
+                query_store.streams.remove(&key);
+                let query = match (&auth, failed.as_ref().map(response_status)) {
+                    (Some(auth), Some(401)) => {
+                        match query_store.auth.wait_for_refresh(auth, &transport.0, query) {
+                            Some(query) => query,
+                            None => continue,
+                        }
+                    }
+                    _ => query,
+                };
+                if let Some(value) = failed {
+                    proto!("Stream {} failed {}", key.0, value);
+                    query_store.cache.insert(key.clone(), (value, query, now));
+                    query_store.mark_updated(key);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Counts an event or item merged for the stream of `key`, returns its index
+fn count_received(query_store: &mut QueryStore, key: &(String, String)) -> usize {
+    let Some(stream) = query_store.streams.get_mut(key) else {
+        return 0;
+    };
+    stream.received += 1;
+    stream.received - 1
+}
+
+/// Cache entry of a response with an error status
+async fn error_entry(response: HttpResponse) -> Value {
+    let status = response.status;
+    let msg = match response.fetch().await {
+        Ok(response) => String::from_utf8_lossy(&response.body).to_string(),
+        Err(err) => err.to_string(),
+    };
+    json!({"status": status, "msg": msg})
+}
+
+/// Copy of `request` with the current credentials, they can change between reconnects
+fn authorized(request: &HttpRequest, auth: &Option<QueryAuth>) -> HttpRequest {
+    let mut request = request.clone();
+    if let Some(auth) = auth {
+        auth.0.authorize(&mut request);
+    }
+    request
+}
+
+/// Reads the event stream, reconnecting whenever the connection drops, until the receiver is dropped
+async fn run_event_stream(
+    transport: Arc<dyn HttpTransport>,
+    auth: Option<QueryAuth>,
+    request: HttpRequest,
+    updates: Sender<StreamUpdate>,
+) {
+    let mut parser = EventStreamParser::default();
+    loop {
+        let mut request = authorized(&request, &auth);
+        if let Some(id) = &parser.last_event_id {
+            request.set_header("Last-Event-ID", id);
+        }
+
+        let error = match transport.send(request).await {
+            Ok(response) if response.status == 204 => {
+                let _ = updates.send(StreamUpdate::Ended(None)).await;
+                return;
+            }
+            Ok(response) if response.status >= 300 => {
+                let entry = error_entry(response).await;
+                let _ = updates.send(StreamUpdate::Ended(Some(entry))).await;
+                return;
+            }
+            Ok(mut response) => {
+                let headers = response.headers.clone();
+                if updates.send(StreamUpdate::Connected { headers }).await.is_err() {
+                    return;
+                }
+                parser.reset();
+                let mut chunk = vec![0; CHUNK_SIZE];
+                loop {
+                    let read = match response.body.read(&mut chunk).await {
+                        Ok(0) => {
+                            break TransportError::Connection("Server closed the stream".to_string())
+                        }
+                        Ok(read) => read,
+                        Err(err) => break err.into(),
+                    };
+                    for event in parser.feed(&chunk[..read]) {
+                        if updates.send(StreamUpdate::Event(event)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            Err(err) => err,
+        };
+
+        if updates.send(StreamUpdate::Disconnected(error)).await.is_err() {
+            return;
+        }
+        Timer::after(parser.retry.unwrap_or(RETRY_DELAY)).await;
+    }
+}
+
+/// Sends the request again after every response, until the receiver is dropped
+async fn run_long_poll(
+    transport: Arc<dyn HttpTransport>,
+    auth: Option<QueryAuth>,
+    request: HttpRequest,
+    cursor_param: String,
+    cursor_pointer: String,
+    updates: Sender<StreamUpdate>,
+) {
+    let mut cursor: Option<String> = None;
+    let mut empty_polls = 0;
+    loop {
+        let mut request = authorized(&request, &auth);
+        if let Some(cursor) = &cursor {
+            request.params.retain(|(name, _)| name != &cursor_param);
+            request.params.push((cursor_param.clone(), cursor.clone()));
+        }
+
+        let update = match transport.send(request).await {
+            // no news before the server or the client gave up
+            Err(TransportError::Timeout) => None,
+            Ok(response) if response.status == 204 || response.status == 304 => None,
+            Ok(response) if response.status >= 300 => {
+                let entry = error_entry(response).await;
+                let _ = updates.send(StreamUpdate::Ended(Some(entry))).await;
+                return;
+            }
+            Ok(response) => match response.fetch().await {
+                Ok(response) => match serde_json::from_slice::<Value>(&response.body) {
+                    Ok(body) => {
+                        let next = body.pointer(&cursor_pointer).and_then(|next| match next {
+                            Value::String(next) => Some(next.clone()),
+                            Value::Null => None,
+                            next => Some(next.to_string()),
+                        });
+                        cursor = next.or(cursor);
+                        Some(StreamUpdate::Response {
+                            body,
+                            cursor: cursor.clone(),
+                        })
+                    }
+                    Err(err) => {
+                        proto!("Failed to deserialize long poll response {:#?}", err);
+                        let _ = updates
+                            .send(StreamUpdate::Ended(Some(json!({"status": 500}))))
+                            .await;
+                        return;
+                    }
+                },
+                Err(TransportError::Timeout) => None,
+                Err(err) => Some(StreamUpdate::Disconnected(err)),
+            },
+            Err(err) => Some(StreamUpdate::Disconnected(err)),
+        };
+        let Some(update) = update else {
+            // a server or a timeout answering right away would otherwise be polled in a loop
+            empty_polls += 1;
+            Timer::after(empty_poll_delay(empty_polls)).await;
+            continue;
+        };
+        empty_polls = 0;
+
+        let disconnected = matches!(update, StreamUpdate::Disconnected(_));
+        if updates.send(update).await.is_err() {
+            return;
+        }
+        if disconnected {
+            Timer::after(RETRY_DELAY).await;
+        }
+    }
+}
+
+/// Delay before sending the next poll after `empty_polls` polls in a row without news
+fn empty_poll_delay(empty_polls: u32) -> Duration {
+    EMPTY_POLL_DELAY
+        .saturating_mul(1 << empty_polls.saturating_sub(1).min(16))
+        .min(RETRY_DELAY)
+}
+
+/// Reads the NDJSON body once, decoding every line as soon as it is complete
+async fn run_ndjson(
+    transport: Arc<dyn HttpTransport>,
+    auth: Option<QueryAuth>,
+    request: HttpRequest,
+    updates: Sender<StreamUpdate>,
+) {
+    let mut response = match transport.send(authorized(&request, &auth)).await {
+        Ok(response) if response.status >= 300 => {
+            let entry = error_entry(response).await;
+            let _ = updates.send(StreamUpdate::Ended(Some(entry))).await;
+            return;
+        }
+        Ok(response) => response,
+        Err(err) => {
+            proto!("NDJSON request failed {:#?}", err);
+            let _ = updates
+                .send(StreamUpdate::Ended(Some(json!({"status": 500}))))
+                .await;
+            return;
+        }
+    };
+    let headers = response.headers.clone();
+    if updates.send(StreamUpdate::Connected { headers }).await.is_err() {
+        return;
+    }
+
+    let mut decoder = NdjsonDecoder::default();
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let read = match response.body.read(&mut chunk).await {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) => {
+                proto!("NDJSON stream dropped {:#?}", err);
+                let _ = updates
+                    .send(StreamUpdate::Ended(Some(json!({"status": 500}))))
+                    .await;
+                return;
+            }
+        };
+        for item in decoder.feed(&chunk[..read]) {
+            if updates.send(StreamUpdate::Item(item)).await.is_err() {
+                return;
+            }
+        }
+    }
+    if let Some(item) = decoder.finish() {
+        let _ = updates.send(StreamUpdate::Item(item)).await;
+    }
+    let _ = updates.send(StreamUpdate::Ended(None)).await;
+}
+
+/// Incremental NDJSON decoder, chunks can split lines anywhere
+#[derive(Default, Debug)]
+pub(crate) struct NdjsonDecoder {
+    line: Vec<u8>,
+}
+
+impl NdjsonDecoder {
+    /// Returns the items of the lines completed by `bytes`
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Value> {
+        let mut items = vec![];
+        for &byte in bytes {
+            match byte {
+                b'\n' => {
+                    let line = mem::take(&mut self.line);
+                    items.extend(decode_line(&line));
+                }
+                _ => self.line.push(byte),
+            }
+        }
+        items
+    }
+
+    /// Decodes the last line when the body does not end with a line break
+    pub fn finish(&mut self) -> Option<Value> {
+        decode_line(&mem::take(&mut self.line))
+    }
+}
+
+fn decode_line(line: &[u8]) -> Option<Value> {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return None;
+    }
+    match serde_json::from_slice(line) {
+        Ok(item) => Some(item),
+        Err(err) => {
+            proto!("Skipping NDJSON line {:#?}", err);
+            None
+        }
+    }
+}
+
+/// Event dispatched by the [`EventStreamParser`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParsedEvent {
+    pub event: String,
+    pub id: Option<String>,
+    pub data: String,
+}
+
+/// Incremental `text/event-stream` parser, chunks can split lines anywhere
+#[derive(Default, Debug)]
+pub(crate) struct EventStreamParser {
+    line: Vec<u8>,
+    /// The last byte was a `\r`, a following `\n` belongs to the same line break
+    after_cr: bool,
+    event: String,
+    data: String,
+    /// Kept across connections
+    pub last_event_id: Option<String>,
+    /// Reconnection delay set by the server, kept across connections
+    pub retry: Option<Duration>,
+}
+
+impl EventStreamParser {
+    /// Returns the events completed by `bytes`
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<ParsedEvent> {
+        let mut events = vec![];
+        for &byte in bytes {
+            match byte {
+                b'\n' if self.after_cr => self.after_cr = false,
+                b'\r' | b'\n' => {
+                    self.after_cr = byte == b'\r';
+                    let line = mem::take(&mut self.line);
+                    events.extend(self.process_line(&String::from_utf8_lossy(&line)));
+                }
+                _ => {
+                    self.after_cr = false;
+                    self.line.push(byte);
+                }
+            }
+        }
+        events
+    }
+
+    /// Drops the partial event of a connection that ended
+    pub fn reset(&mut self) {
+        self.line.clear();
+        self.after_cr = false;
+        self.event.clear();
+        self.data.clear();
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<ParsedEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        // comments keep the connection alive
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = value.to_string(),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => {
+                self.last_event_id = Some(value.to_string()).filter(|id| !id.is_empty())
+            }
+            "retry" if !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit()) => {
+                self.retry = value.parse().ok().map(Duration::from_millis)
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<ParsedEvent> {
+        let event = mem::take(&mut self.event);
+        if self.data.is_empty() {
+            return None;
+        }
+        let mut data = mem::take(&mut self.data);
+        data.pop();
+        Some(ParsedEvent {
+            event: match event.is_empty() {
+                true => "message".to_string(),
+                false => event,
+            },
+            id: self.last_event_id.clone(),
+            data,
+        })
+    }
+}
@@ -1,18 +1,38 @@
 use crate::{
-    debug_end, extractor::QueryConsumable, logging::PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS, proto,
+    auth::{AuthState, QueryAuth},
+    batch::BatchState,
+    debug_end,
+    download::DownloadState,
+    extractor::QueryConsumable,
+    graph::GraphState,
+    infinite::InfiniteQueryState,
+    logging::PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS,
+    middleware::QueryMiddlewareStack,
+    offline::{OfflineMode, OfflineState, OnlineStatus},
+    progress::{ProgressReader, ProgressState, TransferProgress},
+    proto,
+    scheduler::{
+        parse_retry_after, promote_pending_requests, requeue_after, RateLimitState, RequestLimits,
+    },
+    stream::{start_stream, StreamMode, StreamState},
+    transport::{FetchedResponse, HttpRequest, HttpTransport, QueryTransport, TransportError},
+    websocket::SubscriptionState,
 };
+use async_io::Timer;
 use bevy::{
     prelude::*,
-    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task, TaskPool},
+    tasks::{block_on, futures_lite::future, IoTaskPool, Task, TaskPool},
     utils::HashMap,
 };
 use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
     collections::VecDeque,
-    time::{Duration, SystemTime},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
-use ureq::Response;
 
 #[allow(clippy::type_complexity)]
 #[derive(Resource, Default, Debug)]
@@ -20,14 +40,98 @@ use ureq::Response;
 pub struct QueryStore {
     /// Hashmap: (url, query_key, task_sequence) -> (response, query, called at)
     pub loading_requests:
-        HashMap<(String, String, Option<String>), Task<(Result<Response, ureq::Error>, Query, u128)>>,
+        HashMap<(String, String, Option<String>), Task<(Result<FetchedResponse, TransportError>, Query, u128)>>,
+    /// Queries waiting for the [`RequestLimits`] to allow them, in the order they were triggered
+    pub pending_requests: Vec<Query>,
+    /// Token buckets and `Retry-After` pauses of the [`RequestLimits`] rate limits
+    pub rate_limits: RateLimitState,
+    /// Mutations held by the [`OfflineMode`]
+    pub offline: OfflineState,
+    /// Credentials refresh of the [`QueryAuth`] and the queries waiting for it
+    pub auth: AuthState,
+    /// Bytes sent and received by the loading requests
+    pub progress: ProgressState,
+    /// Hashmap: destination -> download in flight, downloads are not cached
+    pub downloads: HashMap<PathBuf, DownloadState>,
+    /// Hashmap: (url, query_key) -> subscription writing into the cache entry
+    pub subscriptions: HashMap<(String, String), SubscriptionState>,
+    /// Hashmap: (url, query_key) -> stream of a query with [`Query::stream`] set
+    pub streams: HashMap<(String, String), StreamState>,
     /// Hashmap: (url, query_key) -> json (value, called at)\
     pub cache: HashMap<(String, String), (serde_json::Value, Query, u128)>,
-    pub sequences: HashMap<String, VecDeque<Query>>,
+    /// Hashmap: (url, query_key) -> body of a cached [`Query::binary`] response, kept out of its JSON entry
+    pub binary_bodies: HashMap<(String, String), Vec<u8>>,
+    /// Hashmap: sequence key -> progress of the running sequence
+    pub sequences: HashMap<String, SequenceState>,
+    /// Hashmap: graph key -> progress of the running graph
+    pub graphs: HashMap<String, GraphState>,
+    /// Hashmap: batch key -> results collected for the running batch
+    pub batches: HashMap<String, BatchState>,
+    /// Hashmap: infinite query key -> pagination state, the pages are in the cache
+    pub infinite_queries: HashMap<String, InfiniteQueryState>,
     pub stale_queries: Vec<Query>,
+    /// Hashmap: (url, query_key) -> version of the cached response, bumped every time the entry is written
+    /// and removed with it
+    pub versions: HashMap<(String, String), u64>,
+    /// Last version handed out, versions keep increasing even when an entry is removed and fetched again
+    pub version_counter: u64,
 }
 
-#[derive(Default, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+impl QueryStore {
+    /// Bumps the version of a cache entry after it was written
+    pub fn mark_updated(&mut self, key: (String, String)) {
+        self.version_counter += 1;
+        self.versions.insert(key, self.version_counter);
+    }
+
+    /// Removes a cache entry along with its version and binary body
+    pub fn remove_entry(&mut self, key: &(String, String)) -> Option<(serde_json::Value, Query, u128)> {
+        self.versions.remove(key);
+        self.binary_bodies.remove(key);
+        self.cache.remove(key)
+    }
+
+    /// Drops the request of a query wherever it waits, so it is not sent again and never cached
+    pub(crate) fn cancel_request(&mut self, query: &Query) {
+        let key = (query.url.clone(), query.query_key.clone().unwrap_or_default());
+        let is_query = |pending: &Query| {
+            pending.url == key.0
+                && pending.query_key.as_deref().unwrap_or_default() == key.1
+                && pending.sequence_key.is_none()
+        };
+
+        self.loading_requests.retain(|(url, query_key, sequence), _| {
+            !(url == &key.0 && query_key == &key.1 && sequence.is_none())
+        });
+        self.progress
+            .transfers
+            .remove(&(key.0.clone(), key.1.clone(), None));
+        // queries queued again after a `Retry-After` are pending as well
+        self.pending_requests.retain(|pending| !is_query(pending));
+        self.rate_limits.requeues.remove(&key);
+        // answered 401, they would be sent again once the credentials are refreshed
+        self.auth.waiting.retain(|waiting| !is_query(waiting));
+        self.auth.replayed.remove(&key);
+    }
+
+    /// Version of the cached response for `(url, query_key)`, `None` if nothing is cached
+    pub fn version(&self, url: &str, query_key: &str) -> Option<u64> {
+        let key = (url.to_string(), query_key.to_string());
+        if !self.cache.contains_key(&key) {
+            return None;
+        }
+        self.versions.get(&key).copied()
+    }
+
+    /// Records that the entry `(url, query_key)` is still used, long polls stop once it is not read anymore
+    pub fn mark_read(&mut self, key: &(String, String)) {
+        if let Some(stream) = self.streams.get_mut(key) {
+            stream.last_read = Instant::now();
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Method {
     #[default]
     Get,
@@ -35,14 +139,25 @@ pub enum Method {
     Delete,
 }
 
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Delete => "DELETE",
+        }
+    }
+}
+
 #[derive(Event)]
 pub struct ErrorTriggerEvent {
     pub url: String,
     pub error: u16,
 }
 
-#[derive(Event, Default, Debug, Eq, PartialEq, Hash, Clone, Builder)]
+#[derive(Event, Default, Debug, Eq, PartialEq, Hash, Clone, Builder, Serialize, Deserialize)]
 #[builder(setter(strip_option, into), default)]
+#[serde(default)]
 pub struct Query {
     pub method: Method,
     pub url: String,
@@ -53,7 +168,30 @@ pub struct Query {
     /// Querys with the same query_key will be cached, if no query key is provided, the url will be used as the key instead
     pub query_key: Option<String>,
     pub skip_cache_check: Option<bool>,
-    sequence_key: Option<String>,
+    /// Queries waiting for a free slot are sent highest priority first, see [`RequestLimits`]
+    pub priority: i32,
+    /// Refetch the cached response when the [`OnlineStatus`] goes back online
+    pub refetch_on_reconnect: bool,
+    /// Sends the JSON body gzipped with `Content-Encoding: gzip`
+    pub gzip_body: bool,
+    /// Keeps the response body as bytes in [`QueryStore::binary_bodies`] instead of parsing it as JSON,
+    /// the body of the cache entry is `null`
+    pub binary: bool,
+    /// Keeps the connection open and merges the streamed updates into the cache, see [`StreamMode`]
+    pub stream: Option<StreamMode>,
+    pub(crate) sequence_key: Option<String>,
+}
+
+/// What a [`QuerySequence`] does when one of its steps fails
+#[derive(Default, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SequenceFailurePolicy {
+    /// Stop the sequence and trigger [`SequenceFailed`]
+    #[default]
+    Abort,
+    /// Move on to the next step, the failed step is reported in [`SequenceCompleted`]
+    SkipStep,
+    /// Send the failed step again up to `max_retries` times, then abort
+    RetryStep { max_retries: u32 },
 }
 
 /// Sequence of tasks to execute in order
@@ -61,6 +199,62 @@ pub struct Query {
 pub struct QuerySequence {
     pub key: String,
     pub tasks: VecDeque<Query>,
+    pub on_failure: SequenceFailurePolicy,
+    /// Hashmap: step index -> query undoing that step
+    ///
+    /// When the sequence aborts, the compensations of the completed steps are sent one after the other
+    /// in reverse order
+    pub compensations: HashMap<usize, Query>,
+}
+
+/// Progress of a running [`QuerySequence`]
+#[derive(Default, Debug, Clone)]
+pub struct SequenceState {
+    /// Steps left to run, the front one is the step in flight
+    pub tasks: VecDeque<Query>,
+    pub on_failure: SequenceFailurePolicy,
+    /// Index of the step in flight
+    pub step: usize,
+    pub total: usize,
+    /// Retries already spent on the step in flight
+    pub retries: u32,
+    /// Steps that failed and were skipped
+    pub skipped_steps: Vec<usize>,
+    pub compensations: HashMap<usize, Query>,
+    /// Compensations left to run once the sequence aborted, the front one is in flight
+    pub compensating: Option<VecDeque<(usize, Query)>>,
+    /// Failure reported once the compensations finished
+    pub failure: Option<SequenceFailed>,
+}
+
+/// Triggered every time a sequence moves past a step
+#[derive(Event, Debug, Clone)]
+pub struct SequenceStepCompleted {
+    pub key: String,
+    pub step: usize,
+    pub total: usize,
+    pub url: String,
+    pub status: u16,
+}
+
+/// Triggered once every step of a sequence has been run
+#[derive(Event, Debug, Clone)]
+pub struct SequenceCompleted {
+    pub key: String,
+    pub skipped_steps: Vec<usize>,
+}
+
+/// Triggered when a sequence stops because of a failed step, after its compensations finished
+#[derive(Event, Default, Debug, Clone)]
+pub struct SequenceFailed {
+    pub key: String,
+    pub step: usize,
+    pub url: String,
+    pub status: u16,
+    /// Steps whose compensation succeeded, in the order they were undone
+    pub compensated: Vec<usize>,
+    /// Steps whose compensation failed
+    pub compensation_failed: Vec<usize>,
 }
 
 /// Tas sequence consumeable
@@ -71,73 +265,125 @@ pub struct QuerySequenceConsumeable {
 }
 
 /// API request handler
-/// Spawn a new task if the url is not already in api_tasks.loading_requests
-pub fn spawn_api_task(trigger: Trigger<Query>, mut query_store: ResMut<QueryStore>) {
+/// Queue a new task if the url is not already in api_tasks.loading_requests or pending_requests
+pub fn spawn_api_task(
+    trigger: Trigger<Query>,
+    mut query_store: ResMut<QueryStore>,
+    transport: Res<QueryTransport>,
+    limits: Res<RequestLimits>,
+    offline_mode: Option<Res<OfflineMode>>,
+    status: Res<OnlineStatus>,
+    auth: Option<Res<QueryAuth>>,
+    middleware: Res<QueryMiddlewareStack>,
+    mut commands: Commands,
+) {
     let url = trigger.event().url.clone();
     let query_key = trigger.event().query_key.clone().unwrap_or_default();
 
-    if query_store.cache.contains_key(&(url.clone(), query_key.clone())) {
+    // event streams and long polls merge into their cache entry, they are opened again even if it exists
+    if matches!(
+        trigger.event().stream,
+        Some(StreamMode::EventStream { .. } | StreamMode::LongPoll { .. })
+    ) {
+        // triggering the query again shows it is still used
+        query_store.mark_read(&(url.clone(), query_key.clone()));
+    } else if let Some((value, _, _)) = query_store.cache.get(&(url.clone(), query_key.clone())) {
+        // a cached sequence step will not be sent again, move the sequence along
+        if let Some(sequence_key) = &trigger.event().sequence_key {
+            let status = response_status(value);
+            advance_sequence(&mut query_store, sequence_key, status, &mut commands);
+        }
+        return;
+    }
+    if query_store.streams.contains_key(&(url.clone(), query_key.clone())) {
+        return;
+    }
+    let query = trigger.event().clone();
+    if offline_mode.is_some() && query_store.offline.should_hold(*status, &query) {
+        query_store.offline.hold(query);
         return;
     }
-    let method = trigger.event().method;
-    let params = trigger.event().params.clone();
-    let body = trigger.event().body.clone();
-    let timeout = trigger.event().timeout;
-    let sequence_key = trigger.event().sequence_key.clone();
-
-    let thread_pool = AsyncComputeTaskPool::get_or_init(TaskPool::new);
-    let headers = trigger.event().headers.clone();
+    let sequence_key = query.sequence_key.clone();
     let key_exists = query_store.loading_requests.contains_key(&(
         url.clone(),
         query_key.clone(),
         sequence_key.clone(),
-    ));
-    let new_url = url.clone();
-    let query = trigger.event().clone();
-    let skip_cache_check = trigger.event().skip_cache_check.unwrap_or_default();
-    let call_get = method == Method::Get;
-
-    if call_get && !skip_cache_check && !key_exists {
-        println!("calling get");
-        let task = thread_pool.spawn(async move {
-            let url = new_url.clone();
-            let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-            (
-                get(url.clone(), params.clone(), headers, timeout).await,
-                query.clone(),
-                now,
-            )
-        });
-
-        query_store
-            .loading_requests
-            .insert((url.clone(), query_key, sequence_key), task);
-    } else if !call_get {
-        let task = thread_pool.spawn(async move {
-            let url = new_url.clone();
-            let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-            (
-                match method {
-                    Method::Post => {
-                        post(url.clone(), params.clone(), body.clone(), headers, timeout).await
-                    }
-                    _ => delete(url.clone(), params.clone(), headers, timeout).await,
-                },
-                query.clone(),
-                now,
-            )
+    )) || query_store
+        .pending_requests
+        .iter()
+        .chain(query_store.auth.waiting.iter())
+        .any(|pending| {
+            pending.url == url
+                && pending.query_key.clone().unwrap_or_default() == query_key
+                && pending.sequence_key == sequence_key
         });
+    let skip_cache_check = query.skip_cache_check.unwrap_or_default();
+    let call_get = query.method == Method::Get;
+
+    if !call_get || (!skip_cache_check && !key_exists) {
+        query_store.pending_requests.push(query);
+    } else if key_exists {
+        // a fail-fast batch sending the same query can not cancel the request anymore
+        for batch in query_store.batches.values_mut() {
+            batch.share(&url, &query_key);
+        }
+    }
+    promote_pending_requests(
+        &mut query_store,
+        &limits,
+        &transport,
+        auth.as_deref(),
+        &middleware,
+    );
+}
 
-        query_store
-            .loading_requests
-            .insert((url.clone(), query_key, sequence_key), task);
+/// Starts the task sending `query`, the limits are checked by [`promote_pending_requests`]
+pub(crate) fn spawn_request(
+    query_store: &mut QueryStore,
+    transport: &QueryTransport,
+    auth: Option<&QueryAuth>,
+    middleware: &QueryMiddlewareStack,
+    query: Query,
+) {
+    let mut outgoing = query.clone();
+    // layers see the credentials, e.g. to sign the request
+    if let Some(auth) = auth {
+        auth.authorize_query(&mut outgoing);
+    }
+    let (layers, answered) = middleware.on_request(&mut outgoing);
+    // streams are read by their own task, unless a layer answered them
+    if query.stream.is_some() && answered.is_none() {
+        start_stream(query_store, transport, auth, query, &outgoing);
+        return;
     }
+    let thread_pool = IoTaskPool::get_or_init(TaskPool::new);
+    let key = (
+        query.url.clone(),
+        query.query_key.clone().unwrap_or_default(),
+        query.sequence_key.clone(),
+    );
+    let mut request = HttpRequest::from_query(&outgoing);
+    let transfer = query_store.progress.start(key.clone());
+    transfer
+        .upload
+        .set_total(request.body.as_ref().map(|body| body.len() as u64));
+    request.upload_progress = Some(transfer.upload);
+    let transport = transport.0.clone();
+    let middleware = middleware.clone();
+    let task = thread_pool.spawn(async move {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let mut response = match answered {
+            Some(response) => Ok(response),
+            None => fetch(transport, request, transfer.download).await,
+        };
+        middleware.on_response(layers, &outgoing, &mut response);
+        (response, query, now)
+    });
+
+    query_store.loading_requests.insert(key, task);
 }
 
 /// Polls the status of all API tasks
@@ -147,12 +393,26 @@ pub fn spawn_api_task(trigger: Trigger<Query>, mut query_store: ResMut<QueryStor
 /// Should remove matching older requests
 ///
 /// @TODO: add stale time for query_key to remove from store
-pub fn api_task_poll(mut query_store: ResMut<QueryStore>, mut commands: Commands) {
+pub fn api_task_poll(
+    mut query_store: ResMut<QueryStore>,
+    transport: Res<QueryTransport>,
+    limits: Res<RequestLimits>,
+    offline_mode: Option<Res<OfflineMode>>,
+    auth: Option<Res<QueryAuth>>,
+    middleware: Res<QueryMiddlewareStack>,
+    mut commands: Commands,
+) {
     let start = SystemTime::now();
     let mut completed_requests = vec![];
-    let current_sequence_tasks = query_store.bypass_change_detection().sequences.clone();
-    query_store
-        .bypass_change_detection()
+    let mut binary_bodies = vec![];
+    let mut sequence_steps = vec![];
+    let mut requeued = vec![];
+    let store = query_store.bypass_change_detection();
+    let rate_limits = &store.rate_limits;
+    let offline = &mut store.offline;
+    let auth_state = &mut store.auth;
+    let progress = &mut store.progress;
+    store
         .loading_requests
         .retain(|(url, query_key, sequence), task| {
             // keep the entry in our HashMap only if the task is not done yet
@@ -161,172 +421,401 @@ pub fn api_task_poll(mut query_store: ResMut<QueryStore>, mut commands: Commands
 
             // check task
             let poll_status = block_on(future::poll_once(task));
+            progress.report(
+                &(url.clone(), query_key.clone(), sequence.clone()),
+                poll_status.is_some(),
+                &mut commands,
+            );
 
             // if this task is done, handle return data
-            if let Some(st) = poll_status {
+            if let Some(mut st) = poll_status {
                 retain = false;
 
+                // the server asked to come back later, queue the query again instead of failing it
+                if let Ok(res) = &st.0 {
+                    if matches!(res.status, 429 | 503) && rate_limits.can_requeue(&limits, url, query_key) {
+                        if let Some(delay) = res.header("retry-after").and_then(parse_retry_after) {
+                            proto!("{} responded with {}, retrying in {:?}", url, res.status, delay);
+                            requeued.push((st.1, delay));
+                            return false;
+                        }
+                    }
+                }
+
+                // the credentials expired, send the query again once they are refreshed
+                if let (Some(auth), Ok(res)) = (&auth, &st.0) {
+                    if res.status == 401 {
+                        match auth_state.wait_for_refresh(auth, &transport.0, st.1) {
+                            Some(query) => st.1 = query,
+                            None => return false,
+                        }
+                    }
+                }
+
+                if let Some(offline_mode) = &offline_mode {
+                    let reached_server = !matches!(st.0, Err(TransportError::Connection(_)));
+                    let status = st.0.as_ref().map_or(500, |res| res.status);
+                    match offline.observe(offline_mode, st.1, reached_server, status, &mut commands) {
+                        Some(query) => st.1 = query,
+                        None => return false,
+                    }
+                }
+
                 match st.0 {
-                    Ok(res) => match res.into_json::<serde_json::Value>() {
-                        Ok(json) => {
-                            if let Some(sequence) = sequence {
-                                if let Some(sequence_tasks) =
-                                    current_sequence_tasks.clone().get_mut(sequence)
-                                {
-                                    if sequence_tasks.pop_front().is_some() {
-                                        commands.trigger(QuerySequence {
-                                            key: sequence.to_string(),
-                                            tasks: sequence_tasks.clone(),
-                                        });
-                                    };
-                                };
+                    Ok(res) if res.status < 400 => {
+                        let headers: serde_json::Map<String, serde_json::Value> = res
+                            .headers
+                            .iter()
+                            .map(|(name, value)| (name.clone(), json!(value)))
+                            .collect();
+
+                        let binary = st.1.binary;
+                        let body = match binary {
+                            true => Ok(serde_json::Value::Null),
+                            false => serde_json::from_slice::<serde_json::Value>(&res.body),
+                        };
+                        match body {
+                            Ok(json) => {
+                                completed_requests.push((
+                                    (url.to_string(), query_key.clone()),
+                                    (
+                                        json!({
+                                            "status": 200,
+                                            "body": json,
+                                            "headers": headers,
+                                            "compressed_size": res.compressed_size,
+                                            "decompressed_size": res.body.len(),
+                                        }),
+                                        st.1,
+                                        st.2,
+                                    ),
+                                ));
+                                if binary {
+                                    binary_bodies.push(((url.to_string(), query_key.clone()), res.body));
+                                }
+                            }
+                            Err(err) => {
+                                proto!("Failed to deserialize response {:#?}", err);
+                                completed_requests.push((
+                                    (url.to_string(), query_key.clone()),
+                                    (json!({"status":500}), st.1, st.2),
+                                ));
                             }
-                            completed_requests.push((
-                                (url.to_string(), query_key.clone()),
-                                (json!({"status": 200, "body": json}), st.1, st.2),
-                            ));
-                        }
-                        Err(err) => {
-                            proto!("Failed to deserialize response {:#?}", err);
-                            completed_requests.push((
-                                (url.to_string(), query_key.clone()),
-                                (json!({"status":500}), st.1, st.2),
-                            ));
                         }
-                    },
+                    }
+                    Ok(res) => {
+                        proto!("{} responded with {}", url, res.status);
+                        commands.trigger(ErrorTriggerEvent {
+                            error: res.status,
+                            url: url.to_string(),
+                        });
+                        completed_requests.push((
+                            (url.to_string(), query_key.clone()),
+                            (
+                                json!({"status": res.status, "msg": String::from_utf8_lossy(&res.body)}),
+                                st.1,
+                                st.2,
+                            ),
+                        ));
+                    }
                     Err(err) => {
                         proto!("{:#?}", err);
-                        if let Some(err_res) = err.into_response() {
-                            commands.trigger(ErrorTriggerEvent {
-                                error: err_res.status(),
-                                url: url.to_string(),
-                            });
-                            completed_requests.push((
-                                (url.to_string(), query_key.clone()),
-                                (json!({"status":err_res.status(),"msg": err_res.into_string().unwrap()}),st.1, st.2),
-                            ));
-                        } else {
-                            completed_requests
-                                .push(((url.to_string(), query_key.clone()), (json!({"status":500}), st.1, st.2)));
-                        }
+                        completed_requests
+                            .push(((url.to_string(), query_key.clone()), (json!({"status":500}), st.1, st.2)));
                     }
                 }
+
+                if let Some(sequence) = sequence {
+                    let status = completed_requests.last().map(|r| response_status(&r.1 .0)).unwrap_or(500);
+                    sequence_steps.push((sequence.clone(), status));
+                }
             }
 
             retain
         });
 
+    poll_auth_refresh(
+        query_store.bypass_change_detection(),
+        &mut completed_requests,
+        &mut sequence_steps,
+        &mut commands,
+    );
+    for (key, _) in completed_requests.iter() {
+        query_store.rate_limits.requeues.remove(key);
+        query_store.auth.replayed.remove(key);
+        query_store.binary_bodies.remove(key);
+        query_store.mark_updated(key.clone());
+    }
     query_store.cache.extend(completed_requests);
+    query_store.binary_bodies.extend(binary_bodies);
+    for (sequence, status) in sequence_steps {
+        advance_sequence(&mut query_store, &sequence, status, &mut commands);
+    }
+    for (query, delay) in requeued {
+        requeue_after(&mut query_store, &limits, query, delay);
+    }
+    promote_pending_requests(
+        &mut query_store,
+        &limits,
+        &transport,
+        auth.as_deref(),
+        &middleware,
+    );
     debug_end!(start, PERFORMANCE_LOG_THRESHOLD_IN_MICROSECONDS);
 }
 
-//@TODO this does not wait for request to complete
+/// Sends the queries that were answered 401 again once the credentials are refreshed
+///
+/// If the refresh failed they fail with their 401
+#[allow(clippy::type_complexity)]
+fn poll_auth_refresh(
+    query_store: &mut QueryStore,
+    completed_requests: &mut Vec<((String, String), (serde_json::Value, Query, u128))>,
+    sequence_steps: &mut Vec<(String, u16)>,
+    commands: &mut Commands,
+) {
+    let Some(task) = query_store.auth.refresh.as_mut() else {
+        return;
+    };
+    let Some(result) = block_on(future::poll_once(task)) else {
+        return;
+    };
+    query_store.auth.refresh = None;
+    let waiting = std::mem::take(&mut query_store.auth.waiting);
+
+    match result {
+        Ok(()) => {
+            for query in waiting.iter() {
+                query_store
+                    .auth
+                    .replayed
+                    .insert((query.url.clone(), query.query_key.clone().unwrap_or_default()));
+            }
+            // they were sent before the queries triggered during the refresh
+            query_store.pending_requests.splice(0..0, waiting);
+        }
+        Err(err) => {
+            proto!("Failed to refresh the credentials {:#?}", err);
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis();
+            for query in waiting {
+                commands.trigger(ErrorTriggerEvent {
+                    error: 401,
+                    url: query.url.clone(),
+                });
+                if let Some(sequence) = &query.sequence_key {
+                    sequence_steps.push((sequence.clone(), 401));
+                }
+                completed_requests.push((
+                    (query.url.clone(), query.query_key.clone().unwrap_or_default()),
+                    (json!({"status": 401, "msg": err.to_string()}), query, now),
+                ));
+            }
+        }
+    }
+}
+
+/// Starts a sequence, every following step is sent by [`advance_sequence`] once the previous one finishes
 pub fn api_task_sequence(
     trigger: Trigger<QuerySequence>,
     mut api_tasks: ResMut<QueryStore>,
     mut commands: Commands,
 ) {
-    api_tasks
-        .sequences
-        .insert(trigger.event().key.clone(), trigger.event().tasks.clone());
-
-    let tasks = api_tasks.sequences.get(&trigger.event().key).unwrap();
-    if !tasks.is_empty() {
-        let next_task = Query {
-            sequence_key: Some(trigger.event().key.clone()),
-            ..tasks[0].clone()
-        };
-        commands.trigger(next_task);
-    }
+    let key = trigger.event().key.clone();
+    let tasks = trigger.event().tasks.clone();
+
+    let Some(first) = tasks.front().cloned() else {
+        api_tasks.sequences.remove(&key);
+        commands.trigger(SequenceCompleted {
+            key,
+            skipped_steps: vec![],
+        });
+        return;
+    };
+
+    api_tasks.sequences.insert(
+        key.clone(),
+        SequenceState {
+            total: tasks.len(),
+            tasks,
+            on_failure: trigger.event().on_failure,
+            compensations: trigger.event().compensations.clone(),
+            ..default()
+        },
+    );
+    commands.trigger(Query {
+        sequence_key: Some(key),
+        ..first
+    });
 }
 
-pub fn watch_cache(mut query_store: ResMut<QueryStore>, mut commands: Commands) {
-    if !query_store.stale_queries.is_empty() {
-        let removed = query_store.stale_queries.remove(0);
-        commands.trigger(removed);
+/// Applies the outcome of the step in flight to a sequence
+///
+/// Sends the next step, retries the failed one or finishes the sequence and removes it from the store
+fn advance_sequence(query_store: &mut QueryStore, key: &str, status: u16, commands: &mut Commands) {
+    let Some(sequence) = query_store.sequences.get_mut(key) else {
+        return;
+    };
+
+    if let Some(compensations) = sequence.compensating.as_mut() {
+        let failure = sequence.failure.as_mut();
+        if let (Some((step, _)), Some(failure)) = (compensations.pop_front(), failure) {
+            match status {
+                200 => failure.compensated.push(step),
+                _ => failure.compensation_failed.push(step),
+            }
+        }
+        send_next_compensation(query_store, key, commands);
+        return;
     }
-}
 
-async fn get(
-    url: String,
-    params: Option<Vec<(String, String)>>,
-    headers: Option<Vec<(String, String)>>,
-    timeout: Option<Duration>,
-) -> Result<ureq::Response, ureq::Error> {
-    let agent = ureq::builder().timeout_connect(Duration::from_secs(5)).build();
-
-    let mut request = agent
-        .get(url.as_str())
-        .query_pairs(
-            params
-                .unwrap_or_default()
-                .iter()
-                .map(|(k, v)| (k.as_str(), v.as_str())),
-        )
-        .timeout(timeout.unwrap_or(Duration::from_secs(5)));
-    if let Some(headers) = headers {
-        for (key, value) in headers {
-            request = request.set(&key, &value);
+    let Some(current) = sequence.tasks.front().cloned() else {
+        return;
+    };
+
+    if status != 200 {
+        match sequence.on_failure {
+            SequenceFailurePolicy::RetryStep { max_retries } if sequence.retries < max_retries => {
+                sequence.retries += 1;
+                let current_key = (current.url.clone(), current.query_key.clone().unwrap_or_default());
+                query_store.remove_entry(&current_key);
+                commands.trigger(Query {
+                    sequence_key: Some(key.to_string()),
+                    ..current
+                });
+                return;
+            }
+            SequenceFailurePolicy::SkipStep => sequence.skipped_steps.push(sequence.step),
+            _ => {
+                sequence.failure = Some(SequenceFailed {
+                    key: key.to_string(),
+                    step: sequence.step,
+                    url: current.url,
+                    status,
+                    ..default()
+                });
+                sequence.compensating = Some(
+                    (0..sequence.step)
+                        .rev()
+                        .filter(|step| !sequence.skipped_steps.contains(step))
+                        .filter_map(|step| Some((step, sequence.compensations.get(&step)?.clone())))
+                        .collect(),
+                );
+                send_next_compensation(query_store, key, commands);
+                return;
+            }
         }
     }
-    let response = request.call()?;
 
-    Ok(response)
+    commands.trigger(SequenceStepCompleted {
+        key: key.to_string(),
+        step: sequence.step,
+        total: sequence.total,
+        url: current.url,
+        status,
+    });
+    sequence.tasks.pop_front();
+    sequence.step += 1;
+    sequence.retries = 0;
+
+    match sequence.tasks.front() {
+        Some(next) => commands.trigger(Query {
+            sequence_key: Some(key.to_string()),
+            ..next.clone()
+        }),
+        None => {
+            let skipped_steps = std::mem::take(&mut sequence.skipped_steps);
+            query_store.sequences.remove(key);
+            commands.trigger(SequenceCompleted {
+                key: key.to_string(),
+                skipped_steps,
+            });
+        }
+    }
 }
 
-async fn post(
-    url: String,
-    params: Option<Vec<(String, String)>>,
-    body: serde_json::Value,
-    headers: Option<Vec<(String, String)>>,
-    timeout: Option<Duration>,
-) -> Result<ureq::Response, ureq::Error> {
-    let agent = ureq::builder().timeout_connect(Duration::from_secs(5)).build();
-    let mut request = agent
-        .post(url.as_str())
-        .query_pairs(
-            params
-                .unwrap_or_default()
-                .iter()
-                .map(|(k, v)| (k.as_str(), v.as_str())),
-        )
-        .timeout(timeout.unwrap_or(Duration::from_secs(5)));
-
-    if let Some(headers) = headers {
-        for (key, value) in headers {
-            request = request.set(&key, &value);
+/// Sends the next compensation of an aborted sequence or triggers [`SequenceFailed`] once none are left
+fn send_next_compensation(query_store: &mut QueryStore, key: &str, commands: &mut Commands) {
+    let Some(sequence) = query_store.sequences.get_mut(key) else {
+        return;
+    };
+
+    match sequence.compensating.as_ref().and_then(|compensations| compensations.front()) {
+        Some((_, compensation)) => {
+            let compensation = Query {
+                sequence_key: Some(key.to_string()),
+                ..compensation.clone()
+            };
+            // compensations always have to reach the server
+            query_store.remove_entry(&(
+                compensation.url.clone(),
+                compensation.query_key.clone().unwrap_or_default(),
+            ));
+            commands.trigger(compensation);
+        }
+        None => {
+            if let Some(failure) = sequence.failure.take() {
+                commands.trigger(failure);
+            }
+            query_store.sequences.remove(key);
         }
     }
+}
 
-    let response = request.send_json(body)?;
-    Ok(response)
+/// Sends a cached query again, the cached response stays readable until it is replaced
+pub(crate) fn refetch_cached(world: &mut World, key: (String, String), query: Query) {
+    let Some(entry) = world.resource_mut::<QueryStore>().cache.remove(&key) else {
+        return;
+    };
+    world.trigger(query);
+    // keep serving the previous response while the new one is in flight
+    world.resource_mut::<QueryStore>().cache.insert(key, entry);
 }
 
-async fn delete(
-    url: String,
-    params: Option<Vec<(String, String)>>,
-    headers: Option<Vec<(String, String)>>,
-    timeout: Option<Duration>,
-) -> Result<ureq::Response, ureq::Error> {
-    let agent = ureq::builder().timeout_connect(Duration::from_secs(5)).build();
-    let mut request = agent
-        .delete(url.as_str())
-        .query_pairs(
-            params
-                .unwrap_or_default()
-                .iter()
-                .map(|(k, v)| (k.as_str(), v.as_str())),
-        )
-        .timeout(timeout.unwrap_or(Duration::from_secs(5)));
-
-    if let Some(headers) = headers {
-        for (key, value) in headers {
-            request = request.set(&key, &value);
-        }
+/// Reads the status code stored alongside a cached response
+pub(crate) fn response_status(value: &serde_json::Value) -> u16 {
+    value
+        .get("status")
+        .and_then(|status| status.as_u64())
+        .map(|status| status as u16)
+        .unwrap_or(500)
+}
+
+pub fn watch_cache(mut query_store: ResMut<QueryStore>, mut commands: Commands) {
+    if !query_store.stale_queries.is_empty() {
+        let removed = query_store.stale_queries.remove(0);
+        commands.trigger(removed);
     }
+}
 
-    let response = request.call()?;
-    Ok(response)
+/// Sends a request through the transport and reads the whole response, counting the bytes received
+///
+/// The query timeout covers reading the body too, transports may only apply it until the headers arrive
+async fn fetch(
+    transport: Arc<dyn HttpTransport>,
+    request: HttpRequest,
+    download: TransferProgress,
+) -> Result<FetchedResponse, TransportError> {
+    let timeout = request.timeout;
+    future::or(
+        async move {
+            let mut response = transport.send(request).await?;
+            download.set_total(
+                response
+                    .header("content-length")
+                    .and_then(|length| length.parse().ok()),
+            );
+            response.body = Box::new(ProgressReader::new(response.body, download));
+            response.fetch().await
+        },
+        async move {
+            Timer::after(timeout).await;
+            Err(TransportError::Timeout)
+        },
+    )
+    .await
 }
 
 pub fn query_store_is_empty(store: Res<QueryStore>) -> bool {
@@ -334,5 +823,5 @@ pub fn query_store_is_empty(store: Res<QueryStore>) -> bool {
 }
 
 pub fn loading_requests_is_empty(store: Res<QueryStore>) -> bool {
-    store.loading_requests.is_empty()
+    store.loading_requests.is_empty() && store.pending_requests.is_empty() && !store.auth.is_refreshing()
 }
@@ -0,0 +1,54 @@
+use brotli::Decompressor;
+use flate2::{
+    read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder},
+    write::GzEncoder,
+    Compression,
+};
+use std::io::{self, Read, Write};
+
+/// Encodings sent in `Accept-Encoding` unless the query sets the header itself
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
+/// Decodes a body sent with the `Content-Encoding` header `encodings`
+///
+/// Encodings are undone in the reverse order they were applied, `identity` is skipped
+pub(crate) fn decode(encodings: &str, mut body: Vec<u8>) -> io::Result<Vec<u8>> {
+    for encoding in encodings.rsplit(',') {
+        let encoding = encoding.trim().to_ascii_lowercase();
+        let mut decoded = vec![];
+        match encoding.as_str() {
+            "gzip" | "x-gzip" => {
+                MultiGzDecoder::new(body.as_slice()).read_to_end(&mut decoded)?;
+            }
+            // zlib wrapped as the spec says, some servers send raw deflate streams instead
+            "deflate" => {
+                if ZlibDecoder::new(body.as_slice())
+                    .read_to_end(&mut decoded)
+                    .is_err()
+                {
+                    decoded.clear();
+                    DeflateDecoder::new(body.as_slice()).read_to_end(&mut decoded)?;
+                }
+            }
+            "br" => {
+                Decompressor::new(body.as_slice(), 4096).read_to_end(&mut decoded)?;
+            }
+            "identity" | "" => continue,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unsupported content encoding {}", encoding),
+                ))
+            }
+        }
+        body = decoded;
+    }
+    Ok(body)
+}
+
+pub(crate) fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    // writing to a Vec does not fail
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
@@ -0,0 +1,261 @@
+use bevy::prelude::*;
+use hyper::Uri;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// Cookie kept by a [`CookieJar`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// Domain the cookie is sent to, lowercase and without a leading dot
+    pub domain: String,
+    /// `false` if the cookie is only sent to the host that set it, without its subdomains
+    pub include_subdomains: bool,
+    pub path: String,
+    /// `None` for session cookies, they are not persisted
+    pub expires: Option<SystemTime>,
+    /// Only sent over https
+    pub secure: bool,
+    pub http_only: bool,
+}
+
+impl Cookie {
+    /// Parses a `Set-Cookie` header received from `uri`, `None` if the cookie is invalid or may not
+    /// be set by that host
+    fn parse(header: &str, uri: &Uri, now: SystemTime) -> Option<Self> {
+        let host = uri.host()?.to_ascii_lowercase();
+        let mut attributes = header.split(';');
+        let (name, value) = attributes.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.trim().trim_matches('"').to_string(),
+            domain: host.clone(),
+            include_subdomains: false,
+            path: default_path(uri.path()),
+            expires: None,
+            secure: false,
+            http_only: false,
+        };
+        let mut max_age = None;
+        for attribute in attributes {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    if !domain_matches(&domain, true, &host) {
+                        return None;
+                    }
+                    cookie.domain = domain;
+                    cookie.include_subdomains = true;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "expires" => {
+                    if let Ok(expires) = httpdate::parse_http_date(value) {
+                        cookie.expires = Some(expires);
+                    }
+                }
+                "max-age" => {
+                    if let Ok(seconds) = value.parse::<i64>() {
+                        max_age = Some(seconds);
+                    }
+                }
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                _ => {}
+            }
+        }
+        // Max-Age takes precedence over Expires, zero or less expires the cookie right away
+        if let Some(seconds) = max_age {
+            cookie.expires = Some(match seconds > 0 {
+                true => now + Duration::from_secs(seconds as u64),
+                false => SystemTime::UNIX_EPOCH,
+            });
+        }
+        if cookie.secure && uri.scheme_str() != Some("https") {
+            return None;
+        }
+
+        Some(cookie)
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Returns `true` if the cookie is sent along a request to `uri`
+    pub fn matches(&self, uri: &Uri) -> bool {
+        let Some(host) = uri.host() else {
+            return false;
+        };
+        domain_matches(&self.domain, self.include_subdomains, &host.to_ascii_lowercase())
+            && path_matches(&self.path, uri.path())
+            && (!self.secure || uri.scheme_str() == Some("https"))
+    }
+}
+
+/// Cookies received by a transport and sent back with the following requests
+///
+/// Domain, path, expiry and secure attributes are honored. The jar is a shared handle, keep a clone to
+/// inspect or clear it, e.g. on logout.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct CookieJar {
+    cookies: Arc<Mutex<Vec<Cookie>>>,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the cookies saved by [`CookieJar::save`], empty if the file does not exist
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let cookies: Vec<Cookie> = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        let now = SystemTime::now();
+        Ok(Self {
+            cookies: Arc::new(Mutex::new(
+                cookies
+                    .into_iter()
+                    .filter(|cookie| !cookie.is_expired(now))
+                    .collect(),
+            )),
+        })
+    }
+
+    /// Writes the cookies that have an expiry, session cookies end with the run
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let persistent: Vec<Cookie> = self
+            .cookies()
+            .into_iter()
+            .filter(|cookie| cookie.expires.is_some())
+            .collect();
+        fs::write(path, serde_json::to_vec(&persistent)?)
+    }
+
+    /// Cookies that did not expire
+    pub fn cookies(&self) -> Vec<Cookie> {
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.is_expired(now));
+        cookies.clone()
+    }
+
+    /// Cookie named `name` sent along a request to `url`
+    pub fn get(&self, url: &str, name: &str) -> Option<Cookie> {
+        let uri: Uri = url.parse().ok()?;
+        self.cookies()
+            .into_iter()
+            .find(|cookie| cookie.name == name && cookie.matches(&uri))
+    }
+
+    /// Adds a cookie, replacing the one with the same name, domain and path
+    pub fn insert(&self, cookie: Cookie) {
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|existing| {
+            !(existing.name == cookie.name
+                && existing.domain == cookie.domain
+                && existing.path == cookie.path)
+        });
+        if !cookie.is_expired(SystemTime::now()) {
+            cookies.push(cookie);
+        }
+    }
+
+    pub fn remove(&self, domain: &str, path: &str, name: &str) {
+        self.cookies
+            .lock()
+            .unwrap()
+            .retain(|cookie| !(cookie.name == name && cookie.domain == domain && cookie.path == path));
+    }
+
+    pub fn clear(&self) {
+        self.cookies.lock().unwrap().clear();
+    }
+
+    /// Value of the `Cookie` header for a request to `url`, longer paths first
+    pub fn header(&self, url: &str) -> Option<String> {
+        let uri: Uri = url.parse().ok()?;
+        let mut cookies: Vec<Cookie> = self
+            .cookies()
+            .into_iter()
+            .filter(|cookie| cookie.matches(&uri))
+            .collect();
+        if cookies.is_empty() {
+            return None;
+        }
+        cookies.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        Some(
+            cookies
+                .iter()
+                .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+                .collect::<Vec<_>>()
+                .join("; "),
+        )
+    }
+
+    /// Stores the `Set-Cookie` headers of a response to `url`, expired cookies are removed
+    pub fn store(&self, url: &str, headers: &[(String, String)]) {
+        let Ok(uri) = url.parse::<Uri>() else {
+            return;
+        };
+        let now = SystemTime::now();
+        for (_, value) in headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("set-cookie"))
+        {
+            let Some(cookie) = Cookie::parse(value, &uri, now) else {
+                continue;
+            };
+            // plain http responses may not replace a secure cookie
+            if uri.scheme_str() != Some("https")
+                && self.cookies().iter().any(|existing| {
+                    existing.secure
+                        && existing.name == cookie.name
+                        && existing.domain == cookie.domain
+                        && existing.path == cookie.path
+                })
+            {
+                continue;
+            }
+            self.insert(cookie);
+        }
+    }
+}
+
+fn domain_matches(domain: &str, include_subdomains: bool, host: &str) -> bool {
+    host == domain
+        || (include_subdomains
+            && host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.ends_with('.')))
+}
+
+fn path_matches(cookie_path: &str, path: &str) -> bool {
+    let path = if path.is_empty() { "/" } else { path };
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// Directory of the request path, used when the cookie has no path
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(index) => path[..index].to_string(),
+    }
+}
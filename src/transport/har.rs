@@ -0,0 +1,616 @@
+use crate::transport::{
+    compression, BodyStream, HttpRequest, HttpResponse, HttpTransport, TransportError,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bevy::{log::warn, tasks::futures_lite::AsyncRead, utils::BoxedFuture};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Instant, SystemTime},
+};
+
+/// HTTP Archive 1.2, only the fields used for recording and replaying are typed
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Har {
+    pub log: HarLog,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HarLog {
+    pub version: String,
+    pub creator: HarCreator,
+    pub entries: Vec<HarEntry>,
+}
+
+impl Default for HarLog {
+    fn default() -> Self {
+        Self {
+            version: "1.2".to_string(),
+            creator: HarCreator {
+                name: env!("CARGO_PKG_NAME").to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            entries: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HarCreator {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarEntry {
+    pub started_date_time: String,
+    /// Total time of the request in milliseconds
+    pub time: f64,
+    pub request: HarRequest,
+    pub response: HarResponse,
+    #[serde(default)]
+    pub cache: serde_json::Value,
+    #[serde(default)]
+    pub timings: HarTimings,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarRequest {
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub http_version: String,
+    #[serde(default)]
+    pub headers: Vec<HarNameValue>,
+    #[serde(default)]
+    pub query_string: Vec<HarNameValue>,
+    #[serde(default)]
+    pub cookies: Vec<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<HarPostData>,
+    #[serde(default = "unknown_size")]
+    pub headers_size: i64,
+    #[serde(default = "unknown_size")]
+    pub body_size: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarResponse {
+    /// 0 if no response was received
+    pub status: u16,
+    #[serde(default)]
+    pub status_text: String,
+    #[serde(default)]
+    pub http_version: String,
+    #[serde(default)]
+    pub headers: Vec<HarNameValue>,
+    #[serde(default)]
+    pub cookies: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub content: HarContent,
+    #[serde(default, rename = "redirectURL")]
+    pub redirect_url: String,
+    #[serde(default = "unknown_size")]
+    pub headers_size: i64,
+    #[serde(default = "unknown_size")]
+    pub body_size: i64,
+    /// Transport error of a request that got no response, or of a body that failed while it was read
+    #[serde(default, rename = "_error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HarNameValue {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarPostData {
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarContent {
+    pub size: i64,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// `base64` for bodies that are not valid UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HarTimings {
+    pub send: f64,
+    pub wait: f64,
+    pub receive: f64,
+}
+
+impl Har {
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        serde_json::from_reader(io::BufReader::new(file)).map_err(io::Error::from)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = fs::File::create(path)?;
+        serde_json::to_writer_pretty(io::BufWriter::new(file), self).map_err(io::Error::from)
+    }
+}
+
+/// Headers carrying credentials, their values are not recorded by default
+pub const REDACTED_HEADERS: [&str; 4] = ["authorization", "proxy-authorization", "cookie", "set-cookie"];
+
+/// Value recorded in place of a redacted header
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Transport that records every request sent through `transport` and its response
+///
+/// Response bodies are handed to the plugin as they are read, so streamed queries and download progress
+/// work while recording. A response is added to the archive once its body ended, failed or was dropped.
+/// The values of the [`REDACTED_HEADERS`] are replaced with [`REDACTED`] so archives can be shared, see
+/// [`HarRecorder::with_redacted_headers`].
+#[derive(Clone)]
+pub struct HarRecorder {
+    transport: Arc<dyn HttpTransport>,
+    har: Arc<Mutex<Har>>,
+    redacted_headers: Arc<Vec<String>>,
+}
+
+impl HarRecorder {
+    pub fn new(transport: impl HttpTransport) -> Self {
+        Self {
+            transport: Arc::new(transport),
+            har: Arc::default(),
+            redacted_headers: Arc::new(REDACTED_HEADERS.iter().map(|name| name.to_string()).collect()),
+        }
+    }
+
+    /// Replaces the headers whose values are not recorded, names are case insensitive and an empty list
+    /// records every header as it was sent
+    pub fn with_redacted_headers(mut self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.redacted_headers = Arc::new(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Archive of the traffic recorded so far
+    pub fn har(&self) -> Har {
+        self.har.lock().unwrap().clone()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.har().save(path)
+    }
+
+    pub fn clear(&self) {
+        self.har.lock().unwrap().log.entries.clear();
+    }
+}
+
+impl HttpTransport for HarRecorder {
+    fn send(&self, request: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>> {
+        let transport = self.transport.clone();
+        let har = self.har.clone();
+        let redacted_headers = self.redacted_headers.clone();
+        Box::pin(async move {
+            let started_at = SystemTime::now();
+            let start = Instant::now();
+            let har_request = har_request(&request, &redacted_headers);
+            let result = transport.send(request).await;
+            let wait = elapsed_millis(start);
+            let mut entry = HarEntry {
+                started_date_time: iso_8601(started_at),
+                time: wait,
+                request: har_request,
+                cache: serde_json::json!({}),
+                timings: HarTimings {
+                    wait,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+
+            match result {
+                Ok(response) => {
+                    entry.response = HarResponse {
+                        status: response.status,
+                        http_version: "HTTP/1.1".to_string(),
+                        headers: name_values(&response.headers, &redacted_headers),
+                        content: HarContent {
+                            mime_type: response.header("content-type").unwrap_or_default().to_string(),
+                            ..Default::default()
+                        },
+                        headers_size: -1,
+                        ..Default::default()
+                    };
+                    Ok(HttpResponse {
+                        body: Box::new(RecordedBody {
+                            body: response.body,
+                            received: vec![],
+                            entry: Some((entry, har)),
+                            start,
+                        }),
+                        ..response
+                    })
+                }
+                Err(err) => {
+                    entry.response = HarResponse {
+                        headers_size: -1,
+                        body_size: -1,
+                        error: Some(err.to_string()),
+                        ..Default::default()
+                    };
+                    har.lock().unwrap().log.entries.push(entry);
+                    Err(err)
+                }
+            }
+        })
+    }
+}
+
+/// Response body read by the plugin while a copy of it is kept for the archive
+struct RecordedBody {
+    body: BodyStream,
+    received: Vec<u8>,
+    /// Entry of the response and the archive it goes to, `None` once it was added
+    entry: Option<(HarEntry, Arc<Mutex<Har>>)>,
+    start: Instant,
+}
+
+impl RecordedBody {
+    /// Adds the entry with the body received so far to the archive
+    fn record(&mut self, error: Option<String>) {
+        let Some((mut entry, har)) = self.entry.take() else {
+            return;
+        };
+        let received = std::mem::take(&mut self.received);
+        let response = &mut entry.response;
+        response.body_size = received.len() as i64;
+        response.error = error;
+
+        // archived decoded like the bodies handed to the plugin, so they can be replayed
+        let encoding = response
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("content-encoding"))
+            .map(|header| header.value.clone());
+        let body = match encoding.map(|encoding| compression::decode(&encoding, received.clone())) {
+            Some(Ok(decoded)) => {
+                response.headers.retain(|header| {
+                    !header.name.eq_ignore_ascii_case("content-encoding")
+                        && !header.name.eq_ignore_ascii_case("content-length")
+                });
+                decoded
+            }
+            _ => received,
+        };
+        response.content = har_content(&body, &response.content.mime_type);
+
+        entry.time = elapsed_millis(self.start);
+        // subtracted in whole microseconds, a float difference would not survive the round trip
+        let wait = (entry.timings.wait * 1000.).round() as i64;
+        let time = (entry.time * 1000.).round() as i64;
+        entry.timings.receive = (time - wait) as f64 / 1000.;
+        har.lock().unwrap().log.entries.push(entry);
+    }
+}
+
+impl AsyncRead for RecordedBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = Pin::new(&mut self.body).poll_read(cx, buf);
+        match &read {
+            Poll::Ready(Ok(0)) if !buf.is_empty() => self.record(None),
+            Poll::Ready(Ok(read)) => self.received.extend_from_slice(&buf[..*read]),
+            Poll::Ready(Err(err)) => self.record(Some(err.to_string())),
+            Poll::Pending => {}
+        }
+        read
+    }
+}
+
+impl Drop for RecordedBody {
+    /// Streams closed by the plugin are recorded with what was received until then
+    fn drop(&mut self) {
+        self.record(None);
+    }
+}
+
+/// What a [`HarReplay`] does with requests that are not in the archive
+#[derive(Clone, Default)]
+pub enum ReplayStrictness {
+    /// Fail the request with a transport error
+    #[default]
+    Error,
+    /// Answer with an empty 404
+    NotFound,
+    /// Send the request through another transport
+    Passthrough(Arc<dyn HttpTransport>),
+}
+
+/// Transport answering requests from a recorded [`Har`]
+///
+/// Requests match an entry when their method, url, query parameters and body are the same, parameters
+/// may be in any order and JSON bodies are compared as values. Identical requests are answered with
+/// their recorded responses in order, the last one is repeated once all of them were served.
+#[derive(Clone)]
+pub struct HarReplay {
+    entries: Arc<Vec<HarEntry>>,
+    /// Number of times each entry was served
+    served: Arc<Mutex<Vec<usize>>>,
+    strictness: ReplayStrictness,
+}
+
+impl HarReplay {
+    pub fn new(har: Har) -> Self {
+        Self {
+            served: Arc::new(Mutex::new(vec![0; har.log.entries.len()])),
+            entries: Arc::new(har.log.entries),
+            strictness: ReplayStrictness::default(),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(Har::from_file(path)?))
+    }
+
+    pub fn with_strictness(mut self, strictness: ReplayStrictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    fn find_entry(&self, request: &HttpRequest) -> Option<&HarEntry> {
+        let key = RequestKey::from_request(request);
+        let matching: Vec<usize> = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| RequestKey::from_har(&entry.request) == key)
+            .map(|(index, _)| index)
+            .collect();
+
+        let mut served = self.served.lock().unwrap();
+        let index = matching
+            .iter()
+            .find(|index| served[**index] == 0)
+            .or(matching.last())
+            .copied()?;
+        served[index] += 1;
+        Some(&self.entries[index])
+    }
+}
+
+impl HttpTransport for HarReplay {
+    fn send(&self, request: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>> {
+        let Some(entry) = self.find_entry(&request) else {
+            let message = format!(
+                "No recorded response for {} {}",
+                request.method.as_str(),
+                request.url
+            );
+            return match &self.strictness {
+                ReplayStrictness::Error => {
+                    warn!("{}", message);
+                    Box::pin(async move { Err(TransportError::Other(message)) })
+                }
+                ReplayStrictness::NotFound => {
+                    warn!("{}", message);
+                    Box::pin(async { Ok(HttpResponse::from_bytes(404, vec![], vec![])) })
+                }
+                ReplayStrictness::Passthrough(transport) => transport.send(request),
+            };
+        };
+
+        let response = entry.response.clone();
+        Box::pin(async move {
+            if response.status == 0 {
+                return Err(TransportError::Connection(response.error.unwrap_or_default()));
+            }
+            let body = match (response.content.text, response.content.encoding.as_deref()) {
+                (Some(text), Some("base64")) => STANDARD
+                    .decode(text)
+                    .map_err(|err| TransportError::Other(err.to_string()))?,
+                (Some(text), _) => text.into_bytes(),
+                (None, _) => vec![],
+            };
+            let headers = response
+                .headers
+                .into_iter()
+                .map(|header| (header.name.to_ascii_lowercase(), header.value))
+                .collect();
+            Ok(HttpResponse::from_bytes(response.status, headers, body))
+        })
+    }
+}
+
+/// Normalized parts of a request that decide which entry answers it
+#[derive(Debug, PartialEq)]
+struct RequestKey {
+    method: String,
+    url: String,
+    params: Vec<(String, String)>,
+    body: Option<RequestBody>,
+}
+
+#[derive(Debug, PartialEq)]
+enum RequestBody {
+    Json(serde_json::Value),
+    Text(String),
+}
+
+impl RequestKey {
+    fn from_request(request: &HttpRequest) -> Self {
+        let (url, mut params) = split_url(&request.url);
+        params.extend(request.params.iter().cloned());
+        params.sort();
+        Self {
+            method: request.method.as_str().to_string(),
+            url,
+            params,
+            body: request
+                .body
+                .as_deref()
+                .map(|body| request_body(&String::from_utf8_lossy(body))),
+        }
+    }
+
+    fn from_har(request: &HarRequest) -> Self {
+        let (url, mut params) = split_url(&request.url);
+        if !request.query_string.is_empty() {
+            params = request
+                .query_string
+                .iter()
+                .map(|param| (param.name.clone(), param.value.clone()))
+                .collect();
+        }
+        params.sort();
+        Self {
+            method: request.method.to_ascii_uppercase(),
+            url,
+            params,
+            body: request
+                .post_data
+                .as_ref()
+                .map(|post_data| request_body(&post_data.text)),
+        }
+    }
+}
+
+fn request_body(text: &str) -> RequestBody {
+    match serde_json::from_str(text) {
+        Ok(json) => RequestBody::Json(json),
+        Err(_) => RequestBody::Text(text.to_string()),
+    }
+}
+
+/// Splits a url into the url without its query string and the decoded query parameters
+fn split_url(url: &str) -> (String, Vec<(String, String)>) {
+    let url = url.split('#').next().unwrap_or_default();
+    let Some((url, query)) = url.split_once('?') else {
+        return (url.to_string(), vec![]);
+    };
+    let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().to_string();
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect();
+    (url.to_string(), params)
+}
+
+fn har_request(request: &HttpRequest, redacted_headers: &[String]) -> HarRequest {
+    let (_, mut query_string) = split_url(&request.url);
+    query_string.extend(request.params.iter().cloned());
+
+    HarRequest {
+        method: request.method.as_str().to_string(),
+        url: request.url_with_params(),
+        http_version: "HTTP/1.1".to_string(),
+        headers: name_values(&request.headers, redacted_headers),
+        query_string: query_string
+            .into_iter()
+            .map(|(name, value)| HarNameValue { name, value })
+            .collect(),
+        post_data: request.body.as_ref().map(|body| HarPostData {
+            mime_type: request.header("content-type").unwrap_or_default().to_string(),
+            text: String::from_utf8_lossy(body).to_string(),
+        }),
+        headers_size: -1,
+        body_size: request.body.as_ref().map_or(0, |body| body.len() as i64),
+        ..Default::default()
+    }
+}
+
+fn har_content(body: &[u8], mime_type: &str) -> HarContent {
+    let (text, encoding) = match std::str::from_utf8(body) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (STANDARD.encode(body), Some("base64".to_string())),
+    };
+    HarContent {
+        size: body.len() as i64,
+        mime_type: mime_type.to_string(),
+        text: Some(text),
+        encoding,
+    }
+}
+
+fn name_values(headers: &[(String, String)], redacted_headers: &[String]) -> Vec<HarNameValue> {
+    headers
+        .iter()
+        .map(|(name, value)| HarNameValue {
+            name: name.clone(),
+            value: match redacted_headers
+                .iter()
+                .any(|redacted| redacted.eq_ignore_ascii_case(name))
+            {
+                true => REDACTED.to_string(),
+                false => value.clone(),
+            },
+        })
+        .collect()
+}
+
+fn unknown_size() -> i64 {
+    -1
+}
+
+/// Milliseconds since `start` with microsecond precision, so the time survives a round trip through
+/// the file
+fn elapsed_millis(start: Instant) -> f64 {
+    start.elapsed().as_micros() as f64 / 1000.
+}
+
+/// Formats a time as an ISO 8601 UTC date with milliseconds
+fn iso_8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
@@ -0,0 +1,382 @@
+use crate::{
+    progress::TransferProgress,
+    transport::{cookies::CookieJar, HttpRequest, HttpResponse, HttpTransport, TransportError},
+};
+use async_io::{Async, Timer};
+use bevy::{
+    tasks::{
+        futures_lite::{future, AsyncRead, AsyncWrite},
+        IoTaskPool, TaskPool,
+    },
+    utils::{BoxedFuture, HashMap},
+};
+use futures_rustls::TlsConnector;
+use hyper::{
+    body::{Body, Bytes, Frame, Incoming, SizeHint},
+    client::conn::http1::{self, SendRequest},
+    rt::ReadBufCursor,
+    Uri,
+};
+use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use std::{
+    convert::Infallible,
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+/// Idle connections kept per host by default, same as `ureq`
+///
+/// Every idle connection holds on to a server worker, small servers run out of them quickly
+const MAX_IDLE_PER_HOST: usize = 1;
+
+/// Size of the frames request bodies are written in
+const UPLOAD_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Idle connections by `scheme://host:port`
+type IdleConnections = Arc<Mutex<HashMap<String, Vec<SendRequest<UploadBody>>>>>;
+
+/// Non-blocking HTTP/1.1 transport based on `hyper`, this is the default transport
+///
+/// Connections are driven on the [`IoTaskPool`] so in-flight requests do not occupy compute threads.
+/// The transport applies the query timeout to connecting and receiving the response headers, queries
+/// reading their whole body are timed out once it took longer, streams and downloads keep reading it.
+#[derive(Clone)]
+pub struct HyperTransport {
+    tls: TlsConnector,
+    idle: IdleConnections,
+    max_idle_per_host: usize,
+    cookies: Option<CookieJar>,
+}
+
+impl Default for HyperTransport {
+    fn default() -> Self {
+        Self::with_tls_config(default_tls_config())
+    }
+}
+
+/// TLS config trusting the webpki roots, also used by the WebSocket subscriptions
+pub(crate) fn default_tls_config() -> Arc<ClientConfig> {
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+impl HyperTransport {
+    pub fn with_tls_config(config: Arc<ClientConfig>) -> Self {
+        Self {
+            tls: TlsConnector::from(config),
+            idle: Arc::default(),
+            max_idle_per_host: MAX_IDLE_PER_HOST,
+            cookies: None,
+        }
+    }
+
+    /// Number of connections kept open per host for later requests
+    pub fn with_max_idle_per_host(mut self, max_idle_per_host: usize) -> Self {
+        self.max_idle_per_host = max_idle_per_host;
+        self
+    }
+
+    /// Keeps the cookies set by responses and sends them with the following requests
+    pub fn with_cookie_jar(mut self, cookies: CookieJar) -> Self {
+        self.cookies = Some(cookies);
+        self
+    }
+
+    /// Reuses an idle connection to the origin or opens a new one
+    async fn connection(&self, uri: &Uri) -> Result<SendRequest<UploadBody>, TransportError> {
+        let origin = origin(uri)?;
+        if let Some(senders) = self.idle.lock().unwrap().get_mut(&origin.key) {
+            senders.retain(|sender| !sender.is_closed());
+            if let Some(index) = senders.iter().position(SendRequest::is_ready) {
+                return Ok(senders.swap_remove(index));
+            }
+        }
+
+        let address = origin.address.clone();
+        let addresses = blocking::unblock(move || address.to_socket_addrs())
+            .await
+            .map_err(|err| TransportError::Connection(err.to_string()))?;
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "Host has no address");
+        let mut tcp = None;
+        for address in addresses {
+            match Async::<TcpStream>::connect(address).await {
+                Ok(stream) => {
+                    tcp = Some(stream);
+                    break;
+                }
+                Err(err) => last_error = err,
+            }
+        }
+        let tcp = tcp.ok_or(TransportError::from(last_error))?;
+
+        let stream: Box<dyn Stream> = match origin.tls {
+            true => {
+                let server_name = ServerName::try_from(origin.host.clone())
+                    .map_err(|err| TransportError::Other(err.to_string()))?;
+                Box::new(self.tls.connect(server_name, tcp).await?)
+            }
+            false => Box::new(tcp),
+        };
+
+        let (sender, connection) = http1::handshake(HyperIo(stream))
+            .await
+            .map_err(|err| TransportError::Connection(err.to_string()))?;
+        IoTaskPool::get_or_init(TaskPool::default)
+            .spawn(async move {
+                let _ = connection.await;
+            })
+            .detach();
+
+        Ok(sender)
+    }
+
+    async fn exchange(&self, mut request: HttpRequest) -> Result<HttpResponse, TransportError> {
+        if let Some(cookies) = &self.cookies {
+            if let Some(header) = cookies.header(&request.url) {
+                request.headers.push(("cookie".to_string(), header));
+            }
+        }
+        let uri: Uri = request
+            .url_with_params()
+            .parse()
+            .map_err(|err: hyper::http::uri::InvalidUri| TransportError::Other(err.to_string()))?;
+        let mut sender = self.connection(&uri).await?;
+
+        let mut builder = hyper::Request::builder()
+            .method(request.method.as_str())
+            .uri(uri.path_and_query().map_or("/", |path| path.as_str()));
+        if request.header("host").is_none() {
+            builder = builder.header("host", uri.authority().map_or("", |authority| authority.as_str()));
+        }
+        for (name, value) in request.headers.iter() {
+            builder = builder.header(name, value);
+        }
+        let hyper_request = builder
+            .body(UploadBody {
+                data: Bytes::from(request.body.take().unwrap_or_default()),
+                progress: request.upload_progress.clone(),
+            })
+            .map_err(|err| TransportError::Other(err.to_string()))?;
+
+        let response = sender
+            .send_request(hyper_request)
+            .await
+            .map_err(|err| TransportError::Connection(err.to_string()))?;
+
+        let (parts, body) = response.into_parts();
+        let headers = parts
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect::<Vec<_>>();
+        if let Some(cookies) = &self.cookies {
+            cookies.store(&request.url, &headers);
+        }
+        Ok(HttpResponse {
+            status: parts.status.as_u16(),
+            headers,
+            body: Box::new(IncomingReader {
+                body,
+                chunk: Bytes::new(),
+                release: origin(&uri).ok().map(|origin| Release {
+                    sender,
+                    idle: self.idle.clone(),
+                    key: origin.key,
+                    max_idle: self.max_idle_per_host,
+                }),
+            }),
+        })
+    }
+}
+
+impl HttpTransport for HyperTransport {
+    fn send(&self, request: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>> {
+        let transport = self.clone();
+        let timeout = request.timeout;
+        let task = IoTaskPool::get_or_init(TaskPool::default).spawn(future::or(
+            async move { transport.exchange(request).await },
+            async move {
+                Timer::after(timeout).await;
+                Err(TransportError::Timeout)
+            },
+        ));
+        Box::pin(task)
+    }
+}
+
+struct Origin {
+    /// `scheme://host:port`
+    key: String,
+    host: String,
+    address: (String, u16),
+    tls: bool,
+}
+
+fn origin(uri: &Uri) -> Result<Origin, TransportError> {
+    let tls = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        scheme => {
+            return Err(TransportError::Other(format!(
+                "Unsupported scheme {}",
+                scheme.unwrap_or_default()
+            )))
+        }
+    };
+    let host = uri
+        .host()
+        .ok_or(TransportError::Other(format!("Missing host in {}", uri)))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+    Ok(Origin {
+        key: format!("{}://{}:{}", if tls { "https" } else { "http" }, host, port),
+        address: (host.clone(), port),
+        host,
+        tls,
+    })
+}
+
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// Adapts a `futures` stream to the io traits of `hyper`
+struct HyperIo(Box<dyn Stream>);
+
+impl hyper::rt::Read for HyperIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        mut buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut chunk = [0; 8192];
+        let len = buf.remaining().min(chunk.len());
+        match Pin::new(&mut self.0).poll_read(cx, &mut chunk[..len]) {
+            Poll::Ready(Ok(read)) => {
+                buf.put_slice(&chunk[..read]);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl hyper::rt::Write for HyperIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+/// Connection to return to the idle connections once the response body is read
+struct Release {
+    sender: SendRequest<UploadBody>,
+    idle: IdleConnections,
+    key: String,
+    max_idle: usize,
+}
+
+impl Release {
+    fn run(self) {
+        let mut idle = self.idle.lock().unwrap();
+        let senders = idle.entry(self.key).or_default();
+        if senders.len() < self.max_idle {
+            senders.push(self.sender);
+        }
+    }
+}
+
+/// Request body written in chunks, each chunk is counted as it is handed to the connection
+struct UploadBody {
+    data: Bytes,
+    progress: Option<TransferProgress>,
+}
+
+impl Body for UploadBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        if self.data.is_empty() {
+            return Poll::Ready(None);
+        }
+        let len = self.data.len().min(UPLOAD_CHUNK_SIZE);
+        let chunk = self.data.split_to(len);
+        if let Some(progress) = &self.progress {
+            progress.add(len as u64);
+        }
+        Poll::Ready(Some(Ok(Frame::data(chunk))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.data.len() as u64)
+    }
+}
+
+/// Streams a `hyper` response body as an [`AsyncRead`]
+struct IncomingReader {
+    body: Incoming,
+    /// Rest of the last data frame
+    chunk: Bytes,
+    release: Option<Release>,
+}
+
+impl AsyncRead for IncomingReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.chunk.is_empty() {
+            match Pin::new(&mut self.body).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => {
+                    if let Ok(data) = frame.into_data() {
+                        self.chunk = data;
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(io::Error::other(err))),
+                Poll::Ready(None) => {
+                    if let Some(release) = self.release.take() {
+                        release.run();
+                    }
+                    return Poll::Ready(Ok(0));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Poll::Ready(Ok(len))
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{
+    tasks::Method,
+    transport::{glob_match, HttpRequest, HttpResponse, HttpTransport, TransportError},
+};
+use async_io::Timer;
+use bevy::utils::BoxedFuture;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Matches requests by method, url pattern and headers
+///
+/// Patterns starting with `/` are matched against the path of the url, other patterns against the whole
+/// url. `*` matches any sequence of characters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestMatcher {
+    /// `None` matches every method
+    pub method: Option<Method>,
+    pub pattern: String,
+    /// Headers the request must have, names are case insensitive
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestMatcher {
+    pub fn new(method: Method, pattern: impl Into<String>) -> Self {
+        Self {
+            method: Some(method),
+            pattern: pattern.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn get(pattern: impl Into<String>) -> Self {
+        Self::new(Method::Get, pattern)
+    }
+
+    pub fn post(pattern: impl Into<String>) -> Self {
+        Self::new(Method::Post, pattern)
+    }
+
+    pub fn delete(pattern: impl Into<String>) -> Self {
+        Self::new(Method::Delete, pattern)
+    }
+
+    /// Matches requests with any method
+    pub fn any(pattern: impl Into<String>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn matches(&self, request: &HttpRequest) -> bool {
+        if self.method.is_some_and(|method| method != request.method) {
+            return false;
+        }
+        if !self
+            .headers
+            .iter()
+            .all(|(name, value)| request.header(name) == Some(value.as_str()))
+        {
+            return false;
+        }
+
+        let url = request.url.split(['?', '#']).next().unwrap_or_default();
+        match self.pattern.starts_with('/') {
+            true => glob_match(&self.pattern, url_path(url)),
+            false => glob_match(&self.pattern, url),
+        }
+    }
+}
+
+/// Canned response served by a [`MockTransport`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Time the transport waits before responding, without holding a task pool thread
+    pub delay: Duration,
+    /// Fail with this error instead of responding
+    pub error: Option<TransportError>,
+}
+
+impl MockResponse {
+    pub fn json(body: serde_json::Value) -> Self {
+        Self {
+            status: 200,
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            body: serde_json::to_vec(&body).unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    /// Error status with an empty body
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            ..Default::default()
+        }
+    }
+
+    pub fn error(error: TransportError) -> Self {
+        Self {
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers
+            .push((name.into().to_ascii_lowercase(), value.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    /// Routes with the number of requests they still answer, `None` for no limit
+    routes: Vec<(RequestMatcher, MockResponse, Option<usize>)>,
+    requests: Vec<HttpRequest>,
+}
+
+/// In-memory transport for tests, serves canned responses and records every request it receives
+///
+/// Clones share their routes and recorded requests, keep a clone to inspect the requests sent by the app.
+/// Requests that match no route get a 404.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `response` to requests matching `matcher`, routes added later take precedence
+    pub fn on(&self, matcher: RequestMatcher, response: MockResponse) -> &Self {
+        self.state.lock().unwrap().routes.push((matcher, response, None));
+        self
+    }
+
+    /// Serves `response` to the next `times` requests matching `matcher`, later ones fall through to the
+    /// routes added before
+    pub fn on_times(&self, matcher: RequestMatcher, response: MockResponse, times: usize) -> &Self {
+        self.state
+            .lock()
+            .unwrap()
+            .routes
+            .push((matcher, response, Some(times)));
+        self
+    }
+
+    /// Every request received so far, in order
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn requests_matching(&self, matcher: &RequestMatcher) -> Vec<HttpRequest> {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|request| matcher.matches(request))
+            .cloned()
+            .collect()
+    }
+
+    /// Panics unless exactly `times` requests matched `matcher`
+    pub fn assert_requested(&self, matcher: &RequestMatcher, times: usize) {
+        let matched = self.requests_matching(matcher).len();
+        assert_eq!(
+            matched,
+            times,
+            "expected {} request(s) matching {:?}, got {}\nrequests: {:#?}",
+            times,
+            matcher,
+            matched,
+            self.requests()
+        );
+    }
+
+    pub fn clear_requests(&self) {
+        self.state.lock().unwrap().requests.clear();
+    }
+}
+
+impl HttpTransport for MockTransport {
+    fn send(&self, request: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>> {
+        let mut state = self.state.lock().unwrap();
+        let response = state
+            .routes
+            .iter_mut()
+            .rev()
+            .find(|(matcher, _, remaining)| remaining != &Some(0) && matcher.matches(&request))
+            .map(|(_, response, remaining)| {
+                if let Some(remaining) = remaining {
+                    *remaining -= 1;
+                }
+                response.clone()
+            })
+            .unwrap_or_else(|| MockResponse::status(404));
+        if let (Some(progress), Some(body)) = (&request.upload_progress, &request.body) {
+            progress.add(body.len() as u64);
+        }
+        state.requests.push(request);
+
+        Box::pin(async move {
+            if !response.delay.is_zero() {
+                Timer::after(response.delay).await;
+            }
+            if let Some(err) = response.error {
+                return Err(err);
+            }
+            Ok(HttpResponse::from_bytes(
+                response.status,
+                response.headers,
+                response.body,
+            ))
+        })
+    }
+}
+
+/// Path of an absolute url, the url itself if it has no scheme
+fn url_path(url: &str) -> &str {
+    match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |index| &rest[index..]),
+        None => url,
+    }
+}
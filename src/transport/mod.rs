@@ -0,0 +1,359 @@
+use crate::{
+    progress::{ProgressReader, TransferProgress},
+    tasks::Method,
+    Query,
+};
+use bevy::{
+    prelude::*,
+    tasks::futures_lite::{io::AssertAsync, AsyncRead, AsyncReadExt},
+    utils::BoxedFuture,
+};
+use compression::ACCEPT_ENCODING;
+use cookies::CookieJar;
+use hyper_transport::HyperTransport;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{fmt, io, sync::Arc, time::Duration};
+
+pub mod compression;
+pub mod cookies;
+pub mod har;
+pub mod hyper_transport;
+pub mod mock;
+
+/// Timeout applied to queries that do not set one
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Characters escaped in query parameters, everything but the unreserved characters of RFC 3986
+const QUERY: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+pub type BodyStream = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Request as it is handed to a transport, built from a [`Query`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    pub params: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+    pub timeout: Duration,
+    /// Counts the body bytes as they are written, set for the requests sent by the plugin
+    pub upload_progress: Option<TransferProgress>,
+}
+
+impl HttpRequest {
+    /// Normalizes a query, POST bodies are sent as JSON, gzipped if the query asks for it
+    pub fn from_query(query: &Query) -> Self {
+        let mut headers = query.headers.clone().unwrap_or_default();
+        if find_header(&headers, "accept-encoding").is_none() {
+            headers.push(("Accept-Encoding".to_string(), ACCEPT_ENCODING.to_string()));
+        }
+        let body = match query.method {
+            Method::Post => {
+                if find_header(&headers, "content-type").is_none() {
+                    headers.push(("Content-Type".to_string(), "application/json".to_string()));
+                }
+                let body = serde_json::to_vec(&query.body).unwrap_or_default();
+                match query.gzip_body {
+                    true => {
+                        headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+                        Some(compression::gzip(&body))
+                    }
+                    false => Some(body),
+                }
+            }
+            _ => None,
+        };
+
+        Self {
+            method: query.method,
+            url: query.url.clone(),
+            params: query.params.clone().unwrap_or_default(),
+            headers,
+            body,
+            timeout: query.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            upload_progress: None,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// Replaces every header named `name`, ignoring case
+    pub fn set_header(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.headers
+            .retain(|(header, _)| !header.eq_ignore_ascii_case(&name));
+        self.headers.push((name, value.into()));
+    }
+
+    /// Url with the percent-encoded params appended to its query string
+    pub fn url_with_params(&self) -> String {
+        let mut url = self.url.clone();
+        for (index, (name, value)) in self.params.iter().enumerate() {
+            let separator = match index == 0 && !self.url.contains('?') {
+                true => '?',
+                false => '&',
+            };
+            url.push_str(&format!(
+                "{}{}={}",
+                separator,
+                utf8_percent_encode(name, QUERY),
+                utf8_percent_encode(value, QUERY)
+            ));
+        }
+        url
+    }
+}
+
+/// Response returned by a transport, the body is streamed
+///
+/// Error statuses are regular responses, only failures to get a response at all are a [`TransportError`]
+pub struct HttpResponse {
+    pub status: u16,
+    /// Header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: BodyStream,
+}
+
+impl HttpResponse {
+    /// Response with a body that is already in memory
+    pub fn from_bytes(status: u16, headers: Vec<(String, String)>, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers,
+            body: Box::new(AssertAsync::new(io::Cursor::new(body))),
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// Reads the whole body and decodes it according to its `Content-Encoding`
+    pub async fn fetch(mut self) -> Result<FetchedResponse, TransportError> {
+        let mut body = vec![];
+        self.body
+            .read_to_end(&mut body)
+            .await
+            .map_err(TransportError::from)?;
+
+        let mut compressed_size = None;
+        if let Some(encoding) = self.header("content-encoding").map(str::to_string) {
+            compressed_size = Some(body.len());
+            body = compression::decode(&encoding, body)
+                .map_err(|err| TransportError::Other(format!("Failed to decode the body: {}", err)))?;
+            self.headers
+                .retain(|(name, _)| name != "content-encoding" && name != "content-length");
+        }
+
+        Ok(FetchedResponse {
+            status: self.status,
+            headers: self.headers,
+            body,
+            compressed_size,
+        })
+    }
+}
+
+impl fmt::Debug for HttpResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpResponse")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Response with its whole body read, this is what loading requests resolve to
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FetchedResponse {
+    pub status: u16,
+    /// Header names are lowercase
+    pub headers: Vec<(String, String)>,
+    /// Decoded body
+    pub body: Vec<u8>,
+    /// Size of the body as it was received, `None` if it was not compressed
+    ///
+    /// `UreqTransport` decodes gzip bodies itself, their compressed size is not known
+    pub compressed_size: Option<usize>,
+}
+
+impl FetchedResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportError {
+    Timeout,
+    /// The connection could not be established or broke while reading the response
+    Connection(String),
+    Other(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Timeout => write!(f, "Request timed out"),
+            TransportError::Connection(err) => write!(f, "Connection failed: {}", err),
+            TransportError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<io::Error> for TransportError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => TransportError::Timeout,
+            _ => TransportError::Connection(err.to_string()),
+        }
+    }
+}
+
+/// Sends HTTP requests for the plugin
+///
+/// The returned future runs on a bevy task pool, it must not borrow from the transport
+pub trait HttpTransport: Send + Sync + 'static {
+    fn send(&self, request: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>>;
+}
+
+/// Transport used by [`spawn_api_task`](crate::tasks::spawn_api_task)
+#[derive(Resource, Clone)]
+pub struct QueryTransport(pub Arc<dyn HttpTransport>);
+
+impl QueryTransport {
+    pub fn new(transport: impl HttpTransport) -> Self {
+        Self(Arc::new(transport))
+    }
+}
+
+impl Default for QueryTransport {
+    fn default() -> Self {
+        Self::new(HyperTransport::default())
+    }
+}
+
+/// Blocking transport based on `ureq`, requests are sent and their bodies read on the `blocking` thread pool
+///
+/// Every in-flight request occupies one of its threads
+#[derive(Clone)]
+pub struct UreqTransport {
+    pub agent: ureq::Agent,
+    /// Keeps the cookies set by responses and sends them with the following requests
+    pub cookies: Option<CookieJar>,
+}
+
+impl Default for UreqTransport {
+    fn default() -> Self {
+        Self {
+            agent: ureq::builder().timeout_connect(Duration::from_secs(5)).build(),
+            cookies: None,
+        }
+    }
+}
+
+impl UreqTransport {
+    pub fn with_cookie_jar(mut self, cookies: CookieJar) -> Self {
+        self.cookies = Some(cookies);
+        self
+    }
+}
+
+impl HttpTransport for UreqTransport {
+    fn send(&self, request: HttpRequest) -> BoxedFuture<'static, Result<HttpResponse, TransportError>> {
+        let agent = self.agent.clone();
+        let cookies = self.cookies.clone();
+        Box::pin(blocking::unblock(move || {
+            let mut ureq_request = agent
+                .request(request.method.as_str(), &request.url)
+                .query_pairs(request.params.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+                .timeout(request.timeout);
+            for (key, value) in request.headers.iter() {
+                ureq_request = ureq_request.set(key, value);
+            }
+            if let Some(header) = cookies.as_ref().and_then(|cookies| cookies.header(&request.url)) {
+                ureq_request = ureq_request.set("cookie", &header);
+            }
+
+            let response = match (request.body, request.upload_progress) {
+                (Some(body), Some(progress)) => ureq_request
+                    .set("content-length", &body.len().to_string())
+                    .send(ProgressReader::new(io::Cursor::new(body), progress)),
+                (Some(body), None) => ureq_request.send_bytes(&body),
+                (None, _) => ureq_request.call(),
+            };
+            let response = match response {
+                Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+                Err(ureq::Error::Transport(err)) => {
+                    return Err(match err.kind() {
+                        ureq::ErrorKind::Io if err.to_string().contains("timed out") => {
+                            TransportError::Timeout
+                        }
+                        ureq::ErrorKind::Dns
+                        | ureq::ErrorKind::ConnectionFailed
+                        | ureq::ErrorKind::Io => TransportError::Connection(err.to_string()),
+                        _ => TransportError::Other(err.to_string()),
+                    })
+                }
+            };
+
+            // every value of repeated headers such as Set-Cookie
+            let mut headers: Vec<(String, String)> = vec![];
+            for name in response.headers_names() {
+                let name = name.to_ascii_lowercase();
+                if headers.iter().any(|(header, _)| header == &name) {
+                    continue;
+                }
+                for value in response.all(&name) {
+                    headers.push((name.clone(), value.to_string()));
+                }
+            }
+            if let Some(cookies) = &cookies {
+                cookies.store(&request.url, &headers);
+            }
+
+            Ok(HttpResponse {
+                status: response.status(),
+                headers,
+                body: Box::new(blocking::Unblock::new(response.into_reader())),
+            })
+        }))
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Matches `value` against a pattern where `*` matches any sequence of characters
+pub(crate) fn glob_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
@@ -0,0 +1,541 @@
+use crate::{
+    auth::QueryAuth,
+    live::{merge_update, MergeStrategy},
+    middleware::QueryMiddlewareStack,
+    proto,
+    tasks::QueryStore,
+    transport::{
+        hyper_transport::{default_tls_config, Stream},
+        HttpRequest, DEFAULT_TIMEOUT,
+    },
+    Query,
+};
+use async_channel::{Receiver, Sender, TryRecvError};
+use async_io::{Async, Timer};
+use bevy::{
+    prelude::*,
+    tasks::{
+        futures_lite::{future, AsyncRead, AsyncWrite, Stream as _},
+        IoTaskPool, TaskPool,
+    },
+};
+use futures_rustls::TlsConnector;
+use hyper::Uri;
+use rustls::pki_types::ServerName;
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    pin::{pin, Pin},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+use tungstenite::{
+    client::IntoClientRequest, handshake::HandshakeError, http::HeaderValue, Message, WebSocket,
+};
+
+/// Delay before the first reconnection, doubled after every failed attempt
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Live data received over a WebSocket and merged into a cache entry
+///
+/// Messages are decoded as JSON and merged into the entry `(url, query_key)` with the `merge` strategy, so
+/// they can be read with the same extractors as fetched responses. The entry can be the one of the query
+/// fetching the initial state, set it with [`Subscription::with_cache_key`]. The connection is opened
+/// again with an exponential backoff when it drops, until [`Unsubscribe`] is triggered.
+///
+/// Every connection attempt is authorized by the [`QueryAuth`] and its handshake then goes through
+/// `on_request` of the [`QueryMiddlewareStack`], like a query. An open socket is a task of the
+/// [`IoTaskPool`] woken by the messages it receives or has to send.
+#[derive(Event, Debug, Clone)]
+pub struct Subscription {
+    /// `ws://` or `wss://` url of the socket
+    pub socket_url: String,
+    /// Url of the cache entry, the socket url unless set
+    pub url: String,
+    pub query_key: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// Messages sent every time the socket connects, e.g. to join a channel
+    pub on_connect: Vec<String>,
+    pub merge: MergeStrategy,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Subscription {
+    pub fn new(socket_url: impl Into<String>) -> Self {
+        let socket_url = socket_url.into();
+        Self {
+            url: socket_url.clone(),
+            socket_url,
+            query_key: None,
+            headers: vec![],
+            on_connect: vec![],
+            merge: MergeStrategy::default(),
+            min_backoff: MIN_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+        }
+    }
+
+    /// Merges the messages into the cache entry of a query
+    pub fn with_cache_key(mut self, url: impl Into<String>, query_key: Option<String>) -> Self {
+        self.url = url.into();
+        self.query_key = query_key;
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_on_connect(mut self, message: impl Into<String>) -> Self {
+        self.on_connect.push(message.into());
+        self
+    }
+
+    pub fn with_merge(mut self, merge: MergeStrategy) -> Self {
+        self.merge = merge;
+        self
+    }
+
+    pub fn with_backoff(mut self, min_backoff: Duration, max_backoff: Duration) -> Self {
+        self.min_backoff = min_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// (url, query_key) of the cache entry
+    pub fn key(&self) -> (String, String) {
+        (self.url.clone(), self.query_key.clone().unwrap_or_default())
+    }
+}
+
+/// Closes the subscription writing into the cache entry `(url, query_key)`, the entry is kept
+#[derive(Event, Debug, Clone)]
+pub struct Unsubscribe {
+    pub url: String,
+    pub query_key: Option<String>,
+}
+
+/// Sends a text message on the socket of a subscription, dropped if it is not connected
+#[derive(Event, Debug, Clone)]
+pub struct SubscriptionSend {
+    pub url: String,
+    pub query_key: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionStatus {
+    Connecting,
+    Open,
+    /// Waiting for the next attempt after the connection dropped or failed
+    Reconnecting {
+        attempt: u32,
+    },
+}
+
+/// Triggered every time the connection of a subscription opens or drops
+#[derive(Event, Debug, Clone)]
+pub struct SubscriptionStatusChanged {
+    /// (url, query_key) of the cache entry
+    pub key: (String, String),
+    pub status: SubscriptionStatus,
+}
+
+/// Triggered for every message merged into the cache
+#[derive(Event, Debug, Clone)]
+pub struct SubscriptionMessage {
+    /// (url, query_key) of the cache entry
+    pub key: (String, String),
+    pub message: serde_json::Value,
+}
+
+/// Running subscription kept in the [`QueryStore`]
+#[derive(Debug)]
+pub struct SubscriptionState {
+    pub subscription: Subscription,
+    pub status: SubscriptionStatus,
+    /// Failed attempts since the socket was last open
+    pub attempts: u32,
+    /// When the next attempt is due while reconnecting
+    pub retry_at: Option<Instant>,
+    connection: Option<Connection>,
+}
+
+/// Channels to the task of an open socket, dropping them closes the socket
+#[derive(Debug)]
+struct Connection {
+    events: Receiver<SocketEvent>,
+    outgoing: Sender<String>,
+}
+
+#[derive(Debug)]
+enum SocketEvent {
+    Connected,
+    Message(Vec<u8>),
+    Closed(Option<String>),
+}
+
+/// Starts a subscription, a subscription writing into the same cache entry is replaced
+pub fn subscribe(
+    trigger: Trigger<Subscription>,
+    mut query_store: ResMut<QueryStore>,
+    auth: Option<Res<QueryAuth>>,
+    middleware: Res<QueryMiddlewareStack>,
+    mut commands: Commands,
+) {
+    let subscription = trigger.event().clone();
+    let key = subscription.key();
+    let connection = connect(&subscription, auth.as_deref(), &middleware);
+    commands.trigger(SubscriptionStatusChanged {
+        key: key.clone(),
+        status: SubscriptionStatus::Connecting,
+    });
+    query_store.subscriptions.insert(
+        key,
+        SubscriptionState {
+            subscription,
+            status: SubscriptionStatus::Connecting,
+            attempts: 0,
+            retry_at: None,
+            connection: Some(connection),
+        },
+    );
+}
+
+pub fn unsubscribe(trigger: Trigger<Unsubscribe>, mut query_store: ResMut<QueryStore>) {
+    let event = trigger.event();
+    query_store
+        .subscriptions
+        .remove(&(event.url.clone(), event.query_key.clone().unwrap_or_default()));
+}
+
+pub fn send_to_subscription(trigger: Trigger<SubscriptionSend>, query_store: Res<QueryStore>) {
+    let event = trigger.event();
+    let key = (event.url.clone(), event.query_key.clone().unwrap_or_default());
+    let connection = query_store
+        .subscriptions
+        .get(&key)
+        .filter(|state| state.status == SubscriptionStatus::Open)
+        .and_then(|state| state.connection.as_ref());
+    match connection {
+        Some(connection) => {
+            let _ = connection.outgoing.try_send(event.message.clone());
+        }
+        None => proto!("Subscription {:?} is not connected, message dropped", key),
+    }
+}
+
+/// Merges the received messages into the cache and reconnects the dropped subscriptions
+pub fn poll_subscriptions(
+    mut query_store: ResMut<QueryStore>,
+    auth: Option<Res<QueryAuth>>,
+    middleware: Res<QueryMiddlewareStack>,
+    mut commands: Commands,
+) {
+    if query_store.subscriptions.is_empty() {
+        return;
+    }
+    let store = query_store.bypass_change_detection();
+    let mut updates = vec![];
+
+    for (key, state) in store.subscriptions.iter_mut() {
+        if state.connection.is_none()
+            && state.retry_at.is_some_and(|retry_at| retry_at <= Instant::now())
+        {
+            state.retry_at = None;
+            state.connection = Some(connect(
+                &state.subscription,
+                auth.as_deref(),
+                &middleware,
+            ));
+        }
+        let Some(connection) = &state.connection else {
+            continue;
+        };
+
+        let mut closed = None;
+        loop {
+            match connection.events.try_recv() {
+                Ok(SocketEvent::Connected) => {
+                    state.status = SubscriptionStatus::Open;
+                    state.attempts = 0;
+                    for message in state.subscription.on_connect.iter() {
+                        let _ = connection.outgoing.try_send(message.clone());
+                    }
+                    commands.trigger(SubscriptionStatusChanged {
+                        key: key.clone(),
+                        status: state.status,
+                    });
+                }
+                Ok(SocketEvent::Message(bytes)) => {
+                    match serde_json::from_slice::<serde_json::Value>(&bytes) {
+                        Ok(message) => updates.push((key.clone(), message, state.subscription.merge)),
+                        Err(err) => proto!("Failed to decode message of {} {:#?}", key.0, err),
+                    }
+                }
+                Ok(SocketEvent::Closed(err)) => {
+                    closed = Some(err);
+                    break;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) => {
+                    closed = Some(None);
+                    break;
+                }
+            }
+        }
+
+        if let Some(err) = closed {
+            if let Some(err) = err {
+                proto!("Subscription {} dropped: {}", state.subscription.socket_url, err);
+            }
+            let backoff = state
+                .subscription
+                .min_backoff
+                .saturating_mul(2u32.saturating_pow(state.attempts))
+                .min(state.subscription.max_backoff);
+            state.attempts += 1;
+            state.connection = None;
+            state.retry_at = Some(Instant::now() + backoff);
+            state.status = SubscriptionStatus::Reconnecting {
+                attempt: state.attempts,
+            };
+            commands.trigger(SubscriptionStatusChanged {
+                key: key.clone(),
+                status: state.status,
+            });
+        }
+    }
+
+    for (key, message, merge) in updates {
+        merge_update(&mut query_store, key.clone(), message.clone(), merge, None);
+        commands.trigger(SubscriptionMessage { key, message });
+    }
+}
+
+/// Builds the handshake of a connection attempt, the credentials are added before the middleware sees it
+fn handshake_request(
+    subscription: &Subscription,
+    auth: Option<&QueryAuth>,
+    middleware: &QueryMiddlewareStack,
+) -> HttpRequest {
+    let mut query = Query {
+        url: subscription.socket_url.clone(),
+        headers: Some(subscription.headers.clone()),
+        ..default()
+    };
+    if let Some(auth) = auth {
+        auth.authorize_query(&mut query);
+    }
+    // a layer can not answer a socket, only its changes to the handshake are kept
+    let _ = middleware.on_request(&mut query);
+    HttpRequest {
+        url: query.url,
+        params: query.params.unwrap_or_default(),
+        headers: query.headers.unwrap_or_default(),
+        ..default()
+    }
+}
+
+/// Opens the socket on a task of the [`IoTaskPool`], the task ends once the socket closes or the channels
+/// are dropped
+fn connect(
+    subscription: &Subscription,
+    auth: Option<&QueryAuth>,
+    middleware: &QueryMiddlewareStack,
+) -> Connection {
+    let request = handshake_request(subscription, auth, middleware);
+    let (events_sender, events) = async_channel::unbounded();
+    let (outgoing, outgoing_receiver) = async_channel::unbounded();
+
+    IoTaskPool::get_or_init(TaskPool::new)
+        .spawn(async move {
+            let opening = open_socket(&request);
+            let timeout = async {
+                Timer::after(DEFAULT_TIMEOUT).await;
+                Err("Timed out opening the socket".to_string())
+            };
+            let closed = match future::or(opening, timeout).await {
+                Ok(socket) => {
+                    let _ = events_sender.try_send(SocketEvent::Connected);
+                    run_socket(socket, &events_sender, outgoing_receiver).await.err()
+                }
+                Err(err) => Some(err),
+            };
+            let _ = events_sender.try_send(SocketEvent::Closed(closed));
+        })
+        .detach();
+
+    Connection { events, outgoing }
+}
+
+/// Blocking io expected by `tungstenite` over an async stream, a pending read or write is reported as
+/// `WouldBlock` and wakes the task polling the socket once it can go on
+struct SocketIo {
+    stream: Box<dyn Stream>,
+    waker: Waker,
+}
+
+fn would_block<T>(poll: Poll<io::Result<T>>) -> io::Result<T> {
+    match poll {
+        Poll::Ready(result) => result,
+        Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+    }
+}
+
+impl Read for SocketIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut cx = Context::from_waker(&self.waker);
+        would_block(Pin::new(&mut self.stream).poll_read(&mut cx, buf))
+    }
+}
+
+impl Write for SocketIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut cx = Context::from_waker(&self.waker);
+        would_block(Pin::new(&mut self.stream).poll_write(&mut cx, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut cx = Context::from_waker(&self.waker);
+        would_block(Pin::new(&mut self.stream).poll_flush(&mut cx))
+    }
+}
+
+fn is_would_block(err: &tungstenite::Error) -> bool {
+    matches!(err, tungstenite::Error::Io(err) if err.kind() == io::ErrorKind::WouldBlock)
+}
+
+async fn open_socket(request: &HttpRequest) -> Result<WebSocket<SocketIo>, String> {
+    let url = request.url_with_params();
+    let uri: Uri = url.parse().map_err(|err| format!("{}", err))?;
+    let tls = match uri.scheme_str() {
+        Some("wss") => true,
+        Some("ws") => false,
+        scheme => return Err(format!("Unsupported scheme {}", scheme.unwrap_or_default())),
+    };
+    let host = uri
+        .host()
+        .ok_or(format!("Missing host in {}", uri))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+    let address = (host.clone(), port);
+    let addresses = blocking::unblock(move || address.to_socket_addrs())
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "Host has no address");
+    let mut tcp = None;
+    for address in addresses {
+        match Async::<TcpStream>::connect(address).await {
+            Ok(stream) => {
+                tcp = Some(stream);
+                break;
+            }
+            Err(err) => last_error = err,
+        }
+    }
+    let tcp = tcp.ok_or(last_error.to_string())?;
+
+    let stream: Box<dyn Stream> = match tls {
+        true => {
+            let server_name = ServerName::try_from(host).map_err(|err| err.to_string())?;
+            let tls = TlsConnector::from(default_tls_config());
+            Box::new(tls.connect(server_name, tcp).await.map_err(|err| err.to_string())?)
+        }
+        false => Box::new(tcp),
+    };
+
+    let mut handshake = url.as_str().into_client_request().map_err(|err| err.to_string())?;
+    for (name, value) in request.headers.iter() {
+        let name: tungstenite::http::HeaderName =
+            name.parse().map_err(|_| format!("Invalid header {}", name))?;
+        let value = HeaderValue::from_str(value).map_err(|err| err.to_string())?;
+        handshake.headers_mut().append(name, value);
+    }
+    let io = SocketIo {
+        stream,
+        waker: Waker::noop().clone(),
+    };
+    // the handshake goes on every time the task is woken, with the waker of the task
+    let mut interrupted = match tungstenite::client(handshake, io) {
+        Ok((socket, _)) => return Ok(socket),
+        Err(HandshakeError::Interrupted(handshake)) => Some(handshake),
+        Err(HandshakeError::Failure(err)) => return Err(err.to_string()),
+    };
+    future::poll_fn(|cx| {
+        let Some(mut handshake) = interrupted.take() else {
+            return Poll::Ready(Err("Handshake already done".to_string()));
+        };
+        handshake.get_mut().get_mut().waker = cx.waker().clone();
+        match handshake.handshake() {
+            Ok((socket, _)) => Poll::Ready(Ok(socket)),
+            Err(HandshakeError::Interrupted(handshake)) => {
+                interrupted = Some(handshake);
+                Poll::Pending
+            }
+            Err(HandshakeError::Failure(err)) => Poll::Ready(Err(err.to_string())),
+        }
+    })
+    .await
+}
+
+/// Forwards received messages and sends queued ones until the socket closes or the subscription ends
+async fn run_socket(
+    mut socket: WebSocket<SocketIo>,
+    events: &Sender<SocketEvent>,
+    outgoing: Receiver<String>,
+) -> Result<(), String> {
+    let mut outgoing = pin!(outgoing);
+    future::poll_fn(|cx| {
+        socket.get_mut().waker = cx.waker().clone();
+        loop {
+            match outgoing.as_mut().poll_next(cx) {
+                // written on the next flush if the socket is busy
+                Poll::Ready(Some(message)) => match socket.write(Message::Text(message)) {
+                    Ok(()) => {}
+                    Err(err) if is_would_block(&err) => {}
+                    Err(err) => return Poll::Ready(Err(err.to_string())),
+                },
+                // the subscription ended
+                Poll::Ready(None) => {
+                    let _ = socket.close(None);
+                    let _ = socket.flush();
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match socket.read() {
+                Ok(Message::Text(text)) => {
+                    let _ = events.try_send(SocketEvent::Message(text.into_bytes()));
+                }
+                Ok(Message::Binary(bytes)) => {
+                    let _ = events.try_send(SocketEvent::Message(bytes));
+                }
+                Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Ok(_) => {}
+                Err(err) if is_would_block(&err) => break,
+                Err(err) => return Poll::Ready(Err(err.to_string())),
+            }
+        }
+
+        // answers pings and writes the queued messages
+        match socket.flush() {
+            Ok(()) => Poll::Pending,
+            Err(err) if is_would_block(&err) => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err.to_string())),
+        }
+    })
+    .await
+}
@@ -8,19 +8,86 @@ fn main() {
         ("/same_url", "{\"msg\": \"success\"}"),
         ("/same_url?second_request=is_discarded", "{\"msg\": \"fail\"}"),
         ("/extractor", "{\"msg\": \"hello world\"}"),
+        ("/cached_query", "{\"msg\": \"typed\"}"),
+        ("/extract_if_changed", "{\"msg\": \"changed\"}"),
         ("/force_next_refetch", "{\"msg\": \"Should be consumed once\"}"),
         ("/is_stale", "{\"msg\": \"Should not be consumed\"}"),
         ("/refetch", "{\"msg\": \"Should refetch\"}"),
         ("/seq1", "{\"msg\": \"1\"}"),
         ("/seq2", "{\"msg\": \"2\"}"),
+        ("/seq_abort_1", "{\"msg\": \"1\"}"),
+        ("/seq_abort_3", "{\"msg\": \"3\"}"),
+        ("/seq_skip_1", "{\"msg\": \"1\"}"),
+        ("/seq_skip_3", "{\"msg\": \"3\"}"),
+        ("/graph_auth", "{\"msg\": \"auth\"}"),
+        ("/graph_profile", "{\"msg\": \"profile\"}"),
+        ("/graph_inventory", "{\"msg\": \"inventory\"}"),
+        ("/graph_settings", "{\"msg\": \"settings\"}"),
+        ("/graph_matchmaking", "{\"msg\": \"matchmaking\"}"),
+        ("/batch_1", "{\"msg\": \"1\"}"),
+        ("/batch_2", "{\"msg\": \"2\"}"),
+        ("/batch_3", "{\"msg\": \"3\"}"),
+        ("/comp_reserve", "{\"msg\": \"reserved\"}"),
+        ("/comp_charge", "{\"msg\": \"charged\"}"),
+        ("/comp_release", "{\"msg\": \"released\"}"),
+        ("/comp_refund", "{\"msg\": \"refunded\"}"),
+        ("/pages", "{\"items\": [1, 2], \"next_cursor\": \"2\"}"),
+        ("/pages?cursor=2", "{\"items\": [3, 4], \"next_cursor\": \"3\"}"),
+        ("/pages?cursor=3", "{\"items\": [5], \"next_cursor\": null}"),
+    ]);
+    let error_responses = HashMap::from([
+        ("/seq_fail", (500, "{\"msg\": \"failed\"}")),
+        ("/graph_fail", (500, "{\"msg\": \"failed\"}")),
+        ("/batch_fail", (500, "{\"msg\": \"failed\"}")),
+    ]);
+    let link_responses = HashMap::from([
+        (
+            "/link_pages?page=2",
+            (
+                "[3, 4]",
+                "<http://127.0.0.1:8080/link_pages?page=1>; rel=\"prev\", \
+                 <http://127.0.0.1:8080/link_pages?page=3>; rel=\"next\"",
+            ),
+        ),
+        (
+            "/link_pages?page=1",
+            ("[1, 2]", "<http://127.0.0.1:8080/link_pages?page=2>; rel=\"next\""),
+        ),
     ]);
     loop {
         let request = server.recv();
 
         if let Ok(request) = request {
-            if let Some(response) = responses.get(&request.url()) {
+            if request.url() == "/session_login" {
+                let response = Response::from_string("{\"msg\": \"logged in\"}")
+                    .with_header(tiny_http::Header::from_bytes("Set-Cookie", "session=abc; Path=/; HttpOnly").unwrap())
+                    .with_header(tiny_http::Header::from_bytes("Set-Cookie", "theme=dark; Path=/settings").unwrap());
+                request.respond(response).expect("Responded");
+            } else if request.url() == "/session_profile" {
+                let cookie = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Cookie"))
+                    .map(|header| header.value.to_string())
+                    .unwrap_or_default();
+                let response = Response::from_string(format!("{{\"msg\": \"{}\"}}", cookie));
+                request.respond(response).expect("Responded");
+            } else if request.url() == "/upload_size" {
+                let mut request = request;
+                let mut body = vec![];
+                request.as_reader().read_to_end(&mut body).expect("Read the body");
+                let response = Response::from_string(format!("{{\"msg\": \"{}\"}}", body.len()));
+                request.respond(response).expect("Responded");
+            } else if let Some(response) = responses.get(&request.url()) {
                 let response = Response::from_string(response.to_string());
                 request.respond(response).expect("Responded");
+            } else if let Some((response, link)) = link_responses.get(&request.url()) {
+                let header = tiny_http::Header::from_bytes("Link", *link).unwrap();
+                let response = Response::from_string(response.to_string()).with_header(header);
+                request.respond(response).expect("Responded");
+            } else if let Some((status, response)) = error_responses.get(&request.url()) {
+                let response = Response::from_string(response.to_string()).with_status_code(*status);
+                request.respond(response).expect("Responded");
             }
         }
     }